@@ -1,6 +1,6 @@
 use ktracepoint::{
-    RawTracePointCallBackFunc, TraceCmdLineCache, TraceEntryParser, TracePipeOps,
-    TracePointCallBackFunc, TracePointMap, global_init_events,
+    RawTracePointCallBackFunc, TraceCmdLineCache, TraceEntryParser, TracePointCallBackFunc,
+    TracePointMap, global_init_events,
 };
 use spin::Mutex;
 extern crate alloc;
@@ -34,8 +34,24 @@ mod tracepoint_test {
         }
 
         fn trace_pipe_push_raw_record(buf: &[u8]) {
+            // The watermark callback runs once the pipe is unlocked
+            let callback = TRACE_RAW_PIPE.lock().push_event_deferred(buf.to_vec());
+            if let Some(callback) = callback {
+                callback();
+            }
+        }
+
+        fn trace_pipe_write_raw_record(len: usize, write: &mut dyn FnMut(&mut [u8])) {
             let mut pipe = TRACE_RAW_PIPE.lock();
-            pipe.push_event(buf.to_vec());
+            let Some(record) = pipe.reserve(len) else {
+                return;
+            };
+            write(record);
+            let callback = pipe.commit_deferred();
+            drop(pipe);
+            if let Some(callback) = callback {
+                callback();
+            }
         }
 
         fn trace_cmdline_push(pid: u32) {
@@ -156,13 +172,35 @@ mod tracepoint_test {
         TP_printk(format_args!("Hello from tracepoint! a={}, b={}", __entry.a, __entry.b))
     );
 
+    define_event_trace!(
+        TEST_OPEN,
+        TP_lock(Mutex<()>),
+        TP_kops(Kops),
+        TP_system(tracepoint_test),
+        TP_PROTO(dfd: i32, path: &str),
+        TP_STRUCT__entry{
+            dfd: i32,
+            path: __string
+        },
+        TP_fast_assign{
+            dfd: dfd,
+            path: __assign_str!(path)
+        },
+        TP_ident(__entry),
+        TP_printk(format_args!("dfd={} path={}", __entry.dfd, __get_str!(path)))
+    );
+
     pub fn test_trace(a: u32, b: u32) {
         let x = TestS {
             a,
             b: Box::new(Arc::new(b)),
         };
         trace_TEST(a, &x);
-        trace_TEST2(a, b);
+        // A hot call site can skip preparing the arguments while the tracepoint is disabled
+        if TEST2_enabled() {
+            trace_TEST2(a, b);
+        }
+        trace_TEST_OPEN(-100, "/etc/passwd");
         println!(
             "Tracepoint TEST called with a={}, b={}, x ptr={:p}",
             a, b, &x
@@ -176,22 +214,17 @@ fn print_trace_records(
 ) {
     let mut snapshot = tracepoint_test::TRACE_RAW_PIPE.lock().snapshot();
     print!("{}", snapshot.default_fmt_str());
-    loop {
-        let mut flag = false;
-        if let Some(event) = snapshot.peek() {
-            let trace_str = TraceEntryParser::parse::<tracepoint_test::Kops, _>(
-                tracepoint_map,
-                trace_cmdline_cache,
-                event,
-            );
-            print!("{}", trace_str);
-            flag = true;
-        }
-        if flag {
-            snapshot.pop();
-        } else {
-            break;
-        }
+    let mut trace_str = String::new();
+    for event in snapshot.drain() {
+        trace_str.clear();
+        TraceEntryParser::write_to::<tracepoint_test::Kops, _>(
+            tracepoint_map,
+            trace_cmdline_cache,
+            &event,
+            &mut trace_str,
+        )
+        .unwrap();
+        print!("{}", trace_str);
     }
 }
 
@@ -218,7 +251,7 @@ fn main() {
     // Then, we need to initialize the tracepoint and events.
     // This will create a new events manager and register the tracepoint.
     // The events manager will be used to manage the tracepoints and events.
-    let manager = global_init_events::<Mutex<()>, tracepoint_test::Kops>().unwrap();
+    let manager = global_init_events::<Mutex<()>, tracepoint_test::Kops>(1).unwrap();
     let tracepoint_map = manager.tracepoint_map();
 
     println!("---Before enabling tracepoints---");
@@ -251,10 +284,12 @@ fn main() {
             // Enable the event
             trace_point_info.tracepoint().enable_event();
 
-            trace_point_info
-                .filter_file()
-                .write("(a > 8 && a<=10) || b >5")
-                .unwrap();
+            if trace_point_info.tracepoint().field("b").is_some() {
+                trace_point_info
+                    .filter_file()
+                    .write("(a > 8 && a<=10) || b >5")
+                    .unwrap();
+            }
 
             let schema = trace_point_info.tracepoint().schema();
             println!("Schema for {}.{}: {:#?}", sbs, event, schema);
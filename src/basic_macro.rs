@@ -7,11 +7,60 @@
 /// - `TP_kops`: The kernel trace operations type. `[crate::KernelTraceOps]` is expected to be implemented for this type.
 /// - `TP_system`: The subsystem or system to which the tracepoint belongs.
 /// - `TP_PROTO`: The prototype of the tracepoint function.
+/// - `TP_CONDITION` (optional): A condition over the `TP_PROTO` arguments, e.g.
+///   `TP_CONDITION(a > 0)`. It is evaluated once the tracepoint is found enabled and if it is
+///   false, the event is discarded before the entry is assembled or any callback runs, and isn't
+///   counted as a hit.
 /// - `TP_STRUCT__entry`: The structure of the tracepoint entry.
-///   **WARN**: User need to make sure the layout of the struct is compatible with C layout.
+///   It is laid out as a `#[repr(C)]` struct, so the compiler aligns every field and no manual
+///   padding is needed. The offsets in the schema, the format file and [`crate::FieldDesc`] are
+///   read from that layout with `offset_of!`, so they always match the recorded bytes.
+///   **WARN**: User need to make sure the field types themselves have a C-compatible layout.
+///   A field may be annotated with a unit, e.g. `latency: u64 [ns]`, see
+///   [`crate::FieldDesc::unit`].
+///   A variable-length string is declared as `name: __string`, see [`crate::DataLoc`].
+///   A field type that doesn't implement [`crate::TraceField`] is described as unsigned and
+///   not an array.
 /// - `TP_fast_assign`: The assignment logic for the tracepoint entry.
+///   A string field is assigned with `name: __assign_str!(src)`, which copies `src` up to its
+///   first NUL into the dynamic area after the fixed part of the entry.
+/// - `TP_lazy` (optional): Fields derived from the recorded entry, e.g.
+///   `sum: u32 = __entry.a + __entry.b`. They are not stored in the entry but computed only when
+///   the record is formatted, which keeps the emit path and the record small at the cost of
+///   recomputing them on every format. They can be used in `TP_printk` by name, but are not
+///   visible to filters or the format file.
 /// - `TP_ident`: The identifier for the tracepoint entry.
-/// - `TP_printk`: The print format for the tracepoint.
+/// - `TP_printk`: The print format for the tracepoint. `__get_str!(name)` returns the string of
+///   a string field.
+///
+/// # Disabled tracepoints
+/// The static key of a tracepoint is enabled while its default print or its event is, so while
+/// neither is, the generated `trace_xxx` function only takes the patched-out branch, then
+/// returns. With the `atomic-dispatch` feature, it also checks the flag replacing the key in
+/// `DispatchMode::AtomicFlag`, see [`crate::TracePoint::flag_is_armed`]. The dispatch mode and
+/// the event flag are only read once the tracepoint is found enabled. `TP_CONDITION`
+/// and `TP_fast_assign` are not evaluated, nothing is pushed to the trace pipe, no callback of
+/// any kind runs and the hit count is unchanged.
+///
+/// The same check is generated as `xxx_enabled()`, for call sites that want to skip preparing
+/// the arguments too: `if xxx_enabled() { trace_xxx(expensive()) }`.
+///
+/// # Default print and event
+/// The default print, enabled with [`crate::TracePoint::enable_default`], and the event,
+/// enabled with [`crate::TracePoint::enable_event`], are independent of each other:
+///
+/// | default | event | records pushed | event and raw event callbacks run |
+/// |---------|-------|----------------|-----------------------------------|
+/// | off     | off   | no             | no                                |
+/// | on      | off   | yes            | no                                |
+/// | off     | on    | no             | yes                               |
+/// | on      | on    | yes            | yes                               |
+///
+/// So a consumer can take every event through a callback without filling the trace pipe.
+/// Only the default print is subject to the filter, the triggers, the subsystem pause and
+/// the push conditions of the manager, e.g. tracing off. The callbacks have their own
+/// filters, and while tracing is off they run only if
+/// [`crate::TracingEventsManager::set_callbacks_while_off`] allows it.
 ///
 /// # Example
 /// ```rust ignore
@@ -48,8 +97,10 @@ macro_rules! define_event_trace{
         TP_kops($kops:path),
         TP_system($system:ident),
         TP_PROTO($($arg:ident:$arg_type:ty),+ $(,)?),
-        TP_STRUCT__entry{$($entry:ident:$entry_type:ty),+ $(,)?},
+        $(TP_CONDITION($cond:expr),)?
+        TP_STRUCT__entry{$($entry:ident:$entry_type:ty $([$unit:ident])?),+ $(,)?},
         TP_fast_assign{$($assign:ident:$value:expr),+ $(,)?},
+        $(TP_lazy{$($lazy:ident:$lazy_type:ty = $lazy_value:expr),+ $(,)?},)?
         TP_ident($tp_ident:ident),
         TP_printk($fmt_expr: expr)
     ) => {
@@ -59,6 +110,8 @@ macro_rules! define_event_trace{
             #[allow(non_upper_case_globals)]
             #[used]
             static [<__ $name>]: $crate::TracePoint<$lock, $kops> = {
+                #[allow(unused_imports)]
+                use $crate::__string;
                 #[repr(C)]
                 struct Entry {
                     $($entry: $entry_type,)*
@@ -69,6 +122,8 @@ macro_rules! define_event_trace{
                     entry: Entry,
                 }
                 use $crate::tp_lexer::{schema,FieldClassifier};
+                #[allow(unused_imports)]
+                use $crate::TraceFieldFallback as _;
                 let schema = schema!(
                     "common_type" => (u16::FIELD_TYPE, 0, 2),
                     "common_flags" => (u8::FIELD_TYPE, 2, 1),
@@ -78,13 +133,57 @@ macro_rules! define_event_trace{
                         stringify!($entry) => (<$entry_type>::FIELD_TYPE, core::mem::offset_of!(FullEntry, entry.$entry), core::mem::size_of::<$entry_type>()),
                     )*
                 );
-                $crate::TracePoint::new(&[<__ $name _KEY>], stringify!($name), stringify!($system),[<trace_fmt_ $name>], [<trace_fmt_show $name>], schema)
+                const FIELDS: &[$crate::FieldDesc] = &[
+                    $(
+                        $crate::FieldDesc {
+                            name: stringify!($entry),
+                            ty: stringify!($entry_type),
+                            offset: core::mem::offset_of!(FullEntry, entry.$entry),
+                            size: core::mem::size_of::<$entry_type>(),
+                            signed: $crate::FieldProbe::<$entry_type>::SIGNED,
+                            array_len: $crate::FieldProbe::<$entry_type>::ARRAY_LEN,
+                            unit: {
+                                #[allow(unused_variables)]
+                                let unit: Option<&'static str> = None;
+                                $(let unit = Some(stringify!($unit));)?
+                                unit
+                            },
+                        },
+                    )*
+                ];
+                $crate::TracePoint::new(&[<__ $name _KEY>], stringify!($name), stringify!($system),[<trace_fmt_ $name>], [<trace_fmt_show $name>], schema, FIELDS)
             };
 
+            /// Check whether `trace_xxx` would do anything, without side effects.
+            ///
+            /// This is the same check the tracepoint starts with, so a hot call site can skip
+            /// preparing expensive arguments while the tracepoint is disabled.
+            #[inline(always)]
+            #[allow(non_snake_case)]
+            pub fn [<$name _enabled>]() -> bool {
+                static_keys::static_branch_unlikely!([<__ $name _KEY>]) || [<__ $name>].flag_is_armed()
+            }
+
             #[inline(always)]
             #[allow(non_snake_case)]
             pub fn [<trace_ $name>]( $($arg:$arg_type),* ){
-                if static_keys::static_branch_unlikely!([<__ $name _KEY>]){
+                // A disabled tracepoint does nothing else, not even evaluating its arguments.
+                if !(static_keys::static_branch_unlikely!([<__ $name _KEY>]) || [<__ $name>].flag_is_armed()) {
+                    return;
+                }
+                let default_enabled = [<__ $name>].default_is_enabled();
+                let event_enabled = [<__ $name>].event_is_enabled();
+                // It may have been disabled since the check above.
+                if !default_enabled && !event_enabled {
+                    return;
+                }
+                $(
+                    if !($cond) {
+                        return;
+                    }
+                )?
+                [<__ $name>].inc_hit_count();
+                if default_enabled {
                     let mut f = |trace_func: &$crate::TracePointFunc |{
                         let func = trace_func.func;
                         let data = trace_func.data.as_ref();
@@ -95,8 +194,14 @@ macro_rules! define_event_trace{
                     trace_point.callback_list(&mut f);
                 }
 
+                // The event path below is independent of the default print above, see the
+                // macro docs.
+                let callbacks_enabled = [<__ $name>].callbacks_enabled();
+
                 // call the raw callback functions
-                if [<__ $name>].event_is_enabled() {
+                if callbacks_enabled && event_enabled {
+                    #[allow(unused_imports)]
+                    use $crate::__string;
                     #[repr(C)]
                     struct Entry {
                         $($entry: $entry_type,)*
@@ -107,19 +212,26 @@ macro_rules! define_event_trace{
                         entry: Entry,
                     }
 
-                    let entry = Entry {
-                        $($assign: $value,)*
-                    };
-
+                    // Read the context before the assignments, which may change it.
                     use $crate::KernelTraceOps;
                     let pid = $kops::current_pid();
                     let common = $crate::TraceEntry {
                         common_type: [<__ $name>].id() as _,
-                        common_flags: [<__ $name>].flags(),
-                        common_preempt_count: 0,
+                        common_flags: [<__ $name>].flags() | $kops::irq_flags(),
+                        common_preempt_count: $kops::preempt_count(),
                         common_pid: pid as i32,
                     };
 
+                    #[allow(unused_mut)]
+                    let mut __data = $crate::DynamicArea::new(core::mem::size_of::<FullEntry>());
+                    #[allow(unused_macros)]
+                    macro_rules! __assign_str {
+                        ($src:expr) => { __data.push($src) };
+                    }
+                    let entry = Entry {
+                        $($assign: $value,)*
+                    };
+
                     let full_entry = FullEntry {
                         common,
                         entry,
@@ -131,19 +243,18 @@ macro_rules! define_event_trace{
                             core::mem::size_of::<FullEntry>(),
                         )
                     };
+                    let event_buf = __data.entry(event_buf);
 
-                    let func = |f:&alloc::boxed::Box<dyn $crate::TracePointCallBackFunc>|{
-                        f.call(event_buf);
-                    };
-
-                    [<__ $name>].event_callback_list(&func);
+                    [<__ $name>].call_event_callbacks(&event_buf);
                 }
 
-                let args = [$($crate::AsU64::as_u64($arg)),*];
-                let func = |f:&alloc::boxed::Box<dyn $crate::RawTracePointCallBackFunc>|{
-                    f.call(&args);
-                };
-                [<__ $name>].raw_event_callback_list(&func);
+                if callbacks_enabled && event_enabled {
+                    let args = [$($crate::AsU64::as_u64($arg)),*];
+                    let func = |f:&alloc::boxed::Box<dyn $crate::RawTracePointCallBackFunc>|{
+                        f.call(&args);
+                    };
+                    [<__ $name>].raw_event_callback_list(&func);
+                }
             }
 
             #[allow(non_snake_case)]
@@ -177,6 +288,13 @@ macro_rules! define_event_trace{
 
             #[allow(non_snake_case)]
             fn [<trace_default_ $name>]<F:$crate::KernelTraceOps + 'static>(data:&mut (dyn core::any::Any+Send+Sync), $($arg:$arg_type),* ){
+                let ctx = data.downcast_ref::<$crate::TracePointContext<$lock, F>>().expect("Invalid tracepoint data");
+                if ctx.is_paused() {
+                    return;
+                }
+
+                #[allow(unused_imports)]
+                use $crate::__string;
                 #[repr(C)]
                 struct Entry {
                     $($entry: $entry_type,)*
@@ -187,18 +305,25 @@ macro_rules! define_event_trace{
                     entry: Entry,
                 }
 
-                let entry = Entry {
-                    $($assign: $value,)*
-                };
-
+                // Read the context before the assignments, which may change it.
                 let pid = F::current_pid();
                 let common = $crate::TraceEntry {
                     common_type: [<__ $name>].id() as _,
-                    common_flags: [<__ $name>].flags(),
-                    common_preempt_count: 0,
+                    common_flags: [<__ $name>].flags() | F::irq_flags(),
+                    common_preempt_count: F::preempt_count(),
                     common_pid: pid as i32,
                 };
 
+                #[allow(unused_mut)]
+                let mut __data = $crate::DynamicArea::new(core::mem::size_of::<FullEntry>());
+                #[allow(unused_macros)]
+                macro_rules! __assign_str {
+                    ($src:expr) => { __data.push($src) };
+                }
+                let entry = Entry {
+                    $($assign: $value,)*
+                };
+
                 let full_entry = FullEntry {
                     common,
                     entry,
@@ -210,31 +335,50 @@ macro_rules! define_event_trace{
                         core::mem::size_of::<FullEntry>(),
                     )
                 };
+                let event_buf = __data.entry(event_buf);
+                let event_buf = &event_buf[..];
 
                 // evaluate the filter expression
-                let tp = data.downcast_mut::<&'static $crate::TracePoint<$lock, F>>().expect("Invalid tracepoint data");
+                let tp = ctx.tracepoint();
                 let tp_compiled_expr = tp.get_compiled_expr();
                 if let Some(compiled_expr) = tp_compiled_expr {
-                    use $crate::tp_lexer::BufContext;
-                    let buf_ctx = BufContext::new(event_buf, &tp.schema());
-                    if !compiled_expr.evaluate(&buf_ctx) {
+                    if !tp.evaluate_filter(&compiled_expr, event_buf) {
                         return;
                     }
                 }
 
+                ctx.run_trigger(event_buf);
                 F::trace_cmdline_push(pid);
-                F::trace_pipe_push_raw_record(event_buf);
+                ctx.push_record(event_buf);
             }
 
             #[allow(non_snake_case)]
             pub fn [<trace_fmt_ $name>](buf: &[u8]) -> alloc::string::String {
+                #[allow(unused_imports)]
+                use $crate::__string;
                 #[repr(C)]
                 struct Entry {
                     $($entry: $entry_type,)*
                 }
-                let $tp_ident = unsafe {
-                    &*(buf.as_ptr() as *const Entry)
+                // The entry may be misaligned in the record, and a short one reads as zeros
+                // past its end rather than out of bounds.
+                let mut raw = core::mem::MaybeUninit::<Entry>::zeroed();
+                let len = buf.len().min(core::mem::size_of::<Entry>());
+                let entry = unsafe {
+                    core::ptr::copy_nonoverlapping(buf.as_ptr(), raw.as_mut_ptr() as *mut u8, len);
+                    core::mem::ManuallyDrop::new(raw.assume_init())
                 };
+                let $tp_ident = &*entry;
+                #[allow(unused_macros)]
+                macro_rules! __get_str {
+                    ($field:ident) => {
+                        $tp_ident.$field.str_in(buf, core::mem::size_of::<$crate::TraceEntry>())
+                    };
+                }
+                $($(
+                    #[allow(unused_variables)]
+                    let $lazy: $lazy_type = $lazy_value;
+                )+)?
                 let fmt = alloc::format!("{}", $fmt_expr);
                 fmt
             }
@@ -248,14 +392,9 @@ macro_rules! define_event_trace{
 \tfield: i32 common_pid; offset: 4; size: 4; signed: 1;
 
 ");
-                fn is_signed<T>() -> bool {
-                    match core::any::type_name::<T>() {
-                        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => true,
-                        _ => false,
-                    }
-                }
-
 
+                #[allow(unused_imports)]
+                use $crate::__string;
                 #[repr(C)]
                 struct Entry {
                     $($entry: $entry_type,)*
@@ -266,10 +405,17 @@ macro_rules! define_event_trace{
                     entry: Entry,
                 }
 
+                #[allow(unused_imports)]
+                use $crate::TraceFieldFallback as _;
                 $(
-                    let mut offset = core::mem::offset_of!(FullEntry, entry.$entry);
-                    fmt.push_str(&alloc::format!("\tfield: {} {} offset: {}; size: {}; signed: {};\n",
-                        stringify!($entry_type), stringify!($entry), offset, core::mem::size_of::<$entry_type>(), if is_signed::<$entry_type>() { 1 } else { 0 }));
+                    let offset = core::mem::offset_of!(FullEntry, entry.$entry);
+                    let ty = match stringify!($entry_type) {
+                        "__string" => "__data_loc char[]",
+                        ty => ty,
+                    };
+                    let signed = $crate::FieldProbe::<$entry_type>::SIGNED;
+                    fmt.push_str(&alloc::format!("\tfield: {} {}; offset: {}; size: {}; signed: {};\n",
+                        ty, stringify!($entry), offset, core::mem::size_of::<$entry_type>(), signed as u8));
                 )*
                 fmt.push_str(&alloc::format!("\nprint fmt: \"{}\"", stringify!($fmt_expr)));
                 fmt
@@ -277,3 +423,256 @@ macro_rules! define_event_trace{
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::Ordering;
+
+    use crate::{
+        TracePoint,
+        testing::{self, Kops, Lock},
+    };
+
+    mod events {
+        #![allow(dead_code)]
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::testing::{Kops, Lock};
+        use tp_lexer::{FieldClassifier, FieldType};
+
+        /// A field type implementing only what the schema needs.
+        #[derive(Clone, Copy)]
+        #[repr(C)]
+        pub struct Opaque(pub u32);
+
+        impl FieldClassifier for Opaque {
+            const FIELD_TYPE: FieldType = FieldType::U32;
+        }
+
+        pub static ASSIGNS: AtomicUsize = AtomicUsize::new(0);
+
+        crate::define_event_trace!(
+            macro_gate,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(macro_tests),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: {
+                    ASSIGNS.fetch_add(1, Ordering::Relaxed);
+                    x
+                },
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        crate::define_event_trace!(
+            macro_condition,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(macro_tests),
+            TP_PROTO(x: u32),
+            TP_CONDITION(x > 0),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        crate::define_event_trace!(
+            macro_signed,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(macro_tests),
+            TP_PROTO(x: i16, y: u32),
+            TP_STRUCT__entry {
+                delta: i16,
+                opaque: Opaque,
+            },
+            TP_fast_assign {
+                delta: x,
+                opaque: Opaque(y),
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("delta={} opaque={}", __entry.delta, __entry.opaque.0))
+        );
+
+        crate::define_event_trace!(
+            macro_padded,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(macro_tests),
+            TP_PROTO(x: u8, y: u64, z: u16),
+            TP_STRUCT__entry {
+                flag: u8,
+                wide: u64,
+                small: u16,
+            },
+            TP_fast_assign {
+                flag: x,
+                wide: y,
+                small: z,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!(
+                "flag={} wide={} small={}",
+                __entry.flag,
+                __entry.wide,
+                __entry.small
+            ))
+        );
+    }
+
+    fn tracepoint(name: &str) -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("macro_tests", name)
+            .unwrap()
+    }
+
+    #[test]
+    fn signed_fields_render_negative() {
+        let _serial = testing::serial();
+        let tp = tracepoint("macro_signed");
+        let signed = |name| tp.field(name).unwrap().signed;
+        assert!(signed("delta"));
+        assert!(!signed("opaque"));
+        assert!(
+            tp.print_fmt()
+                .contains("field: i16 delta; offset: 8; size: 2; signed: 1;")
+        );
+
+        tp.enable_default();
+        events::trace_macro_signed(-3, 9);
+        tp.disable_default();
+        let records = testing::take_records(tp.id());
+        let map = testing::manager().tracepoint_map();
+        let mut options = crate::ParseOptions::new();
+        options.set_decode_fields(true);
+        let line = crate::TraceEntryParser::parse_with(
+            &map,
+            &crate::TraceCmdLineCache::new(4),
+            &records[0],
+            &options,
+        );
+        assert!(line.contains("(delta=-3, opaque=9)"), "{line}");
+    }
+
+    #[test]
+    fn padded_fields_follow_the_repr_c_layout() {
+        #[repr(C)]
+        struct Padded {
+            common: crate::TraceEntry,
+            flag: u8,
+            wide: u64,
+            small: u16,
+        }
+        let _serial = testing::serial();
+        let tp = tracepoint("macro_padded");
+        let offset = |name| tp.field(name).unwrap().offset;
+        assert_eq!(offset("flag"), core::mem::offset_of!(Padded, flag));
+        assert_eq!(offset("wide"), core::mem::offset_of!(Padded, wide));
+        assert_eq!(offset("small"), core::mem::offset_of!(Padded, small));
+        assert_eq!(
+            (offset("flag"), offset("wide"), offset("small")),
+            (8, 16, 24)
+        );
+
+        tp.enable_default();
+        events::trace_macro_padded(1, u64::MAX, 3);
+        tp.disable_default();
+        let records = testing::take_records(tp.id());
+        let entry = &records[0][crate::RecordHeader::from_bytes(&records[0])
+            .unwrap()
+            .entry_offset()..];
+        let decode = |name| tp.field(name).unwrap().decode(entry);
+        assert_eq!(decode("flag"), Some(crate::FieldValue::Unsigned(1)));
+        assert_eq!(decode("wide"), Some(crate::FieldValue::Unsigned(u64::MAX)));
+        assert_eq!(decode("small"), Some(crate::FieldValue::Unsigned(3)));
+    }
+
+    #[test]
+    fn gate_opens_for_either_path() {
+        let _serial = testing::serial();
+        let tp = tracepoint("macro_gate");
+        assert!(!events::macro_gate_enabled());
+        tp.enable_event();
+        assert!(events::macro_gate_enabled());
+        tp.enable_default();
+        tp.disable_event();
+        assert!(events::macro_gate_enabled());
+        tp.disable_default();
+        assert!(!events::macro_gate_enabled());
+        assert!(!tp.flag_is_armed());
+    }
+
+    /// A callback of every kind that fails the test if it runs.
+    struct Panicking;
+
+    impl crate::TracePointCallBackFunc for Panicking {
+        fn call(&self, _entry: &[u8]) {
+            panic!("event callback of a disabled tracepoint");
+        }
+    }
+
+    impl crate::RawTracePointCallBackFunc for Panicking {
+        fn call(&self, _args: &[u64]) {
+            panic!("raw callback of a disabled tracepoint");
+        }
+    }
+
+    fn panicking_default(_data: &(dyn core::any::Any + Send + Sync), _x: u32) {
+        panic!("default callback of a disabled tracepoint");
+    }
+
+    #[test]
+    fn disabled_tracepoint_skips_assignments() {
+        let _serial = testing::serial();
+        let tp = tracepoint("macro_gate");
+        let assigns = || events::ASSIGNS.load(Ordering::Relaxed);
+        let before = assigns();
+        events::register_trace_macro_gate(panicking_default, alloc::boxed::Box::new(()));
+        tp.register_event_callback(701, alloc::boxed::Box::new(Panicking));
+        tp.register_raw_event_callback(702, alloc::boxed::Box::new(Panicking));
+        events::trace_macro_gate(1);
+        events::unregister_trace_macro_gate(panicking_default);
+        tp.unregister_event_callback(701);
+        tp.unregister_raw_event_callback(702);
+        assert!(testing::take_records(tp.id()).is_empty());
+        assert_eq!(assigns(), before);
+        assert_eq!(tp.hit_count(), 0);
+        tp.enable_event();
+        events::trace_macro_gate(2);
+        tp.disable_event();
+        assert_eq!(assigns(), before + 1);
+        assert_eq!(tp.hit_count(), 1);
+        tp.reset_hit_count();
+    }
+
+    #[test]
+    fn false_condition_discards_the_event() {
+        let _serial = testing::serial();
+        let tp = tracepoint("macro_condition");
+        tp.enable_default();
+        events::trace_macro_condition(0);
+        events::trace_macro_condition(2);
+        tp.disable_default();
+        let records = testing::take_records(tp.id());
+        assert_eq!(records.len(), 1);
+        let entry = &records[0][crate::RecordHeader::from_bytes(&records[0])
+            .unwrap()
+            .entry_offset()..];
+        let a = tp.field("a").unwrap().decode(entry);
+        assert_eq!(a, Some(crate::FieldValue::Unsigned(2)));
+        assert_eq!(tp.hit_count(), 1);
+        tp.reset_hit_count();
+    }
+}
@@ -0,0 +1,74 @@
+//! Notices about the health of the tracing system itself.
+//! They are pushed to the diagnostic pipe set with
+//! [`crate::TracingEventsManager::set_diagnostic_pipe`], separately from the traced data,
+//! so an operator can audit data loss.
+//!
+
+/// The kind of a [`DiagnosticNotice`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DiagnosticKind {
+    /// A sink dropped a record because of backpressure.
+    Dropped = 0,
+    /// A record was truncated to the payload cap of its tracepoint.
+    Truncated = 1,
+    /// The filter of the tracepoint could not be evaluated against an entry.
+    FilterError = 2,
+}
+
+impl DiagnosticKind {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(DiagnosticKind::Dropped),
+            1 => Some(DiagnosticKind::Truncated),
+            2 => Some(DiagnosticKind::FilterError),
+            _ => None,
+        }
+    }
+}
+
+/// A lightweight notice pushed to the diagnostic pipe as a raw record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiagnosticNotice {
+    /// What happened.
+    pub kind: DiagnosticKind,
+    /// The ID of the tracepoint whose record was affected.
+    pub event_id: u32,
+    /// The timestamp of the affected record, in nanoseconds.
+    pub timestamp: u64,
+}
+
+/// The raw layout of a [`DiagnosticNotice`].
+#[repr(C)]
+struct RawNotice {
+    kind: u32,
+    event_id: u32,
+    timestamp: u64,
+}
+
+impl DiagnosticNotice {
+    /// Read a notice from a raw record of the diagnostic pipe.
+    ///
+    /// Returns `None` if the record is too short or of an unknown kind.
+    pub fn from_bytes(record: &[u8]) -> Option<Self> {
+        if record.len() < size_of::<RawNotice>() {
+            return None;
+        }
+        let raw = unsafe { core::ptr::read_unaligned(record.as_ptr() as *const RawNotice) };
+        Some(Self {
+            kind: DiagnosticKind::from_u32(raw.kind)?,
+            event_id: raw.event_id,
+            timestamp: raw.timestamp,
+        })
+    }
+
+    /// Returns the raw record of the notice.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let raw = RawNotice {
+            kind: self.kind as u32,
+            event_id: self.event_id,
+            timestamp: self.timestamp,
+        };
+        unsafe { core::mem::transmute::<RawNotice, [u8; 16]>(raw) }
+    }
+}
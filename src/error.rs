@@ -0,0 +1,223 @@
+//! Error types reported by the tracing events management.
+//!
+
+use alloc::{string::String, vec::Vec};
+
+/// Errors reported by the tracing events management.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceError {
+    /// Two tracepoints were defined with the same system and name.
+    DuplicateEvent {
+        /// The system of the duplicated tracepoint.
+        system: &'static str,
+        /// The name of the duplicated tracepoint.
+        name: &'static str,
+    },
+    /// A tracepoint is stored in the tracepoint map under an ID other than its own.
+    IdMismatch {
+        /// The key of the tracepoint in the map.
+        key: u32,
+        /// The ID of the tracepoint.
+        id: u32,
+    },
+    /// A tracepoint in the tracepoint map has no event in its subsystem.
+    MissingEvent {
+        /// The system of the tracepoint.
+        system: &'static str,
+        /// The name of the tracepoint.
+        name: &'static str,
+    },
+    /// An event refers to a tracepoint that is not in the tracepoint map.
+    DanglingEvent {
+        /// The subsystem of the event.
+        system: String,
+        /// The name of the event.
+        name: String,
+    },
+    /// A `set_event` pattern matched no event.
+    NoMatchingEvent {
+        /// The pattern.
+        pattern: String,
+    },
+    /// A callback was not registered because the tracepoint has reached its callback cap.
+    CallbackLimit {
+        /// The cap.
+        max: usize,
+    },
+    /// The tracing events were already initialized.
+    AlreadyInitialized,
+    /// The tracepoint IDs starting at the requested base don't fit in
+    /// [`crate::TraceEntry::common_type`].
+    IdRangeExhausted {
+        /// The requested first ID.
+        start_id: u32,
+        /// The number of tracepoints to number.
+        count: usize,
+    },
+    /// Two tracepoints were assigned the same stable ID.
+    IdCollision {
+        /// The ID.
+        id: u32,
+        /// The first tracepoint, as `system:name`.
+        first: String,
+        /// The second tracepoint, as `system:name`.
+        second: String,
+    },
+    /// The number of tracepoints found in the linker section is not the expected one.
+    UnexpectedCount {
+        /// The expected number of tracepoints.
+        expected: usize,
+        /// The number of tracepoints found.
+        found: usize,
+    },
+}
+
+impl core::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TraceError::DuplicateEvent { system, name } => {
+                write!(f, "duplicate tracepoint: {system}:{name}")
+            }
+            TraceError::IdMismatch { key, id } => {
+                write!(f, "tracepoint with id {id} is stored under id {key}")
+            }
+            TraceError::MissingEvent { system, name } => {
+                write!(f, "tracepoint {system}:{name} has no event")
+            }
+            TraceError::DanglingEvent { system, name } => {
+                write!(
+                    f,
+                    "event {system}:{name} refers to an unregistered tracepoint"
+                )
+            }
+            TraceError::NoMatchingEvent { pattern } => {
+                write!(f, "no event matches {pattern}")
+            }
+            TraceError::CallbackLimit { max } => {
+                write!(f, "the tracepoint already has {max} callbacks of this kind")
+            }
+            TraceError::AlreadyInitialized => write!(f, "already initialized"),
+            TraceError::IdRangeExhausted { start_id, count } => {
+                write!(
+                    f,
+                    "{count} tracepoint ids starting at {start_id} exceed u16"
+                )
+            }
+            TraceError::IdCollision { id, first, second } => {
+                write!(f, "tracepoint id {id} of {second} collides with {first}")
+            }
+            TraceError::UnexpectedCount { expected, found } => {
+                write!(f, "expected {expected} tracepoints, found {found}")
+            }
+        }
+    }
+}
+
+/// Errors reported when compiling a filter expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterError {
+    /// The expression is malformed.
+    Parse {
+        /// The byte offset of the error in the expression, if known.
+        offset: Option<usize>,
+        /// What is wrong with the expression.
+        message: String,
+    },
+    /// The expression refers to a field the tracepoint doesn't have.
+    UnknownField {
+        /// The name of the unknown field.
+        name: String,
+        /// The names of the fields of the tracepoint, including the common fields.
+        valid: Vec<&'static str>,
+    },
+    /// The expression uses a field with an operator its type doesn't support, e.g. an array
+    /// in a comparison, or a glob match on an integer field.
+    TypeMismatch {
+        /// The name of the field.
+        field: &'static str,
+        /// The declared type of the field.
+        ty: &'static str,
+    },
+}
+
+impl core::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FilterError::Parse {
+                offset: Some(offset),
+                message,
+            } => write!(f, "parse error at offset {offset}: {message}"),
+            FilterError::Parse {
+                offset: None,
+                message,
+            } => write!(f, "parse error: {message}"),
+            FilterError::UnknownField { name, valid } => {
+                write!(
+                    f,
+                    "unknown field {name}, valid fields: {}",
+                    valid.join(", ")
+                )
+            }
+            FilterError::TypeMismatch { field, ty } => {
+                write!(
+                    f,
+                    "field {field} of type {ty} doesn't support this operator"
+                )
+            }
+        }
+    }
+}
+
+/// Errors reported when parsing a raw record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The record is shorter than its [`crate::RecordHeader`], or a serialized snapshot ends
+    /// before its last event.
+    TooShort {
+        /// The length of the record or snapshot.
+        len: usize,
+    },
+    /// The record doesn't start with [`crate::RECORD_MAGIC`].
+    BadMagic {
+        /// The magic number found in the record.
+        magic: u16,
+    },
+    /// The record has a format version other than [`crate::RECORD_FORMAT_VERSION`].
+    UnsupportedVersion {
+        /// The version found in the record.
+        version: u16,
+    },
+    /// The `common_type` of the entry isn't the ID of any tracepoint.
+    UnknownId {
+        /// The ID found in the entry.
+        id: u16,
+    },
+    /// The entry is too short to hold the fields of its tracepoint.
+    ///
+    /// A record truncated to the payload cap of its tracepoint isn't affected, since its
+    /// entry is padded back to its original length.
+    Truncated {
+        /// The length of the entry.
+        len: usize,
+        /// The length needed by the fields.
+        expected: usize,
+    },
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::TooShort { len } => write!(f, "input of {len} bytes is too short"),
+            ParseError::BadMagic { magic } => write!(f, "bad record magic {magic:#06x}"),
+            ParseError::UnsupportedVersion { version } => write!(
+                f,
+                "unsupported record version {version}, expected {}",
+                crate::RECORD_FORMAT_VERSION
+            ),
+            ParseError::UnknownId { id } => write!(f, "unknown tracepoint ID {id}"),
+            ParseError::Truncated { len, expected } => {
+                write!(f, "entry of {len} bytes is truncated, expected {expected}")
+            }
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Descriptors of the fields in a trace entry.
+//! They are generated by [`crate::define_event_trace`] from `TP_STRUCT__entry` and
+//! are used to decode field values from the raw entry bytes.
+//!
+
+use alloc::{borrow::Cow, format, string::String, vec::Vec};
+use core::marker::PhantomData;
+
+use tp_lexer::{FieldClassifier, FieldType};
+
+/// Static type information about a type used as a trace entry field.
+pub trait TraceField {
+    /// Whether the type is a signed integer, or an array of signed integers.
+    const SIGNED: bool;
+    /// The number of elements if the type is an array, zero otherwise.
+    const ARRAY_LEN: usize = 0;
+}
+
+macro_rules! impl_trace_field {
+    ($signed:expr => $($t:ty),+) => {
+        $(
+            impl TraceField for $t {
+                const SIGNED: bool = $signed;
+            }
+        )+
+    };
+}
+
+impl_trace_field!(false => u8, u16, u32, u64, u128, usize, bool, char);
+impl_trace_field!(true => i8, i16, i32, i64, i128, isize);
+
+impl<T: TraceField, const N: usize> TraceField for [T; N] {
+    const SIGNED: bool = T::SIGNED;
+    const ARRAY_LEN: usize = N;
+}
+
+/// Reads the [`TraceField`] constants of a field type from [`crate::define_event_trace`],
+/// falling back to [`TraceFieldFallback`] for a type that doesn't implement the trait.
+///
+/// The inherent constants take precedence over the trait ones, but only exist if `T`
+/// implements [`TraceField`], so any other type reads as unsigned and not an array.
+#[doc(hidden)]
+pub struct FieldProbe<T: ?Sized>(PhantomData<T>);
+
+impl<T: TraceField> FieldProbe<T> {
+    /// See [`TraceField::SIGNED`].
+    pub const SIGNED: bool = T::SIGNED;
+    /// See [`TraceField::ARRAY_LEN`].
+    pub const ARRAY_LEN: usize = T::ARRAY_LEN;
+}
+
+/// The constants of [`FieldProbe`] for a type that doesn't implement [`TraceField`].
+#[doc(hidden)]
+pub trait TraceFieldFallback {
+    /// The type isn't known to be signed.
+    const SIGNED: bool = false;
+    /// The type isn't known to be an array.
+    const ARRAY_LEN: usize = 0;
+}
+
+impl<T: ?Sized> TraceFieldFallback for FieldProbe<T> {}
+
+/// The location of a variable-length string in the dynamic area of a trace entry.
+///
+/// It is the type of `__string` fields, see [`crate::define_event_trace`]. Like the Linux
+/// `__data_loc`, it records the offset of the string from the start of the entry and its
+/// length, including the trailing NUL, in 2 bytes each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct DataLoc {
+    offset: u16,
+    len: u16,
+}
+
+/// The type of string fields in `TP_STRUCT__entry`.
+#[allow(non_camel_case_types)]
+pub type __string = DataLoc;
+
+impl TraceField for DataLoc {
+    const SIGNED: bool = false;
+}
+
+impl FieldClassifier for DataLoc {
+    const FIELD_TYPE: FieldType = FieldType::U32;
+}
+
+impl DataLoc {
+    /// The offset of the string from the start of the entry.
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// The length of the string, including the trailing NUL.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Whether the location holds no string.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the string in the given entry, whose first `base` bytes are skipped.
+    ///
+    /// A string outside the entry, e.g. cut off from a truncated record, reads as empty,
+    /// and one that isn't valid UTF-8 reads as `?`.
+    pub fn str_in<'a>(&self, entry: &'a [u8], base: usize) -> &'a str {
+        let start = self.offset().saturating_sub(base);
+        let bytes = entry.get(start..start + self.len()).unwrap_or(&[]);
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        core::str::from_utf8(bytes).unwrap_or("?")
+    }
+}
+
+/// The dynamic area of a trace entry being built, holding its `__string` fields.
+///
+/// It is used by [`crate::define_event_trace`] and placed right after the fixed part of
+/// the entry, which is `base` bytes long.
+#[derive(Debug)]
+pub struct DynamicArea {
+    base: usize,
+    data: Vec<u8>,
+}
+
+impl DynamicArea {
+    /// Create an empty dynamic area for an entry whose fixed part is `base` bytes long.
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base,
+            data: Vec::new(),
+        }
+    }
+
+    /// Copy the string, up to its first NUL, to the area and return its location.
+    ///
+    /// The string is cut short if the entry would grow past the 64 KiB reach of [`DataLoc`].
+    pub fn push(&mut self, src: impl AsRef<[u8]>) -> DataLoc {
+        let src = src.as_ref();
+        let src = src.split(|&b| b == 0).next().unwrap_or(src);
+        let offset = self.base + self.data.len();
+        let room = (u16::MAX as usize).saturating_sub(offset);
+        if room == 0 {
+            return DataLoc { offset: 0, len: 0 };
+        }
+        let len = src.len().min(room - 1);
+        self.data.extend_from_slice(&src[..len]);
+        self.data.push(0);
+        DataLoc {
+            offset: offset as u16,
+            len: (len + 1) as u16,
+        }
+    }
+
+    /// Returns the whole entry, i.e. the fixed part followed by the area.
+    ///
+    /// No copy is made if the area is empty.
+    pub fn entry<'a>(&self, fixed: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.data.is_empty() {
+            return Cow::Borrowed(fixed);
+        }
+        let mut entry = Vec::with_capacity(fixed.len() + self.data.len());
+        entry.extend_from_slice(fixed);
+        entry.extend_from_slice(&self.data);
+        Cow::Owned(entry)
+    }
+}
+
+/// The descriptor of a field in a trace entry.
+///
+/// The offset is relative to the start of the entry, including the [`crate::TraceEntry`] header.
+/// The descriptors of a tracepoint are listed by [`crate::TracePoint::all_fields`], e.g.
+/// to build a query layer on top of the raw records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldDesc {
+    /// The name of the field.
+    pub name: &'static str,
+    /// The declared type of the field.
+    pub ty: &'static str,
+    /// The offset of the field in the entry.
+    pub offset: usize,
+    /// The size of the field in bytes.
+    pub size: usize,
+    /// Whether the field is a signed integer, or an array of signed integers.
+    pub signed: bool,
+    /// The number of elements if the field is an array, zero otherwise.
+    pub array_len: usize,
+    /// The unit of the field, e.g. `ns` or `bytes`, if annotated.
+    pub unit: Option<&'static str>,
+}
+
+/// The common fields at the front of every trace entry.
+pub const COMMON_FIELDS: [FieldDesc; 4] = [
+    FieldDesc {
+        name: "common_type",
+        ty: "u16",
+        offset: 0,
+        size: 2,
+        signed: false,
+        array_len: 0,
+        unit: None,
+    },
+    FieldDesc {
+        name: "common_flags",
+        ty: "u8",
+        offset: 2,
+        size: 1,
+        signed: false,
+        array_len: 0,
+        unit: None,
+    },
+    FieldDesc {
+        name: "common_preempt_count",
+        ty: "u8",
+        offset: 3,
+        size: 1,
+        signed: false,
+        array_len: 0,
+        unit: None,
+    },
+    FieldDesc {
+        name: "common_pid",
+        ty: "i32",
+        offset: 4,
+        size: 4,
+        signed: true,
+        array_len: 0,
+        unit: None,
+    },
+];
+
+/// The value of an integer field decoded from a trace entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FieldValue {
+    /// An unsigned integer value.
+    Unsigned(u64),
+    /// A signed integer value, sign-extended from the field size.
+    Signed(i64),
+}
+
+impl core::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FieldValue::Unsigned(v) => write!(f, "{v}"),
+            FieldValue::Signed(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+impl FieldValue {
+    /// Format the value in a human-friendly way for the given unit.
+    ///
+    /// Times in `ns`, `us` and `ms` are scaled to the largest fitting unit up to seconds,
+    /// e.g. `1500000` ns renders as `1.5ms`, and `bytes` are scaled to KiB, MiB and GiB.
+    /// Other units are appended as is, e.g. `3 pages`.
+    pub fn display_with_unit(&self, unit: &str) -> String {
+        let value = match *self {
+            FieldValue::Unsigned(v) => v as f64,
+            FieldValue::Signed(v) => v as f64,
+        };
+        match unit {
+            "ns" => scale(value, "ns", 1000.0, &["us", "ms", "s"]),
+            "us" => scale(value * 1e3, "ns", 1000.0, &["us", "ms", "s"]),
+            "ms" => scale(value * 1e6, "ns", 1000.0, &["us", "ms", "s"]),
+            "bytes" if value.abs() < 1024.0 => format!("{self} bytes"),
+            "bytes" => scale(value, "bytes", 1024.0, &["KiB", "MiB", "GiB"]),
+            _ => format!("{self} {unit}"),
+        }
+    }
+}
+
+/// Scale the value by `step` for each of the larger units while it is at least one of them.
+fn scale(mut value: f64, unit: &str, step: f64, larger: &[&str]) -> String {
+    let mut unit = unit;
+    for larger_unit in larger {
+        if value.abs() < step {
+            break;
+        }
+        value /= step;
+        unit = larger_unit;
+    }
+    let mut number = format!("{value:.3}");
+    if number.contains('.') {
+        number.truncate(number.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    format!("{number}{unit}")
+}
+
+impl FieldDesc {
+    /// Decode the field as an integer from the entry.
+    ///
+    /// Signed fields are sign-extended, so an `i32` holding `-1` decodes to `-1`.
+    /// Returns `None` if the field is an array, lies outside the entry or its size is not
+    /// 1, 2, 4 or 8. Arrays are decoded with [`FieldDesc::decode_array`].
+    pub fn decode(&self, entry: &[u8]) -> Option<FieldValue> {
+        if self.is_array() {
+            return None;
+        }
+        let bytes = entry.get(self.offset..self.offset.checked_add(self.size)?)?;
+        let value = match self.size {
+            1 => bytes[0] as u64,
+            2 => u16::from_ne_bytes(bytes.try_into().ok()?) as u64,
+            4 => u32::from_ne_bytes(bytes.try_into().ok()?) as u64,
+            8 => u64::from_ne_bytes(bytes.try_into().ok()?),
+            _ => return None,
+        };
+        if self.signed {
+            let shift = 64 - self.size * 8;
+            Some(FieldValue::Signed(((value << shift) as i64) >> shift))
+        } else {
+            Some(FieldValue::Unsigned(value))
+        }
+    }
+
+    /// Whether the field is an array.
+    pub fn is_array(&self) -> bool {
+        self.array_len != 0
+    }
+
+    /// Decode the elements of an array field from the entry.
+    ///
+    /// The elements are decoded like [`FieldDesc::decode`] does for a scalar. Returns `None`
+    /// if the field isn't an array, lies outside the entry or its elements are not 1, 2, 4
+    /// or 8 bytes wide.
+    pub fn decode_array(&self, entry: &[u8]) -> Option<Vec<FieldValue>> {
+        if !self.is_array() {
+            return None;
+        }
+        let elem_size = self.size / self.array_len;
+        (0..self.array_len)
+            .map(|idx| {
+                FieldDesc {
+                    offset: self.offset + idx * elem_size,
+                    size: elem_size,
+                    array_len: 0,
+                    ..*self
+                }
+                .decode(entry)
+            })
+            .collect()
+    }
+
+    /// Returns the bytes of a byte array field as a hex string, e.g. `0a0b0c0d`.
+    ///
+    /// Returns `None` if the field isn't an array of 1-byte elements or lies outside the
+    /// entry.
+    pub fn format_hex(&self, entry: &[u8]) -> Option<String> {
+        if !self.is_array() || self.size != self.array_len {
+            return None;
+        }
+        let bytes = entry.get(self.offset..self.offset.checked_add(self.size)?)?;
+        Some(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    /// Whether the field is a `__string` field holding a [`DataLoc`].
+    pub fn is_string(&self) -> bool {
+        self.ty == "__string"
+    }
+
+    /// Returns the string of a `__string` field in the entry.
+    ///
+    /// Returns `None` if the field isn't a string field or lies outside the entry.
+    pub fn decode_str<'a>(&self, entry: &'a [u8]) -> Option<&'a str> {
+        if !self.is_string() {
+            return None;
+        }
+        let bytes = entry.get(self.offset..self.offset.checked_add(size_of::<DataLoc>())?)?;
+        let loc = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const DataLoc) };
+        Some(loc.str_in(entry, 0))
+    }
+
+    /// Decode the field from the entry and format it with its unit, if any.
+    ///
+    /// String fields are formatted as their string and arrays as their elements, e.g.
+    /// `[1, 2, 3, 4]`. See [`FieldValue::display_with_unit`]. Returns `None` if the field
+    /// can't be decoded.
+    pub fn format(&self, entry: &[u8]) -> Option<String> {
+        if let Some(value) = self.decode_str(entry) {
+            return Some(value.into());
+        }
+        if self.is_array() {
+            let values = self.decode_array(entry)?;
+            let values = values
+                .iter()
+                .map(|value| match self.unit {
+                    Some(unit) => value.display_with_unit(unit),
+                    None => format!("{value}"),
+                })
+                .collect::<Vec<_>>();
+            return Some(format!("[{}]", values.join(", ")));
+        }
+        let value = self.decode(entry)?;
+        Some(match self.unit {
+            Some(unit) => value.display_with_unit(unit),
+            None => format!("{value}"),
+        })
+    }
+}
+
+/// A view of the fields of a trace entry, looked up by name.
+///
+/// This is what [`crate::TracePointFieldCallBackFunc`] receives instead of raw bytes.
+/// The fields are decoded on access with the [`FieldDesc`] of the tracepoint, including the
+/// common fields.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldView<'a> {
+    fields: &'static [FieldDesc],
+    entry: &'a [u8],
+}
+
+impl<'a> FieldView<'a> {
+    /// Create a view of the entry, whose fields are described by `fields`.
+    ///
+    /// The entry starts with the [`crate::TraceEntry`] header, as passed to event callbacks.
+    pub fn new(fields: &'static [FieldDesc], entry: &'a [u8]) -> Self {
+        Self { fields, entry }
+    }
+
+    /// Returns the raw bytes of the entry.
+    pub fn entry(&self) -> &'a [u8] {
+        self.entry
+    }
+
+    /// Returns the descriptor of the field with the given name, including the common fields.
+    pub fn field(&self, name: &str) -> Option<&'static FieldDesc> {
+        COMMON_FIELDS
+            .iter()
+            .chain(self.fields.iter())
+            .find(|field| field.name == name)
+    }
+
+    /// Decode an integer field, see [`FieldDesc::decode`].
+    pub fn value(&self, name: &str) -> Option<FieldValue> {
+        self.field(name)?.decode(self.entry)
+    }
+
+    /// Decode an integer field as a `u64`.
+    ///
+    /// Signed values are sign-extended, so an `i32` holding `-1` reads as `u64::MAX`.
+    pub fn u64(&self, name: &str) -> Option<u64> {
+        match self.value(name)? {
+            FieldValue::Unsigned(value) => Some(value),
+            FieldValue::Signed(value) => Some(value as u64),
+        }
+    }
+
+    /// Decode an integer field as an `i64`.
+    ///
+    /// Unsigned values are reinterpreted, so a `u64` holding `u64::MAX` reads as `-1`.
+    pub fn i64(&self, name: &str) -> Option<i64> {
+        match self.value(name)? {
+            FieldValue::Unsigned(value) => Some(value as i64),
+            FieldValue::Signed(value) => Some(value),
+        }
+    }
+
+    /// Returns the string of a `__string` field, see [`FieldDesc::decode_str`].
+    pub fn str(&self, name: &str) -> Option<&'a str> {
+        self.field(name)?.decode_str(self.entry)
+    }
+
+    /// Decode the elements of an array field, see [`FieldDesc::decode_array`].
+    pub fn array(&self, name: &str) -> Option<Vec<FieldValue>> {
+        self.field(name)?.decode_array(self.entry)
+    }
+
+    /// Format a field with its unit, if any, see [`FieldDesc::format`].
+    pub fn format(&self, name: &str) -> Option<String> {
+        self.field(name)?.format(self.entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(ty: &'static str, size: usize, signed: bool) -> FieldDesc {
+        FieldDesc {
+            name: "value",
+            ty,
+            offset: 0,
+            size,
+            signed,
+            array_len: 0,
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn signed_fields_are_sign_extended() {
+        let entry = (-5i16).to_ne_bytes();
+        assert_eq!(
+            field("i16", 2, true).decode(&entry),
+            Some(FieldValue::Signed(-5))
+        );
+        assert_eq!(
+            field("u16", 2, false).decode(&entry),
+            Some(FieldValue::Unsigned(0xfffb))
+        );
+        assert_eq!(field("i16", 2, true).format(&entry).unwrap(), "-5");
+    }
+
+    #[test]
+    fn each_size_decodes_in_native_byte_order() {
+        let decode = |entry: &[u8], signed| field("", entry.len(), signed).decode(entry);
+        assert_eq!(decode(&[0xab], false), Some(FieldValue::Unsigned(0xab)));
+        let value = 0x1234u16.to_ne_bytes();
+        assert_eq!(decode(&value, false), Some(FieldValue::Unsigned(0x1234)));
+        let value = 0x1234_5678u32.to_ne_bytes();
+        assert_eq!(
+            decode(&value, false),
+            Some(FieldValue::Unsigned(0x1234_5678))
+        );
+        let value = (-2i32).to_ne_bytes();
+        assert_eq!(decode(&value, true), Some(FieldValue::Signed(-2)));
+        let value = 0x0102_0304_0506_0708u64.to_ne_bytes();
+        assert_eq!(
+            decode(&value, false),
+            Some(FieldValue::Unsigned(0x0102_0304_0506_0708))
+        );
+        assert_eq!(decode(&[0; 3], false), None);
+    }
+
+    #[test]
+    fn probe_falls_back_for_other_types() {
+        struct Opaque;
+        const {
+            assert!(FieldProbe::<i32>::SIGNED);
+            assert!(FieldProbe::<[i8; 3]>::SIGNED);
+            assert!(FieldProbe::<[i8; 3]>::ARRAY_LEN == 3);
+            assert!(!FieldProbe::<Opaque>::SIGNED);
+            assert!(FieldProbe::<Opaque>::ARRAY_LEN == 0);
+        }
+    }
+}
@@ -0,0 +1,869 @@
+//! Filter expressions over the fields of a tracepoint.
+//!
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use crate::{FieldDesc, FieldValue, FilterError};
+
+/// A binary operator of a filter expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterOp {
+    /// `||`
+    Or,
+    /// `&&`
+    And,
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+    /// `~`, a glob match of a string
+    Glob,
+    /// `|`
+    BitOr,
+    /// `^`
+    BitXor,
+    /// `&`
+    BitAnd,
+    /// `<<`
+    Shl,
+    /// `>>`
+    Shr,
+}
+
+impl FilterOp {
+    /// Returns the operator as written in an expression, e.g. `<<`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterOp::Or => "||",
+            FilterOp::And => "&&",
+            FilterOp::Eq => "==",
+            FilterOp::Ne => "!=",
+            FilterOp::Lt => "<",
+            FilterOp::Le => "<=",
+            FilterOp::Gt => ">",
+            FilterOp::Ge => ">=",
+            FilterOp::Glob => "~",
+            FilterOp::BitOr => "|",
+            FilterOp::BitXor => "^",
+            FilterOp::BitAnd => "&",
+            FilterOp::Shl => "<<",
+            FilterOp::Shr => ">>",
+        }
+    }
+
+    /// Whether the operator compares its operands.
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            FilterOp::Eq
+                | FilterOp::Ne
+                | FilterOp::Lt
+                | FilterOp::Le
+                | FilterOp::Gt
+                | FilterOp::Ge
+                | FilterOp::Glob
+        )
+    }
+
+    /// The binding strength of the operator, higher binds tighter.
+    fn precedence(&self) -> u8 {
+        match self {
+            FilterOp::Or => 1,
+            FilterOp::And => 2,
+            FilterOp::Eq
+            | FilterOp::Ne
+            | FilterOp::Lt
+            | FilterOp::Le
+            | FilterOp::Gt
+            | FilterOp::Ge
+            | FilterOp::Glob => 3,
+            FilterOp::BitOr => 4,
+            FilterOp::BitXor => 5,
+            FilterOp::BitAnd => 6,
+            FilterOp::Shl | FilterOp::Shr => 7,
+        }
+    }
+}
+
+/// A node of a compiled filter expression.
+///
+/// Its [`Display`](core::fmt::Display) writes every binary node in parentheses, so the
+/// output compiles back to the same expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterExpr {
+    /// A number.
+    Number(FieldValue),
+    /// A string, compared to a `__string` field.
+    Str(String),
+    /// A field read from the entry.
+    Field(&'static FieldDesc),
+    /// The logical negation of an expression.
+    Not(Box<FilterExpr>),
+    /// A binary operation.
+    Binary {
+        /// The operator.
+        op: FilterOp,
+        /// The left operand.
+        lhs: Box<FilterExpr>,
+        /// The right operand.
+        rhs: Box<FilterExpr>,
+    },
+}
+
+impl FilterExpr {
+    /// Evaluate the expression against the entry.
+    ///
+    /// Returns `None` if a field it reads lies outside the entry. A comparison with such
+    /// an operand is false rather than `None`, so the rest of the expression still applies.
+    fn value(&self, entry: &[u8]) -> Option<i128> {
+        match self {
+            FilterExpr::Number(value) => Some(widen(*value)),
+            FilterExpr::Str(_) => None,
+            FilterExpr::Field(field) => field.decode(entry).map(widen),
+            FilterExpr::Not(expr) => Some((!expr.is_true(entry)) as i128),
+            FilterExpr::Binary { op, lhs, rhs } => match op {
+                FilterOp::Or => Some((lhs.is_true(entry) || rhs.is_true(entry)) as i128),
+                FilterOp::And => Some((lhs.is_true(entry) && rhs.is_true(entry)) as i128),
+                _ if op.is_comparison() && lhs.is_str() => {
+                    let (Some(lhs), Some(rhs)) = (lhs.str_value(entry), rhs.str_value(entry))
+                    else {
+                        return Some(0);
+                    };
+                    let result = match op {
+                        FilterOp::Eq => lhs == rhs,
+                        FilterOp::Ne => lhs != rhs,
+                        _ => crate::glob_match(rhs, lhs),
+                    };
+                    Some(result as i128)
+                }
+                _ if op.is_comparison() => {
+                    let (Some(lhs), Some(rhs)) = (lhs.value(entry), rhs.value(entry)) else {
+                        return Some(0);
+                    };
+                    let result = match op {
+                        FilterOp::Eq => lhs == rhs,
+                        FilterOp::Ne => lhs != rhs,
+                        FilterOp::Lt => lhs < rhs,
+                        FilterOp::Le => lhs <= rhs,
+                        FilterOp::Gt => lhs > rhs,
+                        _ => lhs >= rhs,
+                    };
+                    Some(result as i128)
+                }
+                _ => {
+                    let (lhs, rhs) = (lhs.value(entry)?, rhs.value(entry)?);
+                    Some(match op {
+                        FilterOp::BitOr => lhs | rhs,
+                        FilterOp::BitXor => lhs ^ rhs,
+                        FilterOp::BitAnd => lhs & rhs,
+                        // Values are 64 bits wide, so bits shifted past bit 63 are lost
+                        FilterOp::Shl => match u32::try_from(rhs) {
+                            Ok(shift @ 0..64) if lhs < 0 => ((lhs as i64) << shift) as i128,
+                            Ok(shift @ 0..64) => ((lhs as u64) << shift) as i128,
+                            _ => 0,
+                        },
+                        _ => lhs >> rhs.clamp(0, 127),
+                    })
+                }
+            },
+        }
+    }
+
+    fn is_true(&self, entry: &[u8]) -> bool {
+        self.value(entry).is_some_and(|value| value != 0)
+    }
+
+    /// Evaluate a string operand against the entry.
+    fn str_value<'a>(&'a self, entry: &'a [u8]) -> Option<&'a str> {
+        match self {
+            FilterExpr::Str(value) => Some(value),
+            FilterExpr::Field(field) => field.decode_str(entry),
+            _ => None,
+        }
+    }
+
+    /// Whether the expression is a string operand.
+    fn is_str(&self) -> bool {
+        match self {
+            FilterExpr::Str(_) => true,
+            FilterExpr::Field(field) => field.is_string(),
+            _ => false,
+        }
+    }
+}
+
+impl core::fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FilterExpr::Number(value) => write!(f, "{value}"),
+            FilterExpr::Str(value) => write!(f, "\"{value}\""),
+            FilterExpr::Field(field) => write!(f, "{}", field.name),
+            FilterExpr::Not(expr) => write!(f, "!{expr}"),
+            FilterExpr::Binary { op, lhs, rhs } => write!(f, "({lhs} {} {rhs})", op.as_str()),
+        }
+    }
+}
+
+/// Widen a value so that signed and unsigned values compare by their numeric value.
+fn widen(value: FieldValue) -> i128 {
+    match value {
+        FieldValue::Unsigned(value) => value as i128,
+        FieldValue::Signed(value) => value as i128,
+    }
+}
+
+/// A filter expression compiled against the fields of a tracepoint.
+///
+/// The grammar follows the ftrace filter files: fields are compared to numbers, and the
+/// comparisons are combined with `&&`, `||` and `!`, e.g. `(a > 8 && a <= 10) || b > 5`.
+/// Numbers are decimal, possibly negative, or `0x` hexadecimal. Numbers and fields can be
+/// combined with the bitwise operators `&`, `|`, `^`, `<<` and `>>` before they are
+/// compared, e.g. `flags & 0x4 != 0`. The operators bind like in Rust, so bitwise operators
+/// bind tighter than comparisons, which bind tighter than `&&` and `||`.
+///
+/// A `__string` field is compared to a string in double quotes with `==` and `!=`, or
+/// matched against a glob pattern with `~`, e.g. `path ~ "/dev/*"`. Patterns support `*`
+/// and `?`, and strings have no escapes. Any other use of a string, or a string operator
+/// on a numeric field, is a type mismatch.
+///
+/// It is built by [`crate::TracePoint::compile_filter`] and evaluated by
+/// [`crate::TracePoint::evaluate_filter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledFilter {
+    expr: FilterExpr,
+}
+
+impl CompiledFilter {
+    /// Compile the expression against the given fields.
+    pub(crate) fn compile(
+        source: &str,
+        fields: &[&'static FieldDesc],
+    ) -> Result<Self, FilterError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            end: source.len(),
+            fields,
+        };
+        let expr = parser.expr(0)?;
+        if let Some(&(token, offset)) = tokens.get(parser.pos) {
+            return Err(parse_error(
+                offset,
+                match token {
+                    Token::Close => "unmatched ')'",
+                    _ => "expected an operator",
+                },
+            ));
+        }
+        check_numeric(&expr, 0)?;
+        Ok(Self { expr })
+    }
+
+    /// Returns the root of the expression.
+    pub fn expr(&self) -> &FilterExpr {
+        &self.expr
+    }
+
+    /// Whether the entry matches the filter.
+    ///
+    /// Fields are read with bounds checks, see [`FieldDesc::decode`].
+    pub fn matches(&self, entry: &[u8]) -> bool {
+        self.expr.is_true(entry)
+    }
+}
+
+impl core::fmt::Display for CompiledFilter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Number(FieldValue),
+    Str(&'a str),
+    Ident(&'a str),
+    Op(FilterOp),
+    Not,
+    Open,
+    Close,
+}
+
+fn parse_error(offset: usize, message: &str) -> FilterError {
+    FilterError::Parse {
+        offset: Some(offset),
+        message: message.into(),
+    }
+}
+
+/// Split the expression into tokens, each with its offset.
+fn tokenize(source: &str) -> Result<Vec<(Token<'_>, usize)>, FilterError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        let next = bytes.get(pos + 1).copied();
+        let (token, len) = match bytes[pos] {
+            b if b.is_ascii_whitespace() => {
+                pos += 1;
+                continue;
+            }
+            b'(' => (Token::Open, 1),
+            b')' => (Token::Close, 1),
+            b'|' if next == Some(b'|') => (Token::Op(FilterOp::Or), 2),
+            b'&' if next == Some(b'&') => (Token::Op(FilterOp::And), 2),
+            b'=' if next == Some(b'=') => (Token::Op(FilterOp::Eq), 2),
+            b'!' if next == Some(b'=') => (Token::Op(FilterOp::Ne), 2),
+            b'<' if next == Some(b'=') => (Token::Op(FilterOp::Le), 2),
+            b'>' if next == Some(b'=') => (Token::Op(FilterOp::Ge), 2),
+            b'<' if next == Some(b'<') => (Token::Op(FilterOp::Shl), 2),
+            b'>' if next == Some(b'>') => (Token::Op(FilterOp::Shr), 2),
+            b'<' => (Token::Op(FilterOp::Lt), 1),
+            b'>' => (Token::Op(FilterOp::Gt), 1),
+            b'|' => (Token::Op(FilterOp::BitOr), 1),
+            b'^' => (Token::Op(FilterOp::BitXor), 1),
+            b'&' => (Token::Op(FilterOp::BitAnd), 1),
+            b'!' => (Token::Not, 1),
+            b'~' => (Token::Op(FilterOp::Glob), 1),
+            b'"' => {
+                let Some(len) = bytes[pos + 1..].iter().position(|&b| b == b'"') else {
+                    return Err(parse_error(start, "unterminated string"));
+                };
+                (Token::Str(&source[pos + 1..pos + 1 + len]), len + 2)
+            }
+            b'-' if next.is_some_and(|b| b.is_ascii_digit()) => {
+                let len = 1 + word_len(&bytes[pos + 1..]);
+                let value = parse_number(&source[pos + 1..pos + len])
+                    .and_then(|value| 0i64.checked_sub_unsigned(value))
+                    .ok_or_else(|| parse_error(start, "invalid number"))?;
+                let value = match value {
+                    0 => FieldValue::Unsigned(0),
+                    _ => FieldValue::Signed(value),
+                };
+                (Token::Number(value), len)
+            }
+            b if b.is_ascii_digit() => {
+                let len = word_len(&bytes[pos..]);
+                let value = parse_number(&source[pos..pos + len])
+                    .ok_or_else(|| parse_error(start, "invalid number"))?;
+                (Token::Number(FieldValue::Unsigned(value)), len)
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                let len = word_len(&bytes[pos..]);
+                (Token::Ident(&source[pos..pos + len]), len)
+            }
+            _ => return Err(parse_error(start, "unexpected character")),
+        };
+        tokens.push((token, start));
+        pos += len;
+    }
+    Ok(tokens)
+}
+
+/// Returns the length of the identifier or number at the front of `bytes`.
+fn word_len(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .position(|b| !b.is_ascii_alphanumeric() && *b != b'_')
+        .unwrap_or(bytes.len())
+}
+
+/// Parse a decimal or `0x` hexadecimal number.
+fn parse_number(word: &str) -> Option<u64> {
+    match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+/// Check that the operands of the operator at `offset` have the types it supports.
+///
+/// `==` and `!=` take two numbers or two strings, `~` takes a string and a pattern, and
+/// the other operators take numbers.
+fn check_operands(
+    op: FilterOp,
+    lhs: &FilterExpr,
+    rhs: &FilterExpr,
+    offset: usize,
+) -> Result<(), FilterError> {
+    match op {
+        FilterOp::Eq | FilterOp::Ne if lhs.is_str() != rhs.is_str() => {
+            // Blame the field whose type doesn't fit the other operand, if any
+            let field = [lhs, rhs].into_iter().find_map(|expr| match expr {
+                FilterExpr::Field(field) => Some(field),
+                _ => None,
+            });
+            match field {
+                Some(field) => Err(FilterError::TypeMismatch {
+                    field: field.name,
+                    ty: field.ty,
+                }),
+                None => Err(parse_error(
+                    offset,
+                    "a string can't be compared to a number",
+                )),
+            }
+        }
+        FilterOp::Eq | FilterOp::Ne if lhs.is_str() => Ok(()),
+        FilterOp::Glob => {
+            check_str(lhs, offset)?;
+            match rhs {
+                FilterExpr::Str(_) => Ok(()),
+                _ => Err(parse_error(offset, "'~' expects a string pattern")),
+            }
+        }
+        _ => {
+            check_numeric(lhs, offset)?;
+            check_numeric(rhs, offset)
+        }
+    }
+}
+
+/// Check that the operand of the operator at `offset` is a number.
+fn check_numeric(expr: &FilterExpr, offset: usize) -> Result<(), FilterError> {
+    match expr {
+        FilterExpr::Str(_) => Err(parse_error(
+            offset,
+            "strings can only be compared with '==', '!=' and '~'",
+        )),
+        FilterExpr::Field(field) if field.is_string() => Err(FilterError::TypeMismatch {
+            field: field.name,
+            ty: field.ty,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Check that the operand of the operator at `offset` is a string.
+fn check_str(expr: &FilterExpr, offset: usize) -> Result<(), FilterError> {
+    match expr {
+        _ if expr.is_str() => Ok(()),
+        FilterExpr::Field(field) => Err(FilterError::TypeMismatch {
+            field: field.name,
+            ty: field.ty,
+        }),
+        _ => Err(parse_error(offset, "'~' expects a string")),
+    }
+}
+
+struct Parser<'a, 's> {
+    tokens: &'a [(Token<'s>, usize)],
+    pos: usize,
+    /// The length of the expression, the offset of errors at its end.
+    end: usize,
+    fields: &'a [&'static FieldDesc],
+}
+
+impl<'s> Parser<'_, 's> {
+    fn peek(&self) -> Option<(Token<'s>, usize)> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    /// Parse the operators binding at least as tight as `min_precedence`.
+    fn expr(&mut self, min_precedence: u8) -> Result<FilterExpr, FilterError> {
+        let mut lhs = self.unary()?;
+        // Whether `lhs` is a comparison parsed by this loop, rather than in parentheses
+        let mut compared = false;
+        while let Some((Token::Op(op), offset)) = self.peek() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            if op.is_comparison() && compared {
+                return Err(parse_error(offset, "comparisons can't be chained"));
+            }
+            self.pos += 1;
+            let rhs = self.expr(op.precedence() + 1)?;
+            check_operands(op, &lhs, &rhs, offset)?;
+            compared = op.is_comparison();
+            lhs = FilterExpr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<FilterExpr, FilterError> {
+        let Some((token, offset)) = self.peek() else {
+            return Err(parse_error(self.end, "expected an operand"));
+        };
+        self.pos += 1;
+        match token {
+            Token::Not => {
+                let expr = self.unary()?;
+                check_numeric(&expr, offset)?;
+                Ok(FilterExpr::Not(Box::new(expr)))
+            }
+            Token::Open => {
+                let expr = self.expr(0)?;
+                match self.peek() {
+                    Some((Token::Close, _)) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    Some((_, offset)) => Err(parse_error(offset, "expected an operator")),
+                    None => Err(parse_error(offset, "unmatched '('")),
+                }
+            }
+            Token::Number(value) => Ok(FilterExpr::Number(value)),
+            Token::Str(value) => Ok(FilterExpr::Str(value.into())),
+            Token::Ident(name) => self.field(name).map(FilterExpr::Field),
+            Token::Close => Err(parse_error(offset, "unmatched ')'")),
+            Token::Op(_) => Err(parse_error(offset, "expected an operand")),
+        }
+    }
+
+    fn field(&self, name: &str) -> Result<&'static FieldDesc, FilterError> {
+        let Some(field) = self.fields.iter().find(|field| field.name == name) else {
+            return Err(FilterError::UnknownField {
+                name: name.into(),
+                valid: self.fields.iter().map(|field| field.name).collect(),
+            });
+        };
+        if field.is_array() {
+            return Err(FilterError::TypeMismatch {
+                field: field.name,
+                ty: field.ty,
+            });
+        }
+        Ok(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    use super::*;
+    use crate::{
+        RecordBuilder, RecordHeader, TracePoint,
+        testing::{self, Kops, Lock},
+    };
+
+    mod events {
+        #![allow(dead_code)]
+        use crate::testing::{Kops, Lock};
+
+        crate::define_event_trace!(
+            filter_sample,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(filter_tests),
+            TP_PROTO(f: u32, d: i32),
+            TP_STRUCT__entry {
+                flags: u32,
+                delta: i32,
+            },
+            TP_fast_assign {
+                flags: f,
+                delta: d,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("flags={:#x} delta={}", __entry.flags, __entry.delta))
+        );
+    }
+
+    mod string_events {
+        #![allow(dead_code)]
+        use crate::testing::{Kops, Lock};
+
+        crate::define_event_trace!(
+            filter_string,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(filter_tests),
+            TP_PROTO(x: u32, comm: &str),
+            TP_STRUCT__entry {
+                a: u32,
+                name: __string,
+            },
+            TP_fast_assign {
+                a: x,
+                name: __assign_str!(comm),
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+    }
+
+    fn sample() -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("filter_tests", "filter_sample")
+            .unwrap()
+    }
+
+    /// Returns the entry of a `filter_sample` record.
+    fn entry(flags: u32, delta: i32) -> Vec<u8> {
+        let record = RecordBuilder::new(sample().id())
+            .field_u32(flags)
+            .field_i32(delta)
+            .build();
+        let header = RecordHeader::from_bytes(&record).unwrap();
+        record[header.entry_offset()..].to_vec()
+    }
+
+    fn matches(filter: &str, entry: &[u8]) -> bool {
+        let compiled = sample().compile_filter(filter).unwrap();
+        sample().evaluate_filter(&compiled, entry)
+    }
+
+    #[test]
+    fn bitwise_and_tests_flag_bits() {
+        assert!(matches("flags & 0x4 != 0", &entry(0x5, 0)));
+        assert!(!matches("flags & 0x4 != 0", &entry(0x3, 0)));
+        assert!(matches("flags & 0x4 == 0 && delta < 0", &entry(0x3, -2)));
+    }
+
+    #[test]
+    fn bitwise_operators_bind_tighter_than_comparisons() {
+        let compiled = sample().compile_filter("flags & 0x4 != 0").unwrap();
+        let FilterExpr::Binary { op, lhs, rhs } = compiled.expr() else {
+            panic!("expected a comparison, got {compiled}");
+        };
+        assert_eq!(*op, FilterOp::Ne);
+        assert!(matches!(
+            **lhs,
+            FilterExpr::Binary {
+                op: FilterOp::BitAnd,
+                ..
+            }
+        ));
+        assert_eq!(**rhs, FilterExpr::Number(FieldValue::Unsigned(0)));
+
+        let entry = entry(0b1010, 0);
+        assert!(matches("flags >> 1 & 1 == 1", &entry));
+        assert!(matches("flags ^ 0x3 & 0x1 == 0xb", &entry));
+        assert!(matches("flags | 0x4 == 0xe", &entry));
+        assert!(matches("(flags ^ 0xf) << 4 == 0x50", &entry));
+        assert!(matches("flags << 63 == 0", &entry));
+        assert!(!matches("flags << 64 != 0", &entry));
+    }
+
+    #[test]
+    fn compiled_expr_round_trips() {
+        let _serial = testing::serial();
+        let compiled = sample()
+            .compile_filter("!(flags & 0x4) && flags >> 1 | 0x1 != 0 || delta >= -3")
+            .unwrap();
+        assert_eq!(
+            compiled.to_string(),
+            "((!(flags & 4) && (((flags >> 1) | 1) != 0)) || (delta >= -3))"
+        );
+        assert_eq!(
+            sample().compile_filter(&compiled.to_string()).unwrap(),
+            compiled
+        );
+        sample().set_compiled_expr(Some(compiled.clone()));
+        assert_eq!(sample().get_compiled_expr(), Some(compiled));
+        sample().set_compiled_expr(None);
+    }
+
+    #[test]
+    fn filter_applies_to_fired_events() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let compiled = tracepoint.compile_filter("flags & 0x4 != 0").unwrap();
+        tracepoint.set_compiled_expr(Some(compiled));
+        tracepoint.enable_default();
+        events::trace_filter_sample(0x5, 1);
+        events::trace_filter_sample(0x3, 2);
+        tracepoint.disable_default();
+        tracepoint.set_compiled_expr(None);
+        let records = testing::take_records(tracepoint.id());
+        assert_eq!(records.len(), 1);
+        let header = RecordHeader::from_bytes(&records[0]).unwrap();
+        let view = crate::FieldView::new(tracepoint.fields(), &records[0][header.entry_offset()..]);
+        assert_eq!(view.u64("flags"), Some(0x5));
+    }
+
+    #[test]
+    fn syntax_errors_carry_their_offset() {
+        let error = |filter: &str| match sample().compile_filter(filter) {
+            Err(FilterError::Parse { offset, message }) => (offset, message),
+            other => panic!("{filter}: unexpected {other:?}"),
+        };
+        assert_eq!(
+            error("(flags & 1 != 0"),
+            (Some(0), "unmatched '('".to_string())
+        );
+        assert_eq!(
+            error("flags & 1) != 0"),
+            (Some(9), "unmatched ')'".to_string())
+        );
+        assert_eq!(
+            error("flags & != 0"),
+            (Some(8), "expected an operand".to_string())
+        );
+        assert_eq!(
+            error("flags == 1 == 1"),
+            (Some(11), "comparisons can't be chained".to_string())
+        );
+        assert_eq!(
+            error("flags # 1"),
+            (Some(6), "unexpected character".to_string())
+        );
+        assert!(matches!(
+            sample().compile_filter("flag & 1"),
+            Err(FilterError::UnknownField { .. })
+        ));
+    }
+
+    fn string_sample() -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("filter_tests", "filter_string")
+            .unwrap()
+    }
+
+    /// Fire `filter_string` once per name with the filter set, and return the names recorded.
+    fn fire_filtered(filter: &str, names: &[&str]) -> Vec<String> {
+        let tracepoint = string_sample();
+        let compiled = tracepoint.compile_filter(filter).unwrap();
+        tracepoint.set_compiled_expr(Some(compiled));
+        tracepoint.enable_default();
+        for name in names {
+            string_events::trace_filter_string(1, name);
+        }
+        tracepoint.disable_default();
+        tracepoint.set_compiled_expr(None);
+        testing::take_records(tracepoint.id())
+            .iter()
+            .map(|record| {
+                let header = RecordHeader::from_bytes(record).unwrap();
+                let entry = &record[header.entry_offset()..];
+                let view = crate::FieldView::new(tracepoint.fields(), entry);
+                view.str("name").unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn glob_matches_string_fields() {
+        let _serial = testing::serial();
+        let names = ["sh", "bash", "openat", "shell"];
+        assert_eq!(fire_filtered("name ~ \"*sh\"", &names), ["sh", "bash"]);
+        assert_eq!(fire_filtered("name ~ \"sh*\"", &names), ["sh", "shell"]);
+        assert_eq!(fire_filtered("name ~ \"?pen*\"", &names), ["openat"]);
+        assert!(fire_filtered("name ~ \"zsh\"", &names).is_empty());
+    }
+
+    #[test]
+    fn string_equality_matches_string_fields() {
+        let _serial = testing::serial();
+        let names = ["sh", "openat", "open"];
+        assert_eq!(fire_filtered("name == \"openat\"", &names), ["openat"]);
+        assert_eq!(
+            fire_filtered("name != \"openat\" && a == 1", &names),
+            ["sh", "open"]
+        );
+        assert!(fire_filtered("name == \"openat2\"", &names).is_empty());
+    }
+
+    #[test]
+    fn string_operators_check_types() {
+        let tracepoint = string_sample();
+        let mismatch = |filter: &str| match tracepoint.compile_filter(filter) {
+            Err(FilterError::TypeMismatch { field, ty }) => (field, ty),
+            other => panic!("{filter}: unexpected {other:?}"),
+        };
+        assert_eq!(mismatch("a ~ \"1*\""), ("a", "u32"));
+        assert_eq!(mismatch("a == \"1\""), ("a", "u32"));
+        assert_eq!(mismatch("name == 1"), ("name", "__string"));
+        assert_eq!(mismatch("name & 1 != 0"), ("name", "__string"));
+        assert_eq!(mismatch("!name"), ("name", "__string"));
+        assert!(matches!(
+            tracepoint.compile_filter("name ~ name"),
+            Err(FilterError::Parse {
+                offset: Some(5),
+                ..
+            })
+        ));
+        assert!(matches!(
+            tracepoint.compile_filter("\"sh\" < 1"),
+            Err(FilterError::Parse {
+                offset: Some(5),
+                ..
+            })
+        ));
+        assert!(matches!(
+            tracepoint.compile_filter("name == \"sh"),
+            Err(FilterError::Parse {
+                offset: Some(8),
+                ..
+            })
+        ));
+        let compiled = tracepoint.compile_filter("name ~ \"s*\" || a > 2").unwrap();
+        assert_eq!(compiled.to_string(), "((name ~ \"s*\") || (a > 2))");
+        assert_eq!(
+            tracepoint.compile_filter(&compiled.to_string()).unwrap(),
+            compiled
+        );
+    }
+
+    #[test]
+    fn truncated_entries_do_not_match() {
+        let full = entry(0x5, -1);
+        assert!(matches("flags & 0x4 != 0", &full));
+        for len in [0, 4, 8, 10, full.len() - 1] {
+            assert!(!matches("flags & 0x4 != 0", &full[..len]), "{len} bytes");
+            assert!(!matches("delta < 0", &full[..len]), "{len} bytes");
+        }
+        // Every comparison reading past the end is false on its own
+        let compiled = sample().compile_filter("flags == 5 || delta != 0").unwrap();
+        assert!(compiled.matches(&full));
+        assert!(!compiled.matches(&full[..10]));
+
+        let _serial = testing::serial();
+        let tracepoint = string_sample();
+        tracepoint.enable_default();
+        string_events::trace_filter_string(1, "openat");
+        tracepoint.disable_default();
+        let record = testing::take_records(tracepoint.id()).remove(0);
+        let header = RecordHeader::from_bytes(&record).unwrap();
+        let full = &record[header.entry_offset()..];
+        let compiled = tracepoint.compile_filter("name == \"openat\"").unwrap();
+        assert!(tracepoint.evaluate_filter(&compiled, full));
+        // Cut in the dynamic area, the string reads as empty
+        let fixed = &full[..tracepoint.entry_size()];
+        assert!(!tracepoint.evaluate_filter(&compiled, fixed));
+        assert!(!tracepoint.evaluate_filter(&compiled, &fixed[..fixed.len() - 1]));
+    }
+
+    #[test]
+    fn filter_on_common_pid() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let compiled = tracepoint.compile_filter("common_pid == 42").unwrap();
+        tracepoint.set_compiled_expr(Some(compiled));
+        tracepoint.enable_default();
+        for pid in [41, 42, 43, 42] {
+            testing::set_pid(pid);
+            events::trace_filter_sample(pid, 0);
+        }
+        tracepoint.disable_default();
+        tracepoint.set_compiled_expr(None);
+        let records = testing::take_records(tracepoint.id());
+        assert_eq!(records.len(), 2);
+        for record in records {
+            let header = RecordHeader::from_bytes(&record).unwrap();
+            let view =
+                crate::FieldView::new(&crate::COMMON_FIELDS, &record[header.entry_offset()..]);
+            assert_eq!(view.i64("common_pid"), Some(42));
+        }
+    }
+}
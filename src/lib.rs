@@ -12,13 +12,21 @@
 extern crate alloc;
 
 mod basic_macro;
+mod diagnostic;
+mod error;
+mod field;
+mod filter;
 mod point;
 mod ptr;
+#[cfg(any(test, feature = "test-utils"))]
+mod test_utils;
+#[cfg(test)]
+mod testing;
 mod trace_pipe;
 
 use alloc::{
     boxed::Box,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     format,
     string::{String, ToString},
     sync::Arc,
@@ -26,22 +34,38 @@ use alloc::{
 };
 use core::{
     ops::{Deref, DerefMut},
-    sync::atomic::AtomicUsize,
+    sync::atomic::AtomicBool,
 };
 
+pub use diagnostic::{DiagnosticKind, DiagnosticNotice};
+pub use error::{FilterError, ParseError, TraceError};
+pub use field::{
+    __string, COMMON_FIELDS, DataLoc, DynamicArea, FieldDesc, FieldValue, FieldView, TraceField,
+};
+#[doc(hidden)]
+pub use field::{FieldProbe, TraceFieldFallback};
+pub use filter::{CompiledFilter, FilterExpr, FilterOp};
 use lock_api::{Mutex, MutexGuard, RawMutex};
 pub use paste::paste;
 pub use point::{
-    CommonTracePointMeta, RawTracePointCallBackFunc, TraceEntry, TracePoint,
-    TracePointCallBackFunc, TracePointFunc,
+    CommonTracePointMeta, DispatchMode, EnableState, HistAggregate, HistTrigger, RECORD_FLAG_COMM,
+    RECORD_FLAG_MARKER, RECORD_FLAG_TRUNCATED, RECORD_FORMAT_VERSION, RECORD_MAGIC,
+    RawTracePointCallBackFunc, RecordHeader, SinkPolicy, TASK_COMM_LEN, TRACE_FLAG_BH_OFF,
+    TRACE_FLAG_HARDIRQ, TRACE_FLAG_IRQS_NOSUPPORT, TRACE_FLAG_IRQS_OFF, TRACE_FLAG_NEED_RESCHED,
+    TRACE_FLAG_NMI, TRACE_FLAG_PREEMPT_RESCHED, TRACE_FLAG_SOFTIRQ, TraceClock, TraceEntry,
+    TracePoint, TracePointCallBackFunc, TracePointContext, TracePointFieldCallBackFunc,
+    TracePointFunc, TraceTrigger,
 };
-pub use ptr::AsU64;
+use point::{PidFilter, TraceShared, TraceSink};
+pub use ptr::{AsU64, FromU64};
 use static_keys::code_manipulate::CodeManipulator;
+#[cfg(any(test, feature = "test-utils"))]
+pub use test_utils::RecordBuilder;
 pub use tp_lexer;
-use tp_lexer::compile_with_schema;
 pub use trace_pipe::{
-    TraceCmdLineCache, TraceCmdLineCacheSnapshot, TraceEntryParser, TracePipeOps, TracePipeRaw,
-    TracePipeSnapshot,
+    Column, LineTransform, ParseOptions, PerCpuTracePipe, RingMode, SnapshotStats, TimePrecision,
+    TraceCmdLineCache, TraceCmdLineCacheSnapshot, TraceEntryParser, TracePipeCursor, TracePipeOps,
+    TracePipeRaw, TracePipeRawBuilder, TracePipeSink, TracePipeSnapshot,
 };
 
 /// KernelTraceOps trait provides kernel-level operations for tracing.
@@ -50,12 +74,72 @@ pub trait KernelTraceOps: Send + Sync {
     fn time_now() -> u64;
     /// Get the current CPU ID.
     fn cpu_id() -> u32;
+    /// Get the time of a clock synchronized across CPUs, for [`TraceClock::Global`].
+    ///
+    /// By default, this is [`KernelTraceOps::time_now`].
+    fn time_global() -> u64 {
+        Self::time_now()
+    }
+    /// Get the time of the monotonic clock, for [`TraceClock::Mono`].
+    ///
+    /// By default, this is [`KernelTraceOps::time_now`].
+    fn time_mono() -> u64 {
+        Self::time_now()
+    }
+    /// Read a raw cycle counter, for [`TraceClock::Counter`].
+    ///
+    /// By default, this is [`KernelTraceOps::time_now`].
+    fn cycle_counter() -> u64 {
+        Self::time_now()
+    }
     /// Get the current process ID.
     fn current_pid() -> u32;
     /// Push a raw record to the trace pipe.
+    ///
+    /// The record starts with a [`RecordHeader`] followed by the trace entry.
     fn trace_pipe_push_raw_record(buf: &[u8]);
+    /// Write a raw record of `len` bytes to the trace pipe in place.
+    ///
+    /// `write` fills the record, laid out like one passed to
+    /// [`KernelTraceOps::trace_pipe_push_raw_record`]. Implementing this with
+    /// [`TracePipeRaw::reserve`] and [`TracePipeRaw::commit`] lets the generated code write
+    /// the record straight into the buffer, saving the copy of a separately built record.
+    /// By default, the record is built in a temporary buffer and pushed with
+    /// [`KernelTraceOps::trace_pipe_push_raw_record`].
+    fn trace_pipe_write_raw_record(len: usize, write: &mut dyn FnMut(&mut [u8])) {
+        let mut record = alloc::vec![0; len];
+        write(&mut record);
+        Self::trace_pipe_push_raw_record(&record);
+    }
     /// Cache the process name for a given PID.
     fn trace_cmdline_push(pid: u32);
+    /// Get the IRQ state of the current context as `TRACE_FLAG_*` bits, e.g.
+    /// [`TRACE_FLAG_IRQS_OFF`].
+    ///
+    /// The bits are recorded in [`TraceEntry::common_flags`]. By default, no state is reported.
+    fn irq_flags() -> u8 {
+        0
+    }
+    /// Get the preemption count of the current context.
+    ///
+    /// The low nibble is the preemption depth and the high nibble the migrate-disable depth,
+    /// as recorded in [`TraceEntry::common_preempt_count`]. By default, this is zero.
+    fn preempt_count() -> u8 {
+        0
+    }
+    /// Copy the comm of the current task into the buffer, NUL-padded.
+    ///
+    /// This is called for every record while [`TracingEventsManager::set_record_comm`] is
+    /// enabled. By default, the buffer is left empty.
+    fn current_comm(_buf: &mut [u8; TASK_COMM_LEN]) {}
+    /// Look up the comm of a task by PID.
+    ///
+    /// This is the fallback of [`TraceEntryParser::resolve_comm`] for tasks missing from the
+    /// [`TraceCmdLineCache`], e.g. because the cache missed their fork. By default, no comm
+    /// is resolved.
+    fn comm_for_pid(_pid: u32) -> Option<String> {
+        None
+    }
     /// Write data to kernel text memory.
     fn write_kernel_text(addr: *mut core::ffi::c_void, data: &[u8]);
 }
@@ -74,15 +158,70 @@ impl<T: KernelTraceOps> CodeManipulator for KernelCodeManipulator<T> {
 }
 
 /// TracePointMap is a mapping from tracepoint IDs to TracePoint references.
+///
+/// A secondary index by `system` and `name` is kept up to date by
+/// [`TracePointMap::insert`] and [`TracePointMap::remove`].
 #[derive(Debug)]
-pub struct TracePointMap<L: RawMutex + 'static, K: KernelTraceOps + 'static>(
-    BTreeMap<u32, &'static TracePoint<L, K>>,
-);
+pub struct TracePointMap<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    map: BTreeMap<u32, &'static TracePoint<L, K>>,
+    names: BTreeMap<(&'static str, &'static str), u32>,
+}
 
 impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointMap<L, K> {
     /// Create a new TracePointMap
     fn new() -> Self {
-        Self(BTreeMap::new())
+        Self {
+            map: BTreeMap::new(),
+            names: BTreeMap::new(),
+        }
+    }
+
+    /// Insert a tracepoint by ID, returning the tracepoint previously stored under the ID
+    pub fn insert(
+        &mut self,
+        id: u32,
+        tracepoint: &'static TracePoint<L, K>,
+    ) -> Option<&'static TracePoint<L, K>> {
+        let old = self.remove(&id);
+        self.names
+            .insert((tracepoint.system(), tracepoint.name()), id);
+        self.map.insert(id, tracepoint);
+        old
+    }
+
+    /// Remove a tracepoint by ID
+    pub fn remove(&mut self, id: &u32) -> Option<&'static TracePoint<L, K>> {
+        let tracepoint = self.map.remove(id)?;
+        self.names.remove(&(tracepoint.system(), tracepoint.name()));
+        Some(tracepoint)
+    }
+
+    /// Get a tracepoint by its system and name
+    ///
+    /// Tracepoints inserted through the map directly, bypassing [`TracePointMap::insert`],
+    /// are found by a linear scan.
+    pub fn get_by_name(&self, system: &str, name: &str) -> Option<&'static TracePoint<L, K>> {
+        let indexed = self
+            .names
+            .get(&(system, name))
+            .and_then(|id| self.map.get(id))
+            .filter(|tp| tp.system() == system && tp.name() == name);
+        indexed.copied().or_else(|| {
+            self.map
+                .values()
+                .find(|tp| tp.system() == system && tp.name() == name)
+                .copied()
+        })
+    }
+
+    /// Returns the number of registered tracepoints
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Check if no tracepoint is registered
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
     }
 }
 
@@ -90,13 +229,13 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> Deref for TracePointMap
     type Target = BTreeMap<u32, &'static TracePoint<L, K>>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.map
     }
 }
 
 impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> DerefMut for TracePointMap<L, K> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.map
     }
 }
 
@@ -105,6 +244,15 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> DerefMut for TracePoint
 pub struct TracingEventsManager<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
     subsystems: Mutex<L, BTreeMap<String, Arc<EventsSubsystem<L, K>>>>,
     map: Mutex<L, TracePointMap<L, K>>,
+    shared: Arc<TraceShared<L>>,
+    rate_samples: Mutex<L, BTreeMap<u32, RateSample>>,
+}
+
+/// The hit count of a tracepoint at the time of the last rate sample.
+#[derive(Debug, Clone, Copy)]
+struct RateSample {
+    hits: u64,
+    time: u64,
 }
 
 impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracingEventsManager<L, K> {
@@ -112,14 +260,222 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracingEventsManager<L,
         Self {
             subsystems: Mutex::new(BTreeMap::new()),
             map: Mutex::new(map),
+            shared: Arc::new(TraceShared::new()),
+            rate_samples: Mutex::new(BTreeMap::new()),
         }
     }
 
+    /// Add a sink that receives a copy of every record pushed to the trace pipe
+    ///
+    /// Records are still pushed with [`KernelTraceOps::trace_pipe_push_raw_record`] first.
+    /// The policy decides what happens when the sink can't keep up, see [`SinkPolicy`].
+    /// If a sink with the same ID exists, it is replaced.
+    pub fn add_sink(
+        &self,
+        sink_id: usize,
+        sink: Box<dyn TracePipeSink + Send>,
+        policy: SinkPolicy,
+    ) {
+        let mut sinks = self.shared.sinks.lock();
+        let mut updated = BTreeMap::clone(&sinks);
+        updated.insert(sink_id, Arc::new(TraceSink::new(sink, policy)));
+        *sinks = Arc::new(updated);
+    }
+
+    /// Remove the sink by ID
+    pub fn remove_sink(&self, sink_id: usize) -> Option<Box<dyn TracePipeSink + Send>> {
+        let mut sinks = self.shared.sinks.lock();
+        let mut updated = BTreeMap::clone(&sinks);
+        let sink = updated.remove(&sink_id)?;
+        *sinks = Arc::new(updated);
+        drop(sinks);
+        // The record path may still hold the sink, so take the pipe out of it.
+        sink.sink.lock().take()
+    }
+
+    /// Get the number of records dropped by the sink because of backpressure
+    pub fn sink_dropped_count(&self, sink_id: usize) -> Option<usize> {
+        self.shared
+            .sinks()
+            .get(&sink_id)
+            .map(|sink| sink.dropped.load(core::sync::atomic::Ordering::Relaxed))
+    }
+
+    /// Set the pipe that receives a [`DiagnosticNotice`] whenever a record is dropped or truncated
+    ///
+    /// The notices are pushed with [`TracePipeSink::try_push`] and skipped if the pipe is busy,
+    /// so a slow reader never stalls the record path. Returns the previous pipe, if any.
+    pub fn set_diagnostic_pipe(
+        &self,
+        pipe: Box<dyn TracePipeSink + Send>,
+    ) -> Option<Box<dyn TracePipeSink + Send>> {
+        self.shared.diagnostic.lock().replace(pipe)
+    }
+
+    /// Remove the diagnostic pipe
+    pub fn take_diagnostic_pipe(&self) -> Option<Box<dyn TracePipeSink + Send>> {
+        self.shared.diagnostic.lock().take()
+    }
+
+    /// Set whether the comm of the current task is captured into every record
+    ///
+    /// The comm is read with [`KernelTraceOps::current_comm`] at emit time and used by
+    /// [`TraceEntryParser`] instead of the cmdline cache, which can miss or go stale.
+    /// This costs [`TASK_COMM_LEN`] bytes per record.
+    pub fn set_record_comm(&self, record_comm: bool) {
+        self.shared
+            .record_comm
+            .store(record_comm, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Check if the comm of the current task is captured into every record
+    pub fn record_comm(&self) -> bool {
+        self.shared
+            .record_comm
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Turn tracing on or off globally, like ftrace's `tracing_on`
+    ///
+    /// While tracing is off, enabled tracepoints still fire and evaluate their filters, but
+    /// no record is pushed to the trace pipe or any sink. A `traceon` or `traceoff` trigger
+    /// flips the same switch, so it may turn tracing back on or off behind the caller, see
+    /// [`TracePointTriggerFile`].
+    pub fn set_tracing_on(&self, tracing_on: bool) {
+        self.shared
+            .tracing_on
+            .store(tracing_on, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Check if tracing is on
+    pub fn tracing_on(&self) -> bool {
+        self.shared
+            .tracing_on
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Set whether the event callbacks and raw event callbacks run while tracing is off
+    ///
+    /// They do by default.
+    pub fn set_callbacks_while_off(&self, callbacks_while_off: bool) {
+        self.shared
+            .callbacks_while_off
+            .store(callbacks_while_off, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Restrict recording to the CPUs set in `mask`, like ftrace's `tracing_cpumask`
+    ///
+    /// Bit `n` stands for the CPU with ID `n`, see [`KernelTraceOps::cpu_id`]. Records from a
+    /// CPU whose bit is clear are not pushed to the trace pipe or any sink, and are counted
+    /// by [`TracingEventsManager::cpumask_skipped`]. Callbacks still run. CPUs with an ID of
+    /// 64 or more are always recorded. The default mask has all bits set.
+    pub fn set_cpumask(&self, mask: u64) {
+        self.shared
+            .cpumask
+            .store(mask, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the mask of CPUs whose records are pushed
+    pub fn cpumask(&self) -> u64 {
+        self.shared
+            .cpumask
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the number of records skipped because their CPU was masked out
+    pub fn cpumask_skipped(&self) -> u64 {
+        self.shared
+            .cpumask_skipped
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Restrict recording to the given PIDs, like ftrace's `set_event_pid`
+    ///
+    /// Records pushed while [`KernelTraceOps::current_pid`] is not in `pids` are not pushed
+    /// to the trace pipe or any sink. Callbacks still run. An empty set clears the filter.
+    /// By default, all PIDs are recorded.
+    pub fn set_pid_filter(&self, pids: &[u32]) {
+        self.store_pid_filter(pids, false);
+    }
+
+    /// Skip recording for the given PIDs, like ftrace's `set_event_notrace_pid`
+    ///
+    /// This replaces the filter set with [`TracingEventsManager::set_pid_filter`]. An empty
+    /// set clears the filter.
+    pub fn set_pid_filter_exclude(&self, pids: &[u32]) {
+        self.store_pid_filter(pids, true);
+    }
+
+    /// Record all PIDs again
+    pub fn clear_pid_filter(&self) {
+        self.shared.pid_filter.set(None);
+    }
+
+    fn store_pid_filter(&self, pids: &[u32], exclude: bool) {
+        let filter = (!pids.is_empty()).then(|| PidFilter {
+            pids: pids.iter().copied().collect(),
+            exclude,
+        });
+        self.shared.pid_filter.set(filter);
+    }
+
+    /// Select the clock that timestamps the records, like ftrace's `trace_clock`
+    pub fn set_clock(&self, clock: TraceClock) {
+        self.shared
+            .clock
+            .store(clock as u8, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Get the clock that timestamps the records
+    pub fn current_clock(&self) -> TraceClock {
+        TraceClock::from_u8(
+            self.shared
+                .clock
+                .load(core::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
     /// Get the tracepoint map
     pub fn tracepoint_map(&self) -> MutexGuard<'_, L, TracePointMap<L, K>> {
         self.map.lock()
     }
 
+    /// Write a message into the trace stream, like ftrace's `trace_marker`
+    ///
+    /// The message is pushed as a marker record, see [`RECORD_FLAG_MARKER`], timestamped and
+    /// attributed to the current CPU and task like the records of the tracepoints, so it can
+    /// be correlated with them. [`TraceEntryParser`] renders it as `tracing_mark_write: msg`.
+    /// Nothing is pushed while tracing is off. Messages longer than a record can hold are
+    /// truncated.
+    pub fn trace_marker(&self, msg: &str) {
+        self.shared.push_marker::<K>(msg);
+    }
+
+    /// Call `f` for every tracepoint, in ID order
+    ///
+    /// The tracepoint map is locked once for the whole iteration, so `f` must not call back
+    /// into methods of the manager that lock it, e.g. [`TracingEventsManager::tracepoint_map`].
+    pub fn for_each_tracepoint(&self, f: &mut dyn FnMut(&'static TracePoint<L, K>)) {
+        for tracepoint in self.map.lock().values() {
+            f(tracepoint);
+        }
+    }
+
+    /// Call `f` for every tracepoint of the subsystem, in ID order
+    ///
+    /// See [`TracingEventsManager::for_each_tracepoint`].
+    pub fn for_each_tracepoint_in(
+        &self,
+        subsystem_name: &str,
+        f: &mut dyn FnMut(&'static TracePoint<L, K>),
+    ) {
+        for tracepoint in self.map.lock().values() {
+            if tracepoint.system() == subsystem_name {
+                f(tracepoint);
+            }
+        }
+    }
+
     /// Create a subsystem by name
     ///
     /// If the subsystem already exists, return the existing subsystem.
@@ -146,6 +502,26 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracingEventsManager<L,
         self.subsystems.lock().remove(subsystem_name)
     }
 
+    /// Remove a single event from its subsystem and from the tracepoint map
+    ///
+    /// The tracepoint is disabled first, so it stops producing records that could no
+    /// longer be parsed by ID. The subsystem is kept even if it becomes empty.
+    pub fn remove_event(
+        &self,
+        subsystem_name: &str,
+        event_name: &str,
+    ) -> Option<Arc<EventInfo<L, K>>> {
+        let event = self
+            .get_subsystem(subsystem_name)?
+            .remove_event(event_name)?;
+        let tracepoint = event.tracepoint();
+        tracepoint.disable_default();
+        tracepoint.disable_event();
+        self.map.lock().remove(&tracepoint.id());
+        self.rate_samples.lock().remove(&tracepoint.id());
+        Some(event)
+    }
+
     /// Get all subsystems
     pub fn subsystem_names(&self) -> Vec<String> {
         self.subsystems
@@ -154,19 +530,373 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracingEventsManager<L,
             .cloned()
             .collect::<Vec<String>>()
     }
+
+    /// Get the names of all subsystems, sorted
+    ///
+    /// This mirrors `available_subsystems` in the tracing directory.
+    pub fn available_subsystems(&self) -> Vec<String> {
+        self.subsystem_names()
+    }
+
+    /// Get all events as `system:name`, sorted
+    ///
+    /// This mirrors `available_events` in the tracing directory.
+    pub fn available_events(&self) -> Vec<String> {
+        let mut events = Vec::new();
+        for (system, subsystem) in self.subsystems.lock().iter() {
+            for name in subsystem.event_names() {
+                events.push(format!("{system}:{name}"));
+            }
+        }
+        events.sort();
+        events
+    }
+
+    /// Check that the tracepoint map and the events of all subsystems are consistent
+    ///
+    /// Every tracepoint must be stored under its own ID and have an event in its subsystem,
+    /// and every event must refer to a tracepoint in the map. Returns the first inconsistency.
+    pub fn validate(&self) -> Result<(), TraceError> {
+        let map = self.map.lock();
+        let subsystems = self.subsystems.lock();
+        let mut seen: Vec<&'static TracePoint<L, K>> = Vec::with_capacity(map.len());
+        for (key, tracepoint) in map.iter() {
+            if *key != tracepoint.id() {
+                return Err(TraceError::IdMismatch {
+                    key: *key,
+                    id: tracepoint.id(),
+                });
+            }
+            if seen.iter().any(|tp| core::ptr::eq(*tp, *tracepoint)) {
+                return Err(TraceError::DuplicateEvent {
+                    system: tracepoint.system(),
+                    name: tracepoint.name(),
+                });
+            }
+            seen.push(tracepoint);
+            let event = subsystems
+                .get(tracepoint.system())
+                .and_then(|subsys| subsys.get_event(tracepoint.name()));
+            match event {
+                Some(event) if core::ptr::eq(event.tracepoint(), *tracepoint) => {}
+                _ => {
+                    return Err(TraceError::MissingEvent {
+                        system: tracepoint.system(),
+                        name: tracepoint.name(),
+                    });
+                }
+            }
+        }
+        for (subsys_name, subsys) in subsystems.iter() {
+            for (event_name, event) in subsys.events.lock().iter() {
+                let tracepoint = event.tracepoint();
+                let registered = map
+                    .get(&tracepoint.id())
+                    .is_some_and(|tp| core::ptr::eq(*tp, tracepoint));
+                if !registered {
+                    return Err(TraceError::DanglingEvent {
+                        system: subsys_name.clone(),
+                        name: event_name.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sample the hit rate of every tracepoint
+    ///
+    /// Returns `(system, name, events per second)` computed from the hit count since the
+    /// last sample. The last sample is only advanced once at least `window_ns` has passed,
+    /// so frequent calls report the rate over a window of at least that length. The first
+    /// sample of a tracepoint reports a rate of zero.
+    pub fn sample_rates(&self, window_ns: u64) -> Vec<(String, String, f64)> {
+        let now = K::time_now();
+        let map = self.map.lock();
+        let mut samples = self.rate_samples.lock();
+        let mut rates = Vec::with_capacity(map.len());
+        for (id, tracepoint) in map.iter() {
+            let hits = tracepoint.hit_count();
+            let last = *samples.entry(*id).or_insert(RateSample { hits, time: now });
+            let elapsed = now.saturating_sub(last.time);
+            let rate = if elapsed == 0 {
+                0.0
+            } else {
+                hits.saturating_sub(last.hits) as f64 * 1_000_000_000.0 / elapsed as f64
+            };
+            if elapsed >= window_ns {
+                samples.insert(*id, RateSample { hits, time: now });
+            }
+            rates.push((
+                tracepoint.system().to_string(),
+                tracepoint.name().to_string(),
+                rate,
+            ));
+        }
+        rates
+    }
+
+    /// Find all events that have a field with the given name
+    ///
+    /// Returns the `(system, name)` pairs of the matching events, including matches on the
+    /// common fields.
+    pub fn events_with_field(&self, field: &str) -> Vec<(String, String)> {
+        self.map
+            .lock()
+            .values()
+            .filter(|tp| tp.field(field).is_some())
+            .map(|tp| (tp.system().to_string(), tp.name().to_string()))
+            .collect()
+    }
+
+    /// Get the names of the fields of all tracepoints, including the common fields
+    ///
+    /// The names are sorted and deduplicated, e.g. to suggest fields while editing a filter.
+    pub fn all_field_names(&self) -> Vec<String> {
+        let mut names = COMMON_FIELDS
+            .iter()
+            .map(|field| field.name)
+            .collect::<BTreeSet<&'static str>>();
+        for tracepoint in self.map.lock().values() {
+            names.extend(tracepoint.fields().iter().map(|field| field.name));
+        }
+        names.into_iter().map(String::from).collect()
+    }
+
+    /// Enable the events matching a Linux `set_event` style pattern
+    ///
+    /// The pattern is `system:event`, where either part may use the `*` and `?` wildcards,
+    /// e.g. `sched:*` or `*:sys_enter_openat`. A pattern without `:` matches events whose
+    /// system or name matches it. A leading `!` disables the matching events instead.
+    ///
+    /// Returns the number of matching events, or [`TraceError::NoMatchingEvent`] if there are none.
+    pub fn set_event(&self, pattern: &str) -> Result<usize, TraceError> {
+        let (enable, token) = match pattern.strip_prefix('!') {
+            Some(token) => ('0', token),
+            None => ('1', pattern),
+        };
+        let (system, event) = match token.split_once(':') {
+            Some((system, event)) => (Some(system), Some(event)),
+            None => (None, None),
+        };
+        let mut count = 0;
+        for subsystem_name in self.subsystem_names() {
+            let Some(subsystem) = self.get_subsystem(&subsystem_name) else {
+                continue;
+            };
+            for event_name in subsystem.event_names() {
+                let matched = match (system, event) {
+                    (Some(system), Some(event)) => {
+                        glob_match(system, &subsystem_name) && glob_match(event, &event_name)
+                    }
+                    _ => glob_match(token, &subsystem_name) || glob_match(token, &event_name),
+                };
+                if !matched {
+                    continue;
+                }
+                if let Some(event_info) = subsystem.get_event(&event_name) {
+                    event_info.enable_file().write(enable);
+                    count += 1;
+                }
+            }
+        }
+        if count == 0 {
+            return Err(TraceError::NoMatchingEvent {
+                pattern: pattern.to_string(),
+            });
+        }
+        Ok(count)
+    }
+
+    /// Export the current configuration as ftrace shell commands
+    ///
+    /// Replaying the commands on a Linux ftrace setup disables all events, then enables
+    /// the enabled events, sets their filters and triggers and sets `trace_clock`,
+    /// `tracing_cpumask` if it is restricted, and `tracing_on`. The trace buffer is owned by
+    /// the kernel behind [`KernelTraceOps`], so its size isn't exported.
+    pub fn export_commands(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+        commands.push(format!("echo 0 > {TRACING_DIR}/events/enable"));
+        let subsystems = self.subsystems.lock();
+        for (subsystem_name, subsystem) in subsystems.iter() {
+            for (event_name, event) in subsystem.events.lock().iter() {
+                let event_dir = format!("{TRACING_DIR}/events/{subsystem_name}/{event_name}");
+                if let Some(filter) = event.filter_file().filter_expr() {
+                    commands.push(format!(
+                        "echo '{}' > {event_dir}/filter",
+                        filter.replace('\'', "'\\''")
+                    ));
+                }
+                if let Some(trigger) = event.tracepoint().trigger() {
+                    commands.push(format!("echo {} > {event_dir}/trigger", trigger.name()));
+                }
+                if let Some(hist) = event.tracepoint().hist_trigger() {
+                    commands.push(format!("echo '{}' > {event_dir}/trigger", hist.name()));
+                }
+                if event.tracepoint().default_is_enabled() {
+                    commands.push(format!("echo 1 > {event_dir}/enable"));
+                }
+            }
+        }
+        commands.push(format!(
+            "echo {} > {TRACING_DIR}/trace_clock",
+            self.current_clock().name()
+        ));
+        if self.cpumask() != u64::MAX {
+            commands.push(format!(
+                "echo {:x} > {TRACING_DIR}/tracing_cpumask",
+                self.cpumask()
+            ));
+        }
+        if let Some(filter) = self.shared.pid_filter.get() {
+            let pids = filter
+                .pids
+                .iter()
+                .map(|pid| pid.to_string())
+                .collect::<Vec<_>>();
+            let file = if filter.exclude {
+                "set_event_notrace_pid"
+            } else {
+                "set_event_pid"
+            };
+            commands.push(format!("echo '{}' > {TRACING_DIR}/{file}", pids.join(" ")));
+        }
+        commands.push(format!(
+            "echo {} > {TRACING_DIR}/tracing_on",
+            self.tracing_on() as u8
+        ));
+        commands
+    }
 }
 
+/// Match the text against a glob pattern, where `*` matches any run of characters and
+/// `?` matches a single character.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    // The position after the last `*` and the text position it is tried against
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(c) if *c == b'?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// The tracing directory of a Linux ftrace setup.
+const TRACING_DIR: &str = "/sys/kernel/debug/tracing";
+
 /// EventsSubsystem represents a collection of events under a specific subsystem.
 #[derive(Debug)]
 pub struct EventsSubsystem<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
     events: Mutex<L, BTreeMap<String, Arc<EventInfo<L, K>>>>,
+    paused: Arc<AtomicBool>,
+    raw_callbacks_all: Mutex<L, BTreeMap<usize, Vec<String>>>,
 }
 
 impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> EventsSubsystem<L, K> {
     fn new() -> Self {
         Self {
             events: Mutex::new(BTreeMap::new()),
+            paused: Arc::new(AtomicBool::new(false)),
+            raw_callbacks_all: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Pause or resume recording of all events in the subsystem
+    ///
+    /// Unlike disabling each event, this does not patch any code. Paused events are
+    /// still dispatched but return before the entry is assembled.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused
+            .store(paused, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Check if recording is paused for the subsystem
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Enable the default print of every event in the subsystem
+    ///
+    /// Returns the number of events that were disabled before. This is like writing `1`
+    /// to the `enable` file of the subsystem.
+    pub fn enable_all(&self) -> usize {
+        let mut count = 0;
+        for event in self.events.lock().values() {
+            if !event.tracepoint().default_is_enabled() {
+                event.tracepoint().enable_default();
+                count += 1;
+            }
         }
+        count
+    }
+
+    /// Disable the default print of every event in the subsystem
+    ///
+    /// Returns the number of events that were enabled before. This is like writing `0`
+    /// to the `enable` file of the subsystem.
+    pub fn disable_all(&self) -> usize {
+        let mut count = 0;
+        for event in self.events.lock().values() {
+            if event.tracepoint().default_is_enabled() {
+                event.tracepoint().disable_default();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Set the filter of every event in the subsystem
+    ///
+    /// This is like writing to the `filter` file of the subsystem, e.g. `common_pid == 1234`.
+    /// The expression is compiled against each event, and written to the filter file of the
+    /// events where it compiles. The events where it doesn't, e.g. because they lack a field
+    /// it refers to, keep their filter and report the error. Writing `0` clears the filter
+    /// of every event, see [`TraceFilterFile::write`].
+    ///
+    /// Returns the result for each event, by event name.
+    pub fn set_filter(&self, filter: &str) -> Vec<(String, Result<(), FilterError>)> {
+        self.events
+            .lock()
+            .iter()
+            .map(|(name, event)| {
+                let result = if filter.starts_with('0') {
+                    event.filter_file().write(filter)
+                } else {
+                    event
+                        .tracepoint()
+                        .compile_filter(filter)
+                        .and_then(|_| event.filter_file().write(filter))
+                };
+                if let Err(e) = &result {
+                    log::warn!("filter not set on {name}: {e}");
+                }
+                (name.clone(), result)
+            })
+            .collect()
+    }
+
+    /// Get the enable file of the subsystem
+    pub fn enable_file(&self) -> SubsystemEnableFile<'_, L, K> {
+        SubsystemEnableFile { subsystem: self }
     }
 
     /// Create an event by name
@@ -176,6 +906,18 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> EventsSubsystem<L, K> {
             .insert(event_name.to_string(), Arc::new(event_info));
     }
 
+    /// Remove the event by name
+    ///
+    /// This only removes the event from the subsystem. Use
+    /// [`TracingEventsManager::remove_event`] to also drop it from the tracepoint map.
+    pub fn remove_event(&self, event_name: &str) -> Option<Arc<EventInfo<L, K>>> {
+        let event = self.events.lock().remove(event_name)?;
+        for names in self.raw_callbacks_all.lock().values_mut() {
+            names.retain(|name| name != event_name);
+        }
+        Some(event)
+    }
+
     /// Get the event by name
     pub fn get_event(&self, event_name: &str) -> Option<Arc<EventInfo<L, K>>> {
         self.events.lock().get(event_name).cloned()
@@ -185,6 +927,47 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> EventsSubsystem<L, K> {
     pub fn event_names(&self) -> Vec<String> {
         self.events.lock().keys().cloned().collect::<Vec<String>>()
     }
+
+    /// Register a raw event callback to every event in the subsystem under one ID
+    ///
+    /// `make_callback` is called once per event. An event that already has a raw callback
+    /// with this ID keeps it. The events are remembered, so that
+    /// [`EventsSubsystem::unregister_raw_callback_all`] removes the callbacks from all of them.
+    pub fn register_raw_callback_all(
+        &self,
+        callback_id: usize,
+        make_callback: &dyn Fn() -> Box<dyn RawTracePointCallBackFunc>,
+    ) {
+        let events = self.events.lock();
+        for event in events.values() {
+            event
+                .tracepoint()
+                .register_raw_event_callback(callback_id, make_callback());
+        }
+        let mut members = self.raw_callbacks_all.lock();
+        let names = members.entry(callback_id).or_default();
+        for name in events.keys() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+
+    /// Unregister a raw event callback registered with
+    /// [`EventsSubsystem::register_raw_callback_all`]
+    pub fn unregister_raw_callback_all(&self, callback_id: usize) {
+        let Some(names) = self.raw_callbacks_all.lock().remove(&callback_id) else {
+            return;
+        };
+        let events = self.events.lock();
+        for name in names {
+            if let Some(event) = events.get(&name) {
+                event
+                    .tracepoint()
+                    .unregister_raw_event_callback(callback_id);
+            }
+        }
+    }
 }
 
 /// EventInfo holds information about a specific trace event.
@@ -194,8 +977,10 @@ pub struct EventInfo<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
     tracepoint: &'static TracePoint<L, K>,
     format: TracePointFormatFile<L, K>,
     id: TracePointIdFile<L, K>,
+    hit_count: TracePointHitCountFile<L, K>,
     filter: TraceFilterFile<L, K>,
-    // trigger:,
+    trigger: TracePointTriggerFile<L, K>,
+    hist: TracePointHistFile<L, K>,
 }
 
 impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> EventInfo<L, K> {
@@ -203,13 +988,19 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> EventInfo<L, K> {
         let enable = TracePointEnableFile::new(tracepoint);
         let format = TracePointFormatFile::new(tracepoint);
         let id = TracePointIdFile::new(tracepoint);
+        let hit_count = TracePointHitCountFile::new(tracepoint);
         let filter = TraceFilterFile::new(tracepoint);
+        let trigger = TracePointTriggerFile::new(tracepoint);
+        let hist = TracePointHistFile::new(tracepoint);
         Self {
             enable,
             tracepoint,
             format,
             id,
+            hit_count,
             filter,
+            trigger,
+            hist,
         }
     }
 
@@ -233,10 +1024,25 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> EventInfo<L, K> {
         &self.id
     }
 
+    /// Get the hit count file
+    pub fn hit_count_file(&self) -> &TracePointHitCountFile<L, K> {
+        &self.hit_count
+    }
+
     /// Get the filter file
     pub fn filter_file(&self) -> &TraceFilterFile<L, K> {
         &self.filter
     }
+
+    /// Get the trigger file
+    pub fn trigger_file(&self) -> &TracePointTriggerFile<L, K> {
+        &self.trigger
+    }
+
+    /// Get the histogram file
+    pub fn hist_file(&self) -> &TracePointHistFile<L, K> {
+        &self.hist
+    }
 }
 
 /// TracePointFormatFile provides a way to get the format of the tracepoint.
@@ -279,6 +1085,14 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointEnableFile<L,
             "0\n"
         }
     }
+    /// Read the state of both the default print and the event
+    ///
+    /// Unlike [`TracePointEnableFile::read`], which only reflects the default print, this
+    /// tells whether the tracepoint is off, fully on or only partially on.
+    pub fn read_state(&self) -> EnableState {
+        self.tracepoint.enable_state()
+    }
+
     /// Enable or disable the tracepoint
     pub fn write(&self, enable: char) {
         match enable {
@@ -291,6 +1105,48 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointEnableFile<L,
     }
 }
 
+/// SubsystemEnableFile provides a way to enable or disable all events of a subsystem.
+#[derive(Debug, Clone)]
+pub struct SubsystemEnableFile<'a, L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    subsystem: &'a EventsSubsystem<L, K>,
+}
+
+impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> SubsystemEnableFile<'_, L, K> {
+    /// Read the subsystem status
+    ///
+    /// Returns `1` if every event of the subsystem is enabled, `0` if none is, and `X` if
+    /// only some are, like ftrace. A subsystem without events reads `0`.
+    pub fn read(&self) -> &'static str {
+        let events = self.subsystem.events.lock();
+        let enabled = events
+            .values()
+            .filter(|event| event.tracepoint().default_is_enabled())
+            .count();
+        if enabled == 0 {
+            "0\n"
+        } else if enabled == events.len() {
+            "1\n"
+        } else {
+            "X\n"
+        }
+    }
+
+    /// Enable or disable all events of the subsystem
+    pub fn write(&self, enable: char) {
+        match enable {
+            '1' => {
+                self.subsystem.enable_all();
+            }
+            '0' => {
+                self.subsystem.disable_all();
+            }
+            _ => {
+                log::warn!("Invalid value for subsystem enable: {enable}");
+            }
+        }
+    }
+}
+
 /// TracePointEnableFile provides a way to enable or disable the tracepoint.
 #[derive(Debug, Clone)]
 pub struct TracePointIdFile<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
@@ -310,6 +1166,166 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointIdFile<L, K>
     }
 }
 
+/// TracePointHitCountFile provides a way to read how many times the tracepoint has fired.
+#[derive(Debug, Clone)]
+pub struct TracePointHitCountFile<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    tracepoint: &'static TracePoint<L, K>,
+}
+
+impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointHitCountFile<L, K> {
+    fn new(tracepoint: &'static TracePoint<L, K>) -> Self {
+        Self { tracepoint }
+    }
+
+    /// Read the tracepoint hit count
+    ///
+    /// Returns the number of hits since init or the last reset.
+    pub fn read(&self) -> String {
+        format!("{}\n", self.tracepoint.hit_count())
+    }
+
+    /// Reset the tracepoint hit count
+    ///
+    /// Writing `0` resets the count, any other value is ignored.
+    pub fn write(&self, value: char) {
+        match value {
+            '0' => self.tracepoint.reset_hit_count(),
+            _ => {
+                log::warn!("Invalid value for tracepoint hit count: {value}");
+            }
+        }
+    }
+}
+
+/// TracePointTriggerFile provides a way to set a trigger on the tracepoint.
+///
+/// The trigger runs when the tracepoint fires and its entry passes the filter, see
+/// [`TraceTrigger`].
+#[derive(Debug, Clone)]
+pub struct TracePointTriggerFile<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    tracepoint: &'static TracePoint<L, K>,
+}
+
+impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointTriggerFile<L, K> {
+    fn new(tracepoint: &'static TracePoint<L, K>) -> Self {
+        Self { tracepoint }
+    }
+
+    /// Read the tracepoint triggers
+    ///
+    /// Returns the triggers one per line, or the available triggers if none is set.
+    pub fn read(&self) -> String {
+        let mut triggers = String::new();
+        if let Some(trigger) = self.tracepoint.trigger() {
+            triggers.push_str(trigger.name());
+            triggers.push('\n');
+        }
+        if let Some(hist) = self.tracepoint.hist_trigger() {
+            triggers.push_str(&format!("{} [active]\n", hist.info()));
+        }
+        if triggers.is_empty() {
+            return "# Available triggers:\n# traceon traceoff hist\n".to_string();
+        }
+        triggers
+    }
+
+    /// Set a tracepoint trigger
+    ///
+    /// Accepts `traceon`, `traceoff`, `hist:keys=<field>` and
+    /// `hist:keys=<field>:vals=<field>`, or the same prefixed with `!` to remove the trigger.
+    /// The `traceon` and `traceoff` triggers replace each other, while a histogram trigger
+    /// can be set next to one of them.
+    pub fn write(&self, trigger: &str) -> Result<(), &'static str> {
+        let trigger = trigger.trim();
+        let (remove, name) = match trigger.strip_prefix('!') {
+            Some(name) => (true, name),
+            None => (false, trigger),
+        };
+        if let Some(hist) = name.strip_prefix("hist") {
+            if remove {
+                self.tracepoint.clear_hist_trigger();
+                return Ok(());
+            }
+            let keys = hist.strip_prefix(":keys=").ok_or("invalid trigger")?;
+            let (key, val) = match keys.split_once(":vals=") {
+                Some((key, val)) => (key, Some(val)),
+                None => (keys, None),
+            };
+            let valid = |field: &str| !field.is_empty() && !field.contains([':', ',']);
+            if !valid(key) || !val.is_none_or(valid) {
+                return Err("invalid trigger");
+            }
+            return self
+                .tracepoint
+                .set_hist_trigger_with_val(key, val)
+                .map_err(|_| "invalid trigger");
+        }
+        let trigger = match name {
+            "traceon" => TraceTrigger::TraceOn,
+            "traceoff" => TraceTrigger::TraceOff,
+            _ => return Err("invalid trigger"),
+        };
+        if !remove {
+            self.tracepoint.set_trigger(Some(trigger));
+        } else if self.tracepoint.trigger() == Some(trigger) {
+            self.tracepoint.set_trigger(None);
+        }
+        Ok(())
+    }
+}
+
+/// TracePointHistFile provides a way to read the histogram of the tracepoint.
+///
+/// The histogram is collected by a `hist` trigger, see [`TracePointTriggerFile`].
+#[derive(Debug, Clone)]
+pub struct TracePointHistFile<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    tracepoint: &'static TracePoint<L, K>,
+}
+
+impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointHistFile<L, K> {
+    fn new(tracepoint: &'static TracePoint<L, K>) -> Self {
+        Self { tracepoint }
+    }
+
+    /// Read the tracepoint histogram
+    ///
+    /// Like ftrace's `hist` file, the histogram has one `{ key: value } hitcount: count`
+    /// line per key value, sorted by key, followed by the totals. With a value field, each
+    /// line goes on with its `sum`, `min` and `max` under the key. Only the header is
+    /// returned if no histogram trigger is set.
+    pub fn read(&self) -> String {
+        let mut hist = String::from("# event histogram\n#\n");
+        let Some(trigger) = self.tracepoint.hist_trigger() else {
+            return hist;
+        };
+        hist.push_str(&format!(
+            "# trigger info: {} [active]\n#\n\n",
+            trigger.info()
+        ));
+        let key = trigger.key().name;
+        for (value, count) in trigger.counts() {
+            let aggregate = trigger.aggregates().get(value);
+            let value = value.to_string();
+            hist.push_str(&format!("{{ {key}: {value:>10} }} hitcount: {count:>10}"));
+            if let Some(aggregate) = aggregate {
+                hist.push_str(&format!(
+                    "  sum: {:>10}  min: {:>10}  max: {:>10}",
+                    aggregate.sum,
+                    aggregate.min.to_string(),
+                    aggregate.max.to_string()
+                ));
+            }
+            hist.push('\n');
+        }
+        let hits = trigger.counts().values().sum::<u64>();
+        hist.push_str(&format!(
+            "\nTotals:\n    Hits: {hits}\n    Entries: {}\n",
+            trigger.counts().len()
+        ));
+        hist
+    }
+}
+
 /// TraceFilterFile provides a way to set filters on the tracepoint.
 #[derive(Debug)]
 pub struct TraceFilterFile<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
@@ -348,9 +1364,20 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TraceFilterFile<L, K> {
         }
     }
 
+    /// Returns the current filter expression, if any.
+    pub fn filter_expr(&self) -> Option<String> {
+        self.inner.lock().filter_expr.clone()
+    }
+
     /// Write a new filter expression to the tracepoint.
-    pub fn write(&self, filter: &str) -> Result<(), &'static str> {
-        if filter.as_bytes()[0] == b'0' {
+    ///
+    /// The expression is compiled by [`TracePoint::compile_filter`], see [`CompiledFilter`]
+    /// for the grammar, e.g. `flags & 0x4 != 0 && path ~ "/dev/*"`.
+    ///
+    /// On error, the previous filter stays in effect and the error is shown by
+    /// [`TraceFilterFile::read`] until the next successful write.
+    pub fn write(&self, filter: &str) -> Result<(), FilterError> {
+        if filter.starts_with('0') {
             // clear the filter and pre-error
             let mut inner = self.inner.lock();
             inner.filter_expr = None;
@@ -358,9 +1385,7 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TraceFilterFile<L, K> {
             self.tracepoint.set_compiled_expr(None);
             Ok(())
         } else {
-            let schema = self.tracepoint.schema();
-            let res = compile_with_schema(filter, *schema);
-            match res {
+            match self.tracepoint.compile_filter(filter) {
                 Ok(compiled_expr) => {
                     let mut inner = self.inner.lock();
                     inner.filter_expr = Some(filter.to_string());
@@ -368,13 +1393,9 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TraceFilterFile<L, K> {
                     self.tracepoint.set_compiled_expr(Some(compiled_expr));
                     Ok(())
                 }
-                Err(mut e) => {
-                    let mut inner = self.inner.lock();
-                    e.message.push('\n');
-                    inner.pre_error = Some(e.message);
-                    inner.filter_expr = None;
-                    self.tracepoint.set_compiled_expr(None);
-                    Err("compile error")
+                Err(e) => {
+                    self.inner.lock().pre_error = Some(format!("{e}\n"));
+                    Err(e)
                 }
             }
         }
@@ -390,12 +1411,150 @@ unsafe extern "C" {
 ///
 /// The L type parameter is the lock type used for synchronizing access to the tracepoint map.
 /// The K type parameter is the kernel trace operations type used for performing kernel-level operations.
+/// `num_cpus` sizes the per-CPU hit counters of each tracepoint, indexed by
+/// [`KernelTraceOps::cpu_id`].
 ///
-/// Returns a Result containing the initialized TracingEventsManager or an error message.
-pub fn global_init_events<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps + 'static>()
--> Result<TracingEventsManager<L, K>, &'static str> {
-    static TRACE_POINT_ID: AtomicUsize = AtomicUsize::new(0);
-    let events_manager = TracingEventsManager::new(TracePointMap::<L, K>::new());
+/// Returns a Result containing the initialized TracingEventsManager, or
+/// [`TraceError::DuplicateEvent`] if two tracepoints share the same system and name.
+/// The tracing events can be initialized only once, later calls fail with
+/// [`TraceError::AlreadyInitialized`] and leave the tracepoints untouched.
+pub fn global_init_events<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps + 'static>(
+    num_cpus: usize,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    global_init_events_with_mode(num_cpus, DispatchMode::StaticKey)
+}
+
+/// Initialize the tracing events with the given [`DispatchMode`]
+///
+/// See [`global_init_events`]. With `DispatchMode::AtomicFlag`, available with the
+/// `atomic-dispatch` feature, no code is ever patched,
+/// for platforms that can't modify their text at runtime.
+pub fn global_init_events_with_mode<
+    L: RawMutex + 'static + Send + Sync,
+    K: KernelTraceOps + 'static,
+>(
+    num_cpus: usize,
+    mode: DispatchMode,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    global_init_events_with_base(num_cpus, mode, 0)
+}
+
+/// Initialize the tracing events with IDs starting at `start_id`
+///
+/// See [`global_init_events_with_mode`]. The tracepoints get the dense IDs
+/// `start_id..start_id + count`, which reserves the IDs below `start_id` for another source
+/// of records multiplexed with ours. The IDs must fit in [`TraceEntry::common_type`], or this
+/// fails with [`TraceError::IdRangeExhausted`] and can be called again with another base.
+pub fn global_init_events_with_base<
+    L: RawMutex + 'static + Send + Sync,
+    K: KernelTraceOps + 'static,
+>(
+    num_cpus: usize,
+    mode: DispatchMode,
+    start_id: u32,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    init_events(num_cpus, mode, IdScheme::Dense(start_id), None)
+}
+
+/// Initialize the tracing events with IDs derived from the system and name of each tracepoint
+///
+/// See [`global_init_events_with_mode`]. Each tracepoint gets its [`TracePoint::stable_id`],
+/// so adding or removing a tracepoint doesn't shift the IDs of the others and records stay
+/// comparable across builds. If two tracepoints hash to the same ID, this fails with
+/// [`TraceError::IdCollision`] and can be called again with another scheme, e.g. after
+/// renaming one of them.
+///
+/// The IDs are 16-bit hashes, so collisions are resolved by hand only. With 300 tracepoints,
+/// the odds of a collision are already about one in two, so this suits small sets of
+/// tracepoints, while larger ones should use [`global_init_events_with_base`].
+pub fn global_init_events_stable_ids<
+    L: RawMutex + 'static + Send + Sync,
+    K: KernelTraceOps + 'static,
+>(
+    num_cpus: usize,
+    mode: DispatchMode,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    init_events(num_cpus, mode, IdScheme::Stable, None)
+}
+
+/// Initialize the tracing events, checking that exactly `expected` tracepoints are found
+///
+/// See [`global_init_events`]. Tracepoints are collected from the `.tracepoint` linker
+/// section, so a linker script that drops the section, or some of its input sections,
+/// silently leaves tracepoints out. If the count doesn't match, the names of the tracepoints
+/// that were found are logged and this fails with [`TraceError::UnexpectedCount`] before any
+/// tracepoint is touched, so it can be called again.
+pub fn global_init_events_expect<
+    L: RawMutex + 'static + Send + Sync,
+    K: KernelTraceOps + 'static,
+>(
+    num_cpus: usize,
+    expected: usize,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    init_events(
+        num_cpus,
+        DispatchMode::StaticKey,
+        IdScheme::Dense(0),
+        Some(expected),
+    )
+}
+
+/// How [`init_events`] assigns the tracepoint IDs.
+#[derive(Debug, Clone, Copy)]
+enum IdScheme {
+    /// Dense IDs in name order, starting at the given ID.
+    Dense(u32),
+    /// The stable ID of each tracepoint.
+    Stable,
+}
+
+fn init_events<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps + 'static>(
+    num_cpus: usize,
+    mode: DispatchMode,
+    scheme: IdScheme,
+    expected: Option<usize>,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    // Claimed by the first call so a concurrent or later one can't reassign the IDs.
+    static INITIALIZED: AtomicBool = AtomicBool::new(false);
+    init_once(&INITIALIZED, || {
+        init_events_claimed(num_cpus, mode, scheme, expected)
+    })
+}
+
+/// Run `init` unless `initialized` was already claimed, claiming it.
+///
+/// The claim is released if `init` fails, which it must do before touching any tracepoint,
+/// so that the init can be retried, e.g. with another ID scheme.
+fn init_once<T>(
+    initialized: &AtomicBool,
+    init: impl FnOnce() -> Result<T, TraceError>,
+) -> Result<T, TraceError> {
+    if initialized
+        .compare_exchange(
+            false,
+            true,
+            core::sync::atomic::Ordering::AcqRel,
+            core::sync::atomic::Ordering::Acquire,
+        )
+        .is_err()
+    {
+        log::error!("tracing events already initialized");
+        return Err(TraceError::AlreadyInitialized);
+    }
+    let result = init();
+    if result.is_err() {
+        initialized.store(false, core::sync::atomic::Ordering::Release);
+    }
+    result
+}
+
+/// Initialize the tracing events, once [`init_events`] has claimed the initialization.
+fn init_events_claimed<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps + 'static>(
+    num_cpus: usize,
+    mode: DispatchMode,
+    scheme: IdScheme,
+    expected: Option<usize>,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
     let tracepoint_data_start = __start_tracepoint as *mut CommonTracePointMeta<L, K>;
     let tracepoint_data_end = __stop_tracepoint as *mut CommonTracePointMeta<L, K>;
     log::info!(
@@ -407,6 +1566,30 @@ pub fn global_init_events<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps
         / size_of::<CommonTracePointMeta<L, K>>();
     let tracepoint_data =
         unsafe { core::slice::from_raw_parts_mut(tracepoint_data_start, tracepoint_data_len) };
+    register_tracepoints(tracepoint_data, num_cpus, mode, scheme, expected)
+}
+
+/// Register the tracepoints collected from the linker section with a new manager.
+///
+/// Every check is done before any tracepoint is touched, so that a failure can be retried.
+fn register_tracepoints<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps + 'static>(
+    tracepoint_data: &mut [CommonTracePointMeta<L, K>],
+    num_cpus: usize,
+    mode: DispatchMode,
+    scheme: IdScheme,
+    expected: Option<usize>,
+) -> Result<TracingEventsManager<L, K>, TraceError> {
+    let events_manager = TracingEventsManager::new(TracePointMap::<L, K>::new());
+    let tracepoint_data_len = tracepoint_data.len();
+    if let IdScheme::Dense(start_id) = scheme
+        && start_id as usize + tracepoint_data_len > u16::MAX as usize + 1
+    {
+        log::error!("{tracepoint_data_len} tracepoint ids starting at {start_id} exceed u16");
+        return Err(TraceError::IdRangeExhausted {
+            start_id,
+            count: tracepoint_data_len,
+        });
+    }
     tracepoint_data.sort_by(|a, b| {
         a.trace_point
             .name()
@@ -414,24 +1597,759 @@ pub fn global_init_events<L: RawMutex + 'static + Send + Sync, K: KernelTraceOps
             .then(a.trace_point.system().cmp(b.trace_point.system()))
     });
     log::info!("tracepoint_data_len: {tracepoint_data_len}");
+    if let Some(expected) = expected
+        && tracepoint_data_len != expected
+    {
+        log::error!("expected {expected} tracepoints, found {tracepoint_data_len}:");
+        for meta in tracepoint_data.iter() {
+            log::error!(
+                "  {}:{}",
+                meta.trace_point.system(),
+                meta.trace_point.name()
+            );
+        }
+        return Err(TraceError::UnexpectedCount {
+            expected,
+            found: tracepoint_data_len,
+        });
+    }
+    if tracepoint_data_len == 0 {
+        // Not an error, as a build may define no tracepoint, see `global_init_events_expect`
+        log::warn!("no tracepoints found; check linker section retention of `.tracepoint`");
+    }
+
+    // Duplicates are adjacent after sorting, and would overwrite each other in the subsystem
+    for pair in tracepoint_data.windows(2) {
+        let (a, b) = (pair[0].trace_point, pair[1].trace_point);
+        if a.name() == b.name() && a.system() == b.system() {
+            log::error!("duplicate tracepoint: {}:{}", a.system(), a.name());
+            return Err(TraceError::DuplicateEvent {
+                system: a.system(),
+                name: a.name(),
+            });
+        }
+    }
+
+    let ids = match scheme {
+        IdScheme::Dense(start_id) => (start_id..start_id + tracepoint_data_len as u32).collect(),
+        IdScheme::Stable => tracepoint_data
+            .iter()
+            .map(|meta| meta.trace_point.stable_id() as u32)
+            .collect::<Vec<_>>(),
+    };
+    let mut owners = BTreeMap::new();
+    for (id, meta) in ids.iter().zip(tracepoint_data.iter()) {
+        let tracepoint = meta.trace_point;
+        if let Some(first) = owners.insert(*id, tracepoint) {
+            log::error!(
+                "tracepoint id {id} of {}:{} collides with {}:{}",
+                tracepoint.system(),
+                tracepoint.name(),
+                first.system(),
+                first.name()
+            );
+            return Err(TraceError::IdCollision {
+                id: *id,
+                first: format!("{}:{}", first.system(), first.name()),
+                second: format!("{}:{}", tracepoint.system(), tracepoint.name()),
+            });
+        }
+    }
 
     let mut tracepoint_map = events_manager.tracepoint_map();
-    for tracepoint_meta in tracepoint_data {
+    for (id, tracepoint_meta) in ids.into_iter().zip(tracepoint_data.iter()) {
         let tracepoint = tracepoint_meta.trace_point;
-        let id = TRACE_POINT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
-        tracepoint.set_id(id as u32);
-        tracepoint.register(tracepoint_meta.print_func, Box::new(tracepoint));
-        tracepoint_map.insert(id as u32, tracepoint);
+        tracepoint.set_id(id);
+        tracepoint.init_cpu_hit_counts(num_cpus);
+        tracepoint.set_dispatch_mode(mode);
+        if !tracepoint.set_shared(&events_manager.shared) {
+            log::error!(
+                "tracepoint {}:{} is already attached to a manager",
+                tracepoint.system(),
+                tracepoint.name()
+            );
+        }
+        let subsys_name = tracepoint.system();
+        let subsys = events_manager.create_subsystem(subsys_name);
+        let context = TracePointContext::new(
+            tracepoint,
+            subsys.paused.clone(),
+            events_manager.shared.clone(),
+        );
+        tracepoint.register(tracepoint_meta.print_func, Box::new(context));
+        tracepoint_map.insert(id, tracepoint);
         log::info!(
             "tracepoint registered: {}:{}",
             tracepoint.system(),
             tracepoint.name(),
         );
-        let subsys_name = tracepoint.system();
-        let subsys = events_manager.create_subsystem(subsys_name);
         let event_info = EventInfo::new(tracepoint);
         subsys.create_event(tracepoint.name(), event_info);
     }
     drop(tracepoint_map); // Release the lock on the tracepoint map
     Ok(events_manager)
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::*;
+    use crate::testing::{self, Kops, Lock};
+
+    mod events {
+        #![allow(dead_code)]
+        use crate::testing::{Kops, Lock};
+
+        crate::define_event_trace!(
+            lib_sample,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_tests),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        crate::define_event_trace!(
+            lib_other,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_tests),
+            TP_PROTO(x: u64),
+            TP_STRUCT__entry {
+                b: u64,
+            },
+            TP_fast_assign {
+                b: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("b={}", __entry.b))
+        );
+
+        // Only registered again by `dense_ids_start_at_the_base`
+        crate::define_event_trace!(
+            ids_first,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_ids),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        crate::define_event_trace!(
+            ids_second,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_ids),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        // Only registered again by `removed_events_leave_the_map`
+        crate::define_event_trace!(
+            removed,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_removal),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        // The stable IDs of these two collide
+        crate::define_event_trace!(
+            collide_23,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_collisions),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+
+        crate::define_event_trace!(
+            collide_278,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(lib_collisions),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+    }
+
+    fn sample() -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("lib_tests", "lib_sample")
+            .unwrap()
+    }
+
+    /// Fire the sample tracepoint once with its default print enabled.
+    fn fire(a: u32) {
+        sample().enable_default();
+        events::trace_lib_sample(a);
+        sample().disable_default();
+    }
+
+    fn drain(pipe: &mut dyn TracePipeSink) -> Vec<Vec<u8>> {
+        core::iter::from_fn(|| pipe.pop()).collect()
+    }
+
+    #[test]
+    fn sinks_receive_a_copy_of_every_record() {
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        manager.add_sink(1, Box::new(TracePipeRaw::new(8)), SinkPolicy::Blocking);
+        manager.add_sink(2, Box::new(TracePipeRaw::new(8)), SinkPolicy::Blocking);
+        fire(1);
+        fire(2);
+        let main = testing::take_records(sample().id());
+        for sink_id in [1, 2] {
+            let mut sink = manager.remove_sink(sink_id).unwrap();
+            assert_eq!(drain(&mut *sink), main);
+        }
+        assert_eq!(main.len(), 2);
+    }
+
+    #[test]
+    fn drop_sinks_never_wait() {
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        manager.add_sink(
+            3,
+            Box::new(TracePipeRaw::new(1)),
+            SinkPolicy::DropOnBackpressure,
+        );
+        fire(1);
+        fire(2);
+        assert_eq!(manager.sink_dropped_count(3), Some(1));
+
+        // A sink locked by its reader drops the record instead of stalling the tracepoint.
+        let sinks = manager.shared.sinks();
+        let reader = sinks[&3].sink.lock();
+        fire(3);
+        drop(reader);
+        assert_eq!(manager.sink_dropped_count(3), Some(2));
+        let mut sink = manager.remove_sink(3).unwrap();
+        assert_eq!(drain(&mut *sink).len(), 1);
+    }
+
+    fn other() -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("lib_tests", "lib_other")
+            .unwrap()
+    }
+
+    fn enabled() -> (bool, bool) {
+        (sample().default_is_enabled(), other().default_is_enabled())
+    }
+
+    #[test]
+    fn available_lists_are_sorted() {
+        let manager = testing::manager();
+        let subsystems = manager.available_subsystems();
+        assert!(subsystems.is_sorted());
+        assert!(subsystems.iter().any(|name| name == "lib_tests"));
+
+        let events = manager.available_events();
+        assert!(events.is_sorted());
+        assert_eq!(events.len(), manager.tracepoint_map().len());
+        let ours = events
+            .iter()
+            .filter(|event| event.starts_with("lib_tests:"))
+            .collect::<Vec<_>>();
+        assert_eq!(ours, ["lib_tests:lib_other", "lib_tests:lib_sample"]);
+    }
+
+    #[test]
+    fn map_finds_tracepoints_by_name() {
+        let (sample, other) = (sample(), other());
+        let mut map = TracePointMap::<Lock, Kops>::new();
+        assert!(map.is_empty());
+        map.insert(5, sample);
+        map.insert(6, other);
+        assert_eq!(map.len(), 2);
+        let found = |map: &TracePointMap<Lock, Kops>, name| {
+            map.get_by_name("lib_tests", name).map(|tp| tp.name())
+        };
+        assert_eq!(found(&map, "lib_sample"), Some("lib_sample"));
+        assert!(map.get_by_name("other_tests", "lib_sample").is_none());
+
+        // Replacing an ID drops the name of the tracepoint stored under it
+        let old = map.insert(5, other).unwrap();
+        assert_eq!(old.name(), "lib_sample");
+        assert_eq!(found(&map, "lib_sample"), None);
+        map.remove(&5);
+        assert_eq!(map.len(), 1);
+
+        // Inserted through the map directly, so found by a scan
+        map.deref_mut().insert(7, sample);
+        assert_eq!(found(&map, "lib_sample"), Some("lib_sample"));
+    }
+
+    #[test]
+    fn subsystem_enables_every_event() {
+        let _serial = testing::serial();
+        let subsystem = testing::manager().get_subsystem("lib_tests").unwrap();
+        sample().enable_default();
+        assert_eq!(subsystem.enable_all(), 1);
+        assert_eq!(enabled(), (true, true));
+        assert_eq!(subsystem.enable_all(), 0);
+        assert_eq!(subsystem.disable_all(), 2);
+        assert_eq!(enabled(), (false, false));
+        assert_eq!(subsystem.disable_all(), 0);
+    }
+
+    #[test]
+    fn subsystem_enable_file_reads_mixed_state() {
+        let _serial = testing::serial();
+        let subsystem = testing::manager().get_subsystem("lib_tests").unwrap();
+        let file = subsystem.enable_file();
+        assert_eq!(file.read(), "0\n");
+        other().enable_default();
+        assert_eq!(file.read(), "X\n");
+        file.write('1');
+        assert_eq!(file.read(), "1\n");
+        file.write('2');
+        assert_eq!(file.read(), "1\n");
+        file.write('0');
+        assert_eq!(file.read(), "0\n");
+    }
+
+    #[test]
+    fn set_event_matches_globs() {
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        assert_eq!(manager.set_event("lib_tests:*"), Ok(2));
+        assert_eq!(enabled(), (true, true));
+        assert_eq!(manager.set_event("!*:lib_other"), Ok(1));
+        assert_eq!(enabled(), (true, false));
+        assert_eq!(manager.set_event("!lib_sample"), Ok(1));
+        assert_eq!(enabled(), (false, false));
+        assert_eq!(manager.set_event("lib_t?sts:lib_o*"), Ok(1));
+        assert_eq!(enabled(), (false, true));
+        assert_eq!(manager.set_event("!lib_tests"), Ok(2));
+        assert_eq!(enabled(), (false, false));
+        assert_eq!(
+            manager.set_event("lib_tests:nothing*"),
+            Err(TraceError::NoMatchingEvent {
+                pattern: "lib_tests:nothing*".to_string()
+            })
+        );
+    }
+
+    fn sample_info() -> Arc<EventInfo<Lock, Kops>> {
+        let manager = testing::manager();
+        let subsystem = manager.get_subsystem("lib_tests").unwrap();
+        subsystem.get_event("lib_sample").unwrap()
+    }
+
+    #[test]
+    fn subsystem_filter_applies_to_every_event() {
+        let _serial = testing::serial();
+        let subsystem = testing::manager().get_subsystem("lib_tests").unwrap();
+        let results = subsystem.set_filter("common_pid == 7");
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(results.len(), 2);
+        for pid in [7, 8] {
+            testing::set_pid(pid);
+            fire(pid);
+        }
+        assert_eq!(testing::take_records(sample().id()).len(), 1);
+
+        // `b` only exists in lib_other, so lib_sample keeps the pid filter
+        let results = subsystem.set_filter("b == 1");
+        assert!(matches!(&results[..], [(other, Ok(())), (sample, Err(_))]
+            if other == "lib_other" && sample == "lib_sample"));
+        assert_eq!(sample_info().filter_file().read(), "common_pid == 7");
+
+        let results = subsystem.set_filter("0");
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        fire(1);
+        assert_eq!(testing::take_records(sample().id()).len(), 1);
+    }
+
+    #[test]
+    fn hits_file_counts_every_cpu() {
+        let _serial = testing::serial();
+        let info = sample_info();
+        let hits = info.hit_count_file();
+        hits.write('0');
+        for cpu in [0, 1, 3, 9] {
+            testing::set_cpu(cpu);
+            fire(cpu);
+        }
+        events::trace_lib_sample(0);
+        assert_eq!(hits.read(), "4\n");
+        assert_eq!(sample().hit_count(), 4);
+        hits.write('0');
+        assert_eq!(hits.read(), "0\n");
+    }
+
+    #[test]
+    fn hist_counts_each_key() {
+        let _serial = testing::serial();
+        let info = sample_info();
+        info.trigger_file().write("hist:keys=a").unwrap();
+        for a in [1, 2, 1] {
+            fire(a);
+        }
+        let hist = info.hist_file().read();
+        info.trigger_file().write("!hist").unwrap();
+        testing::take_records(sample().id());
+        let expected = "# event histogram\n#\n# trigger info: hist:keys=a:vals=hitcount [active]\n\
+            #\n\n{ a:          1 } hitcount:          2\n{ a:          2 } hitcount:          1\n\
+            \nTotals:\n    Hits: 3\n    Entries: 2\n";
+        assert_eq!(hist, expected);
+    }
+
+    #[test]
+    fn hist_aggregates_values() {
+        let _serial = testing::serial();
+        let info = sample_info();
+        info.trigger_file()
+            .write("hist:keys=common_pid:vals=a")
+            .unwrap();
+        for (pid, a) in [(1, 3), (2, 4), (1, 5)] {
+            testing::set_pid(pid);
+            fire(a);
+        }
+        let hist = info.hist_file().read();
+        info.trigger_file().write("!hist").unwrap();
+        testing::take_records(sample().id());
+        let lines = [
+            "{ common_pid:          1 } hitcount:          2  \
+            sum:          8  min:          3  max:          5",
+            "{ common_pid:          2 } hitcount:          1  \
+            sum:          4  min:          4  max:          4",
+        ];
+        assert!(hist.contains(&lines.join("\n")), "{hist}");
+        assert!(hist.contains("hist:keys=common_pid:vals=hitcount,a [active]"));
+    }
+
+    #[test]
+    fn cpumask_skips_masked_cpus() {
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        let skipped = manager.cpumask_skipped();
+        manager.set_cpumask(0b10);
+        for cpu in [0, 1, 2, 70] {
+            testing::set_cpu(cpu);
+            fire(cpu);
+        }
+        manager.set_cpumask(u64::MAX);
+        let cpus = testing::take_records(sample().id())
+            .iter()
+            .map(|record| RecordHeader::from_bytes(record).unwrap().cpu)
+            .collect::<Vec<_>>();
+        // CPUs past the width of the mask are always recorded
+        assert_eq!(cpus, [1, 70]);
+        assert_eq!(manager.cpumask_skipped() - skipped, 2);
+    }
+
+    #[test]
+    fn selected_clock_stamps_records() {
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        let clocks = [
+            (TraceClock::Global, testing::GLOBAL_BASE),
+            (TraceClock::Mono, testing::MONO_BASE),
+            (TraceClock::Counter, testing::COUNTER_BASE),
+            (TraceClock::Local, 0),
+        ];
+        for (clock, base) in clocks {
+            manager.set_clock(clock);
+            assert_eq!(manager.current_clock(), clock);
+            fire(1);
+            let records = testing::take_records(sample().id());
+            let timestamp = RecordHeader::from_bytes(&records[0]).unwrap().timestamp;
+            assert_eq!(timestamp >> 40, base >> 40, "{}", clock.name());
+        }
+    }
+
+    #[test]
+    fn traceoff_trigger_stops_recording() {
+        let _serial = testing::serial();
+        let info = sample_info();
+        let trigger = info.trigger_file();
+        assert_eq!(trigger.write("traceon:x"), Err("invalid trigger"));
+        trigger.write("traceoff").unwrap();
+        assert_eq!(trigger.read(), "traceoff\n");
+        fire(1);
+        assert!(!testing::manager().tracing_on());
+        fire(2);
+        assert!(testing::take_records(sample().id()).is_empty());
+
+        trigger.write("traceon").unwrap();
+        fire(3);
+        assert!(testing::manager().tracing_on());
+        assert_eq!(testing::take_records(sample().id()).len(), 1);
+        trigger.write("!traceon").unwrap();
+        assert!(trigger.read().starts_with("# Available triggers:"));
+    }
+
+    #[test]
+    fn pid_filter_toggles_while_tracing() {
+        extern crate std;
+
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        sample().enable_default();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(core::sync::atomic::Ordering::Relaxed) {
+                    events::trace_lib_sample(1);
+                }
+            });
+            for round in 0..1000 {
+                match round % 3 {
+                    0 => manager.set_pid_filter(&[1]),
+                    1 => manager.set_pid_filter_exclude(&[1]),
+                    _ => manager.clear_pid_filter(),
+                }
+            }
+            done.store(true, core::sync::atomic::Ordering::Relaxed);
+        });
+        testing::take_records(sample().id());
+
+        manager.set_pid_filter_exclude(&[1]);
+        events::trace_lib_sample(2);
+        assert!(testing::take_records(sample().id()).is_empty());
+        manager.set_pid_filter(&[1, 2]);
+        events::trace_lib_sample(3);
+        assert_eq!(testing::take_records(sample().id()).len(), 1);
+        sample().disable_default();
+    }
+
+    #[test]
+    fn second_init_fails() {
+        let first_id = sample().id();
+        let second = global_init_events::<Lock, Kops>(4);
+        assert!(matches!(second, Err(TraceError::AlreadyInitialized)));
+        let second = global_init_events_stable_ids::<Lock, Kops>(4, DispatchMode::AtomicFlag);
+        assert!(matches!(second, Err(TraceError::AlreadyInitialized)));
+        assert_eq!(sample().id(), first_id);
+    }
+
+    #[test]
+    fn failed_init_releases_the_claim() {
+        let initialized = AtomicBool::new(false);
+        let duplicate = TraceError::DuplicateEvent {
+            system: "lib_tests",
+            name: "lib_sample",
+        };
+        assert_eq!(
+            init_once(&initialized, || Err::<(), _>(duplicate.clone())),
+            Err(duplicate)
+        );
+        assert_eq!(init_once(&initialized, || Ok(1)), Ok(1));
+        assert_eq!(
+            init_once(&initialized, || Ok(2)),
+            Err(TraceError::AlreadyInitialized)
+        );
+    }
+
+    #[test]
+    fn empty_section_is_not_fatal() {
+        let mut empty: [CommonTracePointMeta<Lock, Kops>; 0] = [];
+        let scheme = IdScheme::Dense(0);
+        let manager =
+            register_tracepoints(&mut empty, 4, DispatchMode::AtomicFlag, scheme, None).unwrap();
+        assert_eq!(manager.tracepoint_map().len(), 0);
+        assert!(manager.subsystem_names().is_empty());
+        let manager =
+            register_tracepoints(&mut empty, 4, DispatchMode::AtomicFlag, scheme, Some(0));
+        assert!(manager.is_ok());
+        let expected =
+            register_tracepoints(&mut empty, 4, DispatchMode::AtomicFlag, scheme, Some(2));
+        assert!(matches!(
+            expected,
+            Err(TraceError::UnexpectedCount {
+                expected: 2,
+                found: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn duplicate_tracepoints_are_rejected() {
+        let meta = || CommonTracePointMeta {
+            trace_point: sample(),
+            print_func: || {},
+        };
+        let first_id = sample().id();
+        let mut duplicated = [meta(), meta()];
+        let manager = register_tracepoints(
+            &mut duplicated,
+            4,
+            DispatchMode::AtomicFlag,
+            IdScheme::Dense(100),
+            None,
+        );
+        assert!(matches!(
+            manager,
+            Err(TraceError::DuplicateEvent {
+                system: "lib_tests",
+                name: "lib_sample"
+            })
+        ));
+        assert_eq!(sample().id(), first_id);
+    }
+
+    #[test]
+    fn dense_ids_start_at_the_base() {
+        let map = testing::manager().tracepoint_map();
+        let first = map.get_by_name("lib_ids", "ids_first").unwrap();
+        let second = map.get_by_name("lib_ids", "ids_second").unwrap();
+        drop(map);
+        let ids = (first.id(), second.id());
+        // Out of order, as the linker doesn't sort the section
+        let mut tracepoints = [second, first].map(|trace_point| CommonTracePointMeta {
+            trace_point,
+            print_func: || {},
+        });
+        let manager = register_tracepoints(
+            &mut tracepoints,
+            4,
+            DispatchMode::AtomicFlag,
+            IdScheme::Dense(100),
+            None,
+        )
+        .unwrap();
+        assert_eq!((first.id(), second.id()), (100, 101));
+        let map = manager.tracepoint_map();
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), [100, 101]);
+        assert_eq!(map.get_by_name("lib_ids", "ids_second").unwrap().id(), 101);
+        drop(map);
+        first.set_id(ids.0);
+        second.set_id(ids.1);
+    }
+
+    #[test]
+    fn for_each_visits_every_tracepoint() {
+        let manager = testing::manager();
+        let expected = manager
+            .tracepoint_map()
+            .values()
+            .map(|tp| tp.name())
+            .collect::<Vec<_>>();
+        let mut visited = Vec::new();
+        manager.for_each_tracepoint(&mut |tp| visited.push(tp.name()));
+        assert_eq!(visited, expected);
+
+        let mut names = Vec::new();
+        manager.for_each_tracepoint_in("lib_collisions", &mut |tp| names.push(tp.name()));
+        names.sort();
+        assert_eq!(names, ["collide_23", "collide_278"]);
+        manager.for_each_tracepoint_in("no_such_system", &mut |_| panic!("visited"));
+    }
+
+    #[test]
+    fn removed_events_leave_the_map() {
+        let removed = testing::manager()
+            .tracepoint_map()
+            .get_by_name("lib_removal", "removed")
+            .unwrap();
+        let id = removed.id();
+        // A manager of its own, as the global one is shared by every test
+        let mut tracepoints = [CommonTracePointMeta {
+            trace_point: removed,
+            print_func: || {},
+        }];
+        let manager = register_tracepoints(
+            &mut tracepoints,
+            4,
+            DispatchMode::AtomicFlag,
+            IdScheme::Dense(200),
+            None,
+        )
+        .unwrap();
+        removed.enable_default();
+        let event = manager.remove_event("lib_removal", "removed").unwrap();
+        assert!(core::ptr::eq(event.tracepoint(), removed));
+        assert!(!removed.default_is_enabled());
+        assert!(manager.tracepoint_map().is_empty());
+        let subsystem = manager.get_subsystem("lib_removal").unwrap();
+        assert!(subsystem.event_names().is_empty());
+        assert!(manager.remove_event("lib_removal", "removed").is_none());
+        assert!(manager.remove_event("lib_nothing", "removed").is_none());
+        removed.set_id(id);
+    }
+
+    #[test]
+    fn stable_ids_are_deterministic() {
+        // A change here renumbers the records of every build
+        assert_eq!(sample().stable_id(), 21956);
+        assert_ne!(sample().stable_id(), other().stable_id());
+    }
+
+    #[test]
+    fn stable_id_collisions_are_rejected() {
+        let map = testing::manager().tracepoint_map();
+        let a = map.get_by_name("lib_collisions", "collide_23").unwrap();
+        let b = map.get_by_name("lib_collisions", "collide_278").unwrap();
+        drop(map);
+        assert_eq!(a.stable_id(), b.stable_id());
+        let ids = (a.id(), b.id());
+        let mut colliding = [a, b].map(|trace_point| CommonTracePointMeta {
+            trace_point,
+            print_func: || {},
+        });
+        let manager = register_tracepoints(
+            &mut colliding,
+            4,
+            DispatchMode::AtomicFlag,
+            IdScheme::Stable,
+            None,
+        );
+        let Err(TraceError::IdCollision { id, first, second }) = manager else {
+            panic!("the stable ids don't collide");
+        };
+        assert_eq!(id, 48395);
+        assert_eq!(first, "lib_collisions:collide_23");
+        assert_eq!(second, "lib_collisions:collide_278");
+        assert_eq!((a.id(), b.id()), ids);
+    }
+}
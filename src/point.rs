@@ -1,14 +1,26 @@
-use alloc::{boxed::Box, collections::BTreeMap, format, string::String};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
 use core::{
     any::Any,
-    sync::atomic::{AtomicBool, AtomicU32},
+    cmp::Reverse,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU32, AtomicU64, AtomicUsize},
 };
 
 use lock_api::{Mutex, RawMutex};
 use static_keys::RawStaticFalseKey;
-use tp_lexer::{Compiled, Schema};
+use tp_lexer::Schema;
 
-use crate::{KernelCodeManipulator, KernelTraceOps};
+use crate::{
+    CompiledFilter, DiagnosticKind, DiagnosticNotice, FieldDesc, FieldValue, FieldView,
+    FilterError, KernelCodeManipulator, KernelTraceOps, ParseError, TraceError, TracePipeSink,
+};
 
 /// A trace entry structure that holds metadata about a trace event.
 #[derive(Debug)]
@@ -24,49 +36,348 @@ pub struct TraceEntry {
     pub common_pid: i32,
 }
 
+/// IRQs were disabled when the event was recorded.
+pub const TRACE_FLAG_IRQS_OFF: u8 = 0x01;
+/// The platform can't tell whether IRQs were disabled.
+pub const TRACE_FLAG_IRQS_NOSUPPORT: u8 = 0x02;
+/// The current task needed to be rescheduled.
+pub const TRACE_FLAG_NEED_RESCHED: u8 = 0x04;
+/// The event was recorded in hardirq context.
+pub const TRACE_FLAG_HARDIRQ: u8 = 0x08;
+/// The event was recorded in softirq context.
+pub const TRACE_FLAG_SOFTIRQ: u8 = 0x10;
+/// A preemption was pending.
+pub const TRACE_FLAG_PREEMPT_RESCHED: u8 = 0x20;
+/// The event was recorded in NMI context.
+pub const TRACE_FLAG_NMI: u8 = 0x40;
+/// Bottom halves were disabled when the event was recorded.
+pub const TRACE_FLAG_BH_OFF: u8 = 0x80;
+
 impl TraceEntry {
     /// Returns a formatted string representing the latency and preemption state.
+    ///
+    /// The `common_flags` are decoded with the `TRACE_FLAG_*` bits, as by the kernel's
+    /// `trace_print_lat_fmt`:
+    /// - irqs-off: `D` (IRQs and BH off), `d` (IRQs off), `b` (BH off), `X` (unsupported)
+    /// - need-resched: `N` (both), `n` (need resched), `p` (preempt resched)
+    /// - hardirq/softirq: `Z` (NMI in hardirq), `z` (NMI), `H` (hardirq in softirq),
+    ///   `h` (hardirq), `s` (softirq)
+    /// - preempt-depth and migrate-disable: the low and high nibbles of the preemption count
+    ///
+    /// Unset states are printed as `.`.
     pub fn trace_print_lat_fmt(&self) -> String {
-        // todo!("Implement IRQs off logic");
-        let irqs_off = '.';
-        let resched = '.';
-        let hardsoft_irq = '.';
-        let mut preempt_low = '.';
-        if self.common_preempt_count & 0xf != 0 {
-            preempt_low = ((b'0') + (self.common_preempt_count & 0xf)) as char;
-        }
-        let mut preempt_high = '.';
-        if self.common_preempt_count >> 4 != 0 {
-            preempt_high = ((b'0') + (self.common_preempt_count >> 4)) as char;
-        }
+        let flags = self.common_flags;
+        let has = |flag: u8| flags & flag != 0;
+        let irqs_off = match (has(TRACE_FLAG_IRQS_OFF), has(TRACE_FLAG_BH_OFF)) {
+            (true, true) => 'D',
+            (true, false) => 'd',
+            (false, true) => 'b',
+            (false, false) if has(TRACE_FLAG_IRQS_NOSUPPORT) => 'X',
+            _ => '.',
+        };
+        let resched = match (
+            has(TRACE_FLAG_NEED_RESCHED),
+            has(TRACE_FLAG_PREEMPT_RESCHED),
+        ) {
+            (true, true) => 'N',
+            (true, false) => 'n',
+            (false, true) => 'p',
+            (false, false) => '.',
+        };
+        let hardsoft_irq = match (
+            has(TRACE_FLAG_NMI),
+            has(TRACE_FLAG_HARDIRQ),
+            has(TRACE_FLAG_SOFTIRQ),
+        ) {
+            (true, true, _) => 'Z',
+            (true, false, _) => 'z',
+            (false, true, true) => 'H',
+            (false, true, false) => 'h',
+            (false, false, true) => 's',
+            (false, false, false) => '.',
+        };
+        let nibble = |value: u8| match value {
+            0 => '.',
+            value => char::from_digit(value as u32, 16).unwrap_or('.'),
+        };
+        let preempt_low = nibble(self.common_preempt_count & 0xf);
+        let preempt_high = nibble(self.common_preempt_count >> 4);
         format!("{irqs_off}{resched}{hardsoft_irq}{preempt_low}{preempt_high}")
     }
 }
 
+/// The record flag set when the entry was truncated to the tracepoint's payload cap.
+pub const RECORD_FLAG_TRUNCATED: u16 = 1 << 0;
+
+/// The record flag set when the comm of the current task follows the header.
+///
+/// See [`crate::TracingEventsManager::set_record_comm`].
+pub const RECORD_FLAG_COMM: u16 = 1 << 1;
+
+/// The record flag set when the record is a marker written with
+/// [`crate::TracingEventsManager::trace_marker`].
+///
+/// The message follows the [`TraceEntry`] in place of the fields of a tracepoint, and the
+/// `common_type` of the entry is zero.
+pub const RECORD_FLAG_MARKER: u16 = 1 << 2;
+
+/// The length of a task comm, including the trailing NUL bytes.
+pub const TASK_COMM_LEN: usize = 16;
+
+/// The magic number stored in the [`RecordHeader`] of every record.
+pub const RECORD_MAGIC: u16 = 0x5450;
+
+/// The version of the record format, bumped when the layout of a record changes.
+///
+/// A record of another version is rejected by [`crate::TraceEntryParser::try_parse`].
+pub const RECORD_FORMAT_VERSION: u16 = 1;
+
+/// The header prepended to every record pushed to the trace pipe.
+///
+/// The [`TraceEntry`] and the event payload follow the header directly, or after the
+/// comm of the task if the record has [`RECORD_FLAG_COMM`] set.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RecordHeader {
+    /// The time at which the record was taken, in nanoseconds.
+    pub timestamp: u64,
+    /// The CPU on which the record was taken.
+    pub cpu: u32,
+    /// Flags describing the record, see [`RECORD_FLAG_TRUNCATED`].
+    pub flags: u16,
+    /// The length of the entry before truncation.
+    ///
+    /// An entry longer than `u16::MAX` bytes is cut to that length when the record is built,
+    /// and the record is marked truncated.
+    pub entry_len: u16,
+    /// How many identical records following this one were coalesced into it.
+    ///
+    /// See [`crate::TracePipeRaw::set_coalesce`].
+    pub repeat: u32,
+    /// The magic number identifying a record, always [`RECORD_MAGIC`].
+    pub magic: u16,
+    /// The format version of the record, see [`RECORD_FORMAT_VERSION`].
+    pub version: u16,
+}
+
+impl RecordHeader {
+    /// Read the header from the front of a raw record.
+    ///
+    /// Returns `None` if the record is shorter than the header.
+    pub fn from_bytes(record: &[u8]) -> Option<Self> {
+        if record.len() < size_of::<Self>() {
+            return None;
+        }
+        Some(unsafe { core::ptr::read_unaligned(record.as_ptr() as *const Self) })
+    }
+
+    /// Check that the header has the magic number and the format version of this crate.
+    pub fn validate(&self) -> Result<(), ParseError> {
+        if self.magic != RECORD_MAGIC {
+            return Err(ParseError::BadMagic { magic: self.magic });
+        }
+        if self.version != RECORD_FORMAT_VERSION {
+            return Err(ParseError::UnsupportedVersion {
+                version: self.version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the raw bytes of the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+
+    /// Returns the offset of the [`TraceEntry`] in the record.
+    pub fn entry_offset(&self) -> usize {
+        if self.flags & RECORD_FLAG_COMM != 0 {
+            size_of::<Self>() + TASK_COMM_LEN
+        } else {
+            size_of::<Self>()
+        }
+    }
+
+    /// Returns the comm of the task recorded in the record, if any.
+    pub fn comm<'a>(&self, record: &'a [u8]) -> Option<&'a str> {
+        if self.flags & RECORD_FLAG_COMM == 0 {
+            return None;
+        }
+        let comm = record.get(size_of::<Self>()..size_of::<Self>() + TASK_COMM_LEN)?;
+        let len = comm.iter().position(|b| *b == 0).unwrap_or(TASK_COMM_LEN);
+        core::str::from_utf8(&comm[..len]).ok()
+    }
+
+    /// Check if two records differ only in their timestamp and repeat count.
+    pub fn is_repeat_of(record: &[u8], other: &[u8]) -> bool {
+        match (Self::from_bytes(record), Self::from_bytes(other)) {
+            (Some(a), Some(b)) => {
+                a.cpu == b.cpu
+                    && a.flags == b.flags
+                    && a.entry_len == b.entry_len
+                    && record[size_of::<Self>()..] == other[size_of::<Self>()..]
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if the entry of the record was truncated.
+    pub fn is_truncated(&self) -> bool {
+        self.flags & RECORD_FLAG_TRUNCATED != 0
+    }
+
+    /// Check if the record is a marker, see [`RECORD_FLAG_MARKER`].
+    pub fn is_marker(&self) -> bool {
+        self.flags & RECORD_FLAG_MARKER != 0
+    }
+
+    /// Returns the message of a marker record.
+    ///
+    /// Returns `None` if the record isn't a marker. A message cut in the middle of a
+    /// character is returned up to that character.
+    pub fn marker<'a>(&self, record: &'a [u8]) -> Option<&'a str> {
+        if !self.is_marker() {
+            return None;
+        }
+        let start = self.entry_offset() + size_of::<TraceEntry>();
+        let end = self.entry_offset() + self.entry_len as usize;
+        let msg = record.get(start..end.min(record.len()))?;
+        match core::str::from_utf8(msg) {
+            Ok(msg) => Some(msg),
+            Err(e) => core::str::from_utf8(&msg[..e.valid_up_to()]).ok(),
+        }
+    }
+}
+
+/// Write a record into `record`, which must be exactly as long as the header, the comm, if
+/// any, and the payload.
+fn write_record(
+    record: &mut [u8],
+    header: &RecordHeader,
+    comm: Option<&[u8; TASK_COMM_LEN]>,
+    payload: &[u8],
+) {
+    let (head, rest) = record.split_at_mut(size_of::<RecordHeader>());
+    head.copy_from_slice(header.as_bytes());
+    let rest = match comm {
+        Some(comm) => {
+            let (dst, rest) = rest.split_at_mut(TASK_COMM_LEN);
+            dst.copy_from_slice(comm);
+            rest
+        }
+        None => rest,
+    };
+    rest.copy_from_slice(payload);
+}
+
 /// The TracePoint structure represents a tracepoint in the system.
 pub struct TracePoint<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
     name: &'static str,
     system: &'static str,
+    // Enabled while the default print or the event is, in `DispatchMode::StaticKey`.
     key: &'static RawStaticFalseKey<KernelCodeManipulator<K>>,
+    // Set at init before the tracepoint can be enabled.
+    atomic_dispatch: AtomicBool,
+    // Replaces the static key in `DispatchMode::AtomicFlag`, and is never read without the
+    // `atomic-dispatch` feature. It only gates the checks of
+    // `default_status` and `event_status`, which order the emit, so `Relaxed` is enough.
+    armed: AtomicBool,
+    // Serializes the updates of the key and `armed` with the statuses they summarize.
+    gate: Mutex<L, ()>,
+    // Ordered like `event_status`.
+    default_status: AtomicBool,
+    // Published with `Release` and read with `Acquire`, so an emit that observes the event
+    // enabled also observes the callbacks registered before it was enabled.
     event_status: AtomicBool,
+    // Written once during init before the tracepoint is reachable from the manager.
     id: AtomicU32,
     default_callbacks: Mutex<L, BTreeMap<usize, TracePointFunc>>,
-    event_callbacks: Mutex<L, BTreeMap<usize, Box<dyn TracePointCallBackFunc>>>,
+    // Keyed by descending priority, then ID, so iteration runs in firing order.
+    event_callbacks: Mutex<L, BTreeMap<(Reverse<i32>, usize), EventCallback>>,
     raw_event_callbacks: Mutex<L, BTreeMap<usize, Box<dyn RawTracePointCallBackFunc>>>,
     trace_entry_fmt_func: fn(&[u8]) -> String,
     trace_print_func: fn() -> String,
     schema: Schema,
-    compiled_expr: Mutex<L, Option<Compiled>>,
-    flags: u8,
+    fields: &'static [FieldDesc],
+    compiled_expr: Mutex<L, Option<CompiledFilter>>,
+    // `TRACE_FLAG_*` bits stamped on every entry, with nothing published through them, so
+    // `Relaxed` is enough.
+    flags: AtomicU8,
+    // A standalone setting and statistics counters: no other data is published through
+    // them, so `Relaxed` is enough.
+    max_payload: AtomicUsize,
+    max_callbacks: AtomicUsize,
+    hit_count: AtomicU64,
+    // Holds `TRIGGER_*`, with nothing published through it, so `Relaxed` is enough.
+    trigger: AtomicU8,
+    hist: Mutex<L, Option<HistTrigger>>,
+    // Allocated once at init, its length is published by the `Release` store of the pointer.
+    cpu_hit_counts: AtomicPtr<CpuHitCount>,
+    cpu_hit_counts_len: AtomicUsize,
+    // Set at init to a strong reference that is never released, published with `Release`.
+    shared: AtomicPtr<TraceShared<L>>,
+}
+
+const TRIGGER_NONE: u8 = 0;
+const TRIGGER_TRACE_ON: u8 = 1;
+const TRIGGER_TRACE_OFF: u8 = 2;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Feed an item to a 64-bit FNV-1a hash.
+fn fnv_feed(hash: u64, bytes: &[u8]) -> u64 {
+    // Terminate every item so that adjacent strings can't be confused
+    bytes
+        .iter()
+        .chain(core::iter::once(&0))
+        .fold(hash, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Returns the C type and the name of a field as declared in Linux's `format` file.
+fn linux_field_decl(field: &FieldDesc) -> (String, String) {
+    fn c_type(ty: &str) -> &str {
+        match ty {
+            "i8" => "s8",
+            "i16" => "s16",
+            "i32" => "s32",
+            "i64" => "s64",
+            "usize" => "unsigned long",
+            "isize" => "long",
+            ty => ty,
+        }
+    }
+    if field.is_string() {
+        return ("__data_loc char[]".into(), field.name.into());
+    }
+    // An array is declared as `[T; N]`
+    if let Some((elem, len)) = field
+        .ty
+        .strip_prefix('[')
+        .and_then(|ty| ty.strip_suffix(']'))
+        .and_then(|ty| ty.split_once(';'))
+    {
+        return (
+            c_type(elem.trim()).into(),
+            format!("{}[{}]", field.name, len.trim()),
+        );
+    }
+    (c_type(field.ty).into(), field.name.into())
 }
 
+/// A hit counter of one CPU, on its own cache line so CPUs don't contend for it.
+#[repr(align(64))]
+struct CpuHitCount(AtomicU64);
+
 impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> core::fmt::Debug for TracePoint<L, K> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("TracePoint")
             .field("name", &self.name)
             .field("system", &self.system)
             .field("id", &self.id())
-            .field("flags", &self.flags)
+            .field("flags", &self.flags())
             .finish()
     }
 }
@@ -81,18 +392,665 @@ pub struct CommonTracePointMeta<L: RawMutex + 'static, K: KernelTraceOps + 'stat
     pub print_func: fn(),
 }
 
+/// How the record path pushes to a sink that can't accept a record right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SinkPolicy {
+    /// Wait for the sink lock and push unconditionally.
+    Blocking,
+    /// Drop the record if the sink is locked or full, counting the drop.
+    ///
+    /// This is the safe default for anything touching the kernel fast path, as a slow
+    /// consumer can never stall the traced code.
+    #[default]
+    DropOnBackpressure,
+}
+
+/// An action run when a tracepoint fires and its entry passes the filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceTrigger {
+    /// Turn tracing on globally.
+    TraceOn,
+    /// Turn tracing off globally.
+    TraceOff,
+}
+
+impl TraceTrigger {
+    /// Returns the name of the trigger as written to the trigger file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceTrigger::TraceOn => "traceon",
+            TraceTrigger::TraceOff => "traceoff",
+        }
+    }
+}
+
+/// The clock that timestamps the records, like ftrace's `trace_clock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceClock {
+    /// The per-CPU clock of [`KernelTraceOps::time_now`].
+    #[default]
+    Local,
+    /// The clock of [`KernelTraceOps::time_global`], synchronized across CPUs.
+    Global,
+    /// The monotonic clock of [`KernelTraceOps::time_mono`].
+    Mono,
+    /// The raw counter of [`KernelTraceOps::cycle_counter`], which isn't in nanoseconds.
+    Counter,
+}
+
+impl TraceClock {
+    /// Returns the name of the clock as written to `trace_clock`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceClock::Local => "local",
+            TraceClock::Global => "global",
+            TraceClock::Mono => "mono",
+            TraceClock::Counter => "counter",
+        }
+    }
+
+    /// Read the clock.
+    pub fn now<K: KernelTraceOps>(&self) -> u64 {
+        match self {
+            TraceClock::Local => K::time_now(),
+            TraceClock::Global => K::time_global(),
+            TraceClock::Mono => K::time_mono(),
+            TraceClock::Counter => K::cycle_counter(),
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TraceClock::Global,
+            2 => TraceClock::Mono,
+            3 => TraceClock::Counter,
+            _ => TraceClock::Local,
+        }
+    }
+}
+
+/// A histogram trigger, like ftrace's `hist:keys=<field>`, counting the events that pass
+/// the filter by the value of a key field.
+///
+/// With `hist:keys=<field>:vals=<field>`, the sum, minimum and maximum of a value field
+/// are aggregated under each key value as well.
+#[derive(Debug, Clone)]
+pub struct HistTrigger {
+    key: &'static FieldDesc,
+    val: Option<&'static FieldDesc>,
+    counts: BTreeMap<FieldValue, u64>,
+    aggregates: BTreeMap<FieldValue, HistAggregate>,
+}
+
+/// The aggregates of the value field of a [`HistTrigger`] under one key value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistAggregate {
+    /// The sum of the values, wide enough to never overflow in practice.
+    pub sum: i128,
+    /// The smallest value.
+    pub min: FieldValue,
+    /// The largest value.
+    pub max: FieldValue,
+}
+
+impl HistAggregate {
+    fn new(value: FieldValue) -> Self {
+        Self {
+            sum: 0,
+            min: value,
+            max: value,
+        }
+        .with(value)
+    }
+
+    fn with(mut self, value: FieldValue) -> Self {
+        self.sum += match value {
+            FieldValue::Unsigned(v) => v as i128,
+            FieldValue::Signed(v) => v as i128,
+        };
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self
+    }
+}
+
+impl HistTrigger {
+    /// Returns the descriptor of the key field.
+    pub fn key(&self) -> &'static FieldDesc {
+        self.key
+    }
+
+    /// Returns the descriptor of the value field, if any.
+    pub fn val(&self) -> Option<&'static FieldDesc> {
+        self.val
+    }
+
+    /// Returns the number of events counted under each key value, sorted by key.
+    pub fn counts(&self) -> &BTreeMap<FieldValue, u64> {
+        &self.counts
+    }
+
+    /// Returns the aggregates of the value field under each key value, sorted by key.
+    ///
+    /// This is empty if the trigger has no value field. The number of aggregated values is
+    /// the count of the key in [`HistTrigger::counts`].
+    pub fn aggregates(&self) -> &BTreeMap<FieldValue, HistAggregate> {
+        &self.aggregates
+    }
+
+    /// Returns the trigger as written to the trigger file.
+    pub fn name(&self) -> String {
+        match self.val {
+            Some(val) => format!("hist:keys={}:vals={}", self.key.name, val.name),
+            None => format!("hist:keys={}", self.key.name),
+        }
+    }
+
+    /// Returns the trigger as read from the trigger file, listing the hit count as a value.
+    pub(crate) fn info(&self) -> String {
+        match self.val {
+            Some(val) => format!("hist:keys={}:vals=hitcount,{}", self.key.name, val.name),
+            None => format!("hist:keys={}:vals=hitcount", self.key.name),
+        }
+    }
+
+    /// Count an entry under the value of its key field, aggregating its value field.
+    ///
+    /// An entry too short to hold the key, e.g. a truncated one, is not counted, and one
+    /// too short to hold the value field is counted but not aggregated.
+    fn record(&mut self, entry: &[u8]) {
+        let Some(key) = self.key.decode(entry) else {
+            return;
+        };
+        *self.counts.entry(key).or_insert(0) += 1;
+        if let Some(value) = self.val.and_then(|val| val.decode(entry)) {
+            self.aggregates
+                .entry(key)
+                .and_modify(|aggregate| *aggregate = aggregate.with(value))
+                .or_insert_with(|| HistAggregate::new(value));
+        }
+    }
+}
+
+/// How the generated `trace_xxx` function checks whether the tracepoint is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchMode {
+    /// Patch the branch in the traced code with a static key.
+    ///
+    /// While disabled, this costs only the patched-out branch, unless the `atomic-dispatch`
+    /// feature is enabled, which adds a relaxed load of the flag of `AtomicFlag`, never set
+    /// in this mode. It needs writable kernel text and a working
+    /// [`KernelTraceOps::write_kernel_text`].
+    #[default]
+    StaticKey,
+    /// Branch on an atomic flag instead of patching any code.
+    ///
+    /// This costs a load and a well-predicted branch on every call, and suits platforms
+    /// that forbid runtime code modification. `static_keys::global_init` is not needed.
+    /// It is only available with the `atomic-dispatch` feature, so that the tracepoints of
+    /// builds without it check nothing but the static key.
+    #[cfg(any(test, feature = "atomic-dispatch"))]
+    AtomicFlag,
+}
+
+/// Whether the default print and the event of a tracepoint are enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnableState {
+    /// Neither the default print nor the event is enabled.
+    Off,
+    /// Only one of the default print and the event is enabled.
+    Partial,
+    /// Both the default print and the event are enabled.
+    On,
+}
+
+/// A sink registered with the [`crate::TracingEventsManager`].
+pub(crate) struct TraceSink<L: RawMutex + 'static> {
+    // Taken out when the sink is removed, while the record path may still hold the sink.
+    pub(crate) sink: Mutex<L, Option<Box<dyn TracePipeSink + Send>>>,
+    pub(crate) policy: SinkPolicy,
+    // A statistics counter, so `Relaxed` is enough.
+    pub(crate) dropped: AtomicUsize,
+}
+
+impl<L: RawMutex + 'static> TraceSink<L> {
+    pub(crate) fn new(sink: Box<dyn TracePipeSink + Send>, policy: SinkPolicy) -> Self {
+        Self {
+            sink: Mutex::new(Some(sink)),
+            policy,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Push the record according to the policy, returning false if it was dropped.
+    ///
+    /// A sink removed in the meantime takes no record and drops none.
+    fn push(&self, record: Vec<u8>) -> bool {
+        match self.policy {
+            SinkPolicy::Blocking => {
+                if let Some(sink) = self.sink.lock().as_mut() {
+                    sink.push(record);
+                }
+                true
+            }
+            SinkPolicy::DropOnBackpressure => {
+                let pushed = self.sink.try_lock().is_some_and(|mut sink| {
+                    sink.as_mut()
+                        .is_none_or(|sink| sink.try_push(record).is_ok())
+                });
+                if !pushed {
+                    self.dropped
+                        .fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+                }
+                pushed
+            }
+        }
+    }
+}
+
+/// The sinks of the [`crate::TracingEventsManager`] by ID.
+pub(crate) type SinkMap<L> = Arc<BTreeMap<usize, Arc<TraceSink<L>>>>;
+
+/// State shared between the [`crate::TracingEventsManager`] and the record path of every
+/// tracepoint.
+pub(crate) struct TraceShared<L: RawMutex + 'static> {
+    // Replaced as a whole when a sink is added or removed, so the record path holds the lock
+    // only to clone the `Arc` and never while pushing, and a blocking sink can't stall a
+    // `DropOnBackpressure` one.
+    pub(crate) sinks: Mutex<L, SinkMap<L>>,
+    pub(crate) diagnostic: Mutex<L, Option<Box<dyn TracePipeSink + Send>>>,
+    // Only selects the record layout and publishes no other data, so `Relaxed` is enough.
+    pub(crate) record_comm: AtomicBool,
+    // Flipped by the manager and by triggers, gating only the push, so `Relaxed` is enough.
+    pub(crate) tracing_on: AtomicBool,
+    pub(crate) callbacks_while_off: AtomicBool,
+    // The selected `TraceClock`, only read to timestamp records, so `Relaxed` is enough.
+    pub(crate) clock: AtomicU8,
+    // A standalone mask and counter gating only the push, so `Relaxed` is enough.
+    pub(crate) cpumask: AtomicU64,
+    pub(crate) cpumask_skipped: AtomicU64,
+    pub(crate) pid_filter: PidFilterSlot<L>,
+}
+
+/// The set of PIDs whose records are pushed, or not pushed if `exclude` is set.
+#[derive(Debug, Clone)]
+pub(crate) struct PidFilter {
+    pub(crate) pids: BTreeSet<u32>,
+    pub(crate) exclude: bool,
+}
+
+impl PidFilter {
+    fn allows(&self, pid: u32) -> bool {
+        self.pids.contains(&pid) != self.exclude
+    }
+}
+
+/// The current [`PidFilter`], read by the record path without taking any lock.
+///
+/// The filter is published as an `Arc` snapshot swapped in as a whole. A reader counts itself
+/// in `readers` around its use of the pointer, and a writer waits for the count to drop to zero
+/// before releasing the snapshot it replaced. A reader never waits, so the record path may run
+/// in IRQ context, even on the CPU of an interrupted writer.
+pub(crate) struct PidFilterSlot<L: RawMutex + 'static> {
+    // Lets the record path skip the counter while no filter is set. Only a hint, the pointer
+    // decides, so `Relaxed` is enough.
+    active: AtomicBool,
+    // Null or a strong reference from `Arc::into_raw`.
+    current: AtomicPtr<PidFilter>,
+    readers: AtomicUsize,
+    // Serializes the writers, so that `active` matches the last pointer stored.
+    update: Mutex<L, ()>,
+}
+
+impl<L: RawMutex + 'static> PidFilterSlot<L> {
+    fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            current: AtomicPtr::new(core::ptr::null_mut()),
+            readers: AtomicUsize::new(0),
+            update: Mutex::new(()),
+        }
+    }
+
+    /// Check if records from the PID pass the filter, without taking any lock.
+    fn allows(&self, pid: u32) -> bool {
+        if !self.active.load(core::sync::atomic::Ordering::Relaxed) {
+            return true;
+        }
+        self.with_current(|filter| filter.is_none_or(|filter| filter.allows(pid)))
+    }
+
+    /// Returns the current filter, if any.
+    pub(crate) fn get(&self) -> Option<Arc<PidFilter>> {
+        self.with_current(|filter| {
+            filter.map(|filter| {
+                let ptr = filter as *const PidFilter;
+                // The reference is still held by the slot while counted as a reader
+                unsafe {
+                    Arc::increment_strong_count(ptr);
+                    Arc::from_raw(ptr)
+                }
+            })
+        })
+    }
+
+    fn with_current<R>(&self, f: impl FnOnce(Option<&PidFilter>) -> R) -> R {
+        // `SeqCst` orders the count before the load of the pointer, so a writer replacing it
+        // either sees this reader or stored its pointer before this load
+        self.readers
+            .fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        let ptr = self.current.load(core::sync::atomic::Ordering::SeqCst);
+        // Not released while this reader is counted
+        let result = f(unsafe { ptr.as_ref() });
+        self.readers
+            .fetch_sub(1, core::sync::atomic::Ordering::Release);
+        result
+    }
+
+    /// Replace the filter, or remove it with `None`.
+    ///
+    /// This waits for the readers of the replaced filter, which only test a PID.
+    pub(crate) fn set(&self, filter: Option<PidFilter>) {
+        let ptr = filter.map_or(core::ptr::null_mut(), |filter| {
+            Arc::into_raw(Arc::new(filter)).cast_mut()
+        });
+        let _update = self.update.lock();
+        let old = self.current.swap(ptr, core::sync::atomic::Ordering::SeqCst);
+        self.active
+            .store(!ptr.is_null(), core::sync::atomic::Ordering::Relaxed);
+        if old.is_null() {
+            return;
+        }
+        while self.readers.load(core::sync::atomic::Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+        // Unpublished, and no reader that may have loaded it is left
+        drop(unsafe { Arc::from_raw(old) });
+    }
+}
+
+impl<L: RawMutex + 'static> Drop for PidFilterSlot<L> {
+    fn drop(&mut self) {
+        let ptr = *self.current.get_mut();
+        if !ptr.is_null() {
+            drop(unsafe { Arc::from_raw(ptr) });
+        }
+    }
+}
+
+impl<L: RawMutex + 'static> TraceShared<L> {
+    pub(crate) fn new() -> Self {
+        Self {
+            sinks: Mutex::new(Arc::new(BTreeMap::new())),
+            diagnostic: Mutex::new(None),
+            record_comm: AtomicBool::new(false),
+            tracing_on: AtomicBool::new(true),
+            callbacks_while_off: AtomicBool::new(true),
+            clock: AtomicU8::new(TraceClock::Local as u8),
+            cpumask: AtomicU64::new(u64::MAX),
+            cpumask_skipped: AtomicU64::new(0),
+            pid_filter: PidFilterSlot::new(),
+        }
+    }
+
+    /// Returns the current sinks, without holding their lock.
+    pub(crate) fn sinks(&self) -> SinkMap<L> {
+        self.sinks.lock().clone()
+    }
+
+    /// Check if records from the PID pass the PID filter.
+    fn pid_allowed(&self, pid: u32) -> bool {
+        self.pid_filter.allows(pid)
+    }
+
+    /// Check if records from the CPU pass the cpumask, counting them as skipped otherwise.
+    ///
+    /// CPUs past the width of the mask are always recorded.
+    fn cpu_allowed(&self, cpu: u32) -> bool {
+        let mask = self.cpumask.load(core::sync::atomic::Ordering::Relaxed);
+        if cpu >= u64::BITS || mask & (1 << cpu) != 0 {
+            return true;
+        }
+        self.cpumask_skipped
+            .fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        false
+    }
+
+    /// Check if event callbacks may run given the tracing switch.
+    fn callbacks_enabled(&self) -> bool {
+        self.tracing_on.load(core::sync::atomic::Ordering::Relaxed)
+            || self
+                .callbacks_while_off
+                .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Push a marker record carrying `msg` to the trace pipe and every additional sink.
+    ///
+    /// See [`crate::TracingEventsManager::trace_marker`].
+    pub(crate) fn push_marker<K: KernelTraceOps>(&self, msg: &str) {
+        if !self.tracing_on.load(core::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        let common = TraceEntry {
+            common_type: 0,
+            common_flags: K::irq_flags(),
+            common_preempt_count: K::preempt_count(),
+            common_pid: K::current_pid() as i32,
+        };
+        let common = unsafe {
+            core::slice::from_raw_parts(
+                &common as *const TraceEntry as *const u8,
+                size_of::<TraceEntry>(),
+            )
+        };
+        let msg = &msg.as_bytes()[..msg.len().min(u16::MAX as usize - common.len())];
+        let mut comm = [0; TASK_COMM_LEN];
+        let record_comm = self.record_comm.load(core::sync::atomic::Ordering::Relaxed);
+        let mut flags = RECORD_FLAG_MARKER;
+        if record_comm {
+            K::current_comm(&mut comm);
+            flags |= RECORD_FLAG_COMM;
+        }
+        let clock = TraceClock::from_u8(self.clock.load(core::sync::atomic::Ordering::Relaxed));
+        let header = RecordHeader {
+            timestamp: clock.now::<K>(),
+            cpu: K::cpu_id(),
+            flags,
+            entry_len: (common.len() + msg.len()) as u16,
+            repeat: 0,
+            magic: RECORD_MAGIC,
+            version: RECORD_FORMAT_VERSION,
+        };
+        let mut record = Vec::with_capacity(header.entry_offset() + header.entry_len as usize);
+        record.extend_from_slice(header.as_bytes());
+        if record_comm {
+            record.extend_from_slice(&comm);
+        }
+        record.extend_from_slice(common);
+        record.extend_from_slice(msg);
+        K::trace_pipe_push_raw_record(&record);
+        for sink in self.sinks().values() {
+            if !sink.push(record.clone()) {
+                self.report(DiagnosticNotice {
+                    kind: DiagnosticKind::Dropped,
+                    event_id: 0,
+                    timestamp: header.timestamp,
+                });
+            }
+        }
+    }
+
+    /// Push a notice to the diagnostic pipe, if any.
+    ///
+    /// The notice is lost if the pipe is locked or full, so reporting never stalls the
+    /// record path.
+    pub(crate) fn report(&self, notice: DiagnosticNotice) {
+        let Some(mut diagnostic) = self.diagnostic.try_lock() else {
+            return;
+        };
+        if let Some(pipe) = diagnostic.as_mut() {
+            let _ = pipe.try_push(notice.to_bytes().to_vec());
+        }
+    }
+}
+
+impl<L: RawMutex + 'static> core::fmt::Debug for TraceShared<L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TraceShared")
+            .field("sinks", &self.sinks.lock().len())
+            .field("diagnostic", &self.diagnostic.lock().is_some())
+            .finish()
+    }
+}
+
+/// The data passed to the default print callback registered for each tracepoint at init.
+///
+/// It gives the record path access to the state shared with the tracepoint's subsystem
+/// and the [`crate::TracingEventsManager`].
+#[derive(Debug)]
+pub struct TracePointContext<L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    tracepoint: &'static TracePoint<L, K>,
+    // Only gates recording and publishes no other data, so `Relaxed` is enough.
+    paused: Arc<AtomicBool>,
+    shared: Arc<TraceShared<L>>,
+}
+
+impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePointContext<L, K> {
+    pub(crate) fn new(
+        tracepoint: &'static TracePoint<L, K>,
+        paused: Arc<AtomicBool>,
+        shared: Arc<TraceShared<L>>,
+    ) -> Self {
+        Self {
+            tracepoint,
+            paused,
+            shared,
+        }
+    }
+
+    /// Run the trigger of the tracepoint, if any.
+    ///
+    /// This is called by the generated code for every entry that passes the filter, before
+    /// it is pushed, so a `traceoff` trigger drops the entry that fired it. The entry is
+    /// counted by the histogram trigger, if any, whether or not tracing is on.
+    pub fn run_trigger(&self, entry: &[u8]) {
+        if let Some(hist) = self.tracepoint.hist.lock().as_mut() {
+            hist.record(entry);
+        }
+        let tracing_on = match self.tracepoint.trigger() {
+            Some(TraceTrigger::TraceOn) => true,
+            Some(TraceTrigger::TraceOff) => false,
+            None => return,
+        };
+        self.shared
+            .tracing_on
+            .store(tracing_on, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Push an assembled trace entry to the trace pipe and every additional sink.
+    ///
+    /// Nothing is pushed while tracing is off. Truncated records and records dropped by a
+    /// sink are reported to the diagnostic pipe.
+    pub fn push_record(&self, entry: &[u8]) {
+        if !self
+            .shared
+            .tracing_on
+            .load(core::sync::atomic::Ordering::Relaxed)
+        {
+            return;
+        }
+        if !self.shared.cpu_allowed(K::cpu_id()) {
+            return;
+        }
+        if !self.shared.pid_allowed(K::current_pid()) {
+            return;
+        }
+        let mut comm = [0; TASK_COMM_LEN];
+        let record_comm = self
+            .shared
+            .record_comm
+            .load(core::sync::atomic::Ordering::Relaxed);
+        if record_comm {
+            K::current_comm(&mut comm);
+        }
+        let comm = record_comm.then_some(&comm);
+        let (header, len) = self.tracepoint.record_header(entry, record_comm);
+        let payload = &entry[..len];
+        // Written in place, so the entry is copied once, straight into the trace pipe
+        K::trace_pipe_write_raw_record(header.entry_offset() + len, &mut |record| {
+            write_record(record, &header, comm, payload)
+        });
+        let notice = |kind| DiagnosticNotice {
+            kind,
+            event_id: self.tracepoint.id(),
+            timestamp: header.timestamp,
+        };
+        if header.is_truncated() {
+            self.shared.report(notice(DiagnosticKind::Truncated));
+        }
+        let sinks = self.shared.sinks();
+        if sinks.is_empty() {
+            return;
+        }
+        let mut record = vec![0; header.entry_offset() + len];
+        write_record(&mut record, &header, comm, payload);
+        for sink in sinks.values() {
+            if !sink.push(record.clone()) {
+                self.shared.report(notice(DiagnosticKind::Dropped));
+            }
+        }
+    }
+
+    /// Returns the tracepoint.
+    pub fn tracepoint(&self) -> &'static TracePoint<L, K> {
+        self.tracepoint
+    }
+
+    /// Check if recording is paused for the subsystem of the tracepoint.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(core::sync::atomic::Ordering::Relaxed)
+    }
+}
+
 /// A trait for callback functions that can be registered with a tracepoint.
 pub trait TracePointCallBackFunc: Send + Sync {
     /// Call the callback function with the given trace entry data.
     fn call(&self, entry: &[u8]);
 }
 
+/// A trait for callback functions that receive the fields of a trace entry.
+///
+/// Unlike [`TracePointCallBackFunc`], the callback reads the fields by name through a
+/// [`FieldView`] instead of decoding the raw entry itself.
+pub trait TracePointFieldCallBackFunc: Send + Sync {
+    /// Call the callback function with a view of the fields of the trace entry.
+    fn call(&self, view: &FieldView<'_>);
+}
+
+/// Adapts a [`TracePointFieldCallBackFunc`] to the event callbacks.
+struct FieldCallback {
+    fields: &'static [FieldDesc],
+    callback: Box<dyn TracePointFieldCallBackFunc>,
+}
+
+impl TracePointCallBackFunc for FieldCallback {
+    fn call(&self, entry: &[u8]) {
+        self.callback.call(&FieldView::new(self.fields, entry));
+    }
+}
+
 /// A trait for raw callback functions that can be registered with a tracepoint.
 pub trait RawTracePointCallBackFunc: Send + Sync {
     /// Call the callback function with the given raw trace entry data.
     fn call(&self, args: &[u64]);
 }
 
+/// A registered event callback and the filter restricting the entries it sees.
+struct EventCallback {
+    callback: Box<dyn TracePointCallBackFunc>,
+    filter: Option<CompiledFilter>,
+}
+
 /// A structure representing a registered tracepoint callback function.
 #[derive(Debug)]
 pub struct TracePointFunc {
@@ -111,29 +1069,125 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePoint<L, K> {
         fmt_func: fn(&[u8]) -> String,
         trace_print_func: fn() -> String,
         schema: Schema,
+        fields: &'static [FieldDesc],
     ) -> Self {
         Self {
             name,
             system,
             key,
+            atomic_dispatch: AtomicBool::new(false),
+            armed: AtomicBool::new(false),
+            gate: Mutex::new(()),
+            default_status: AtomicBool::new(false),
             event_status: AtomicBool::new(false),
             id: AtomicU32::new(0),
-            flags: 0,
+            flags: AtomicU8::new(0),
             trace_entry_fmt_func: fmt_func,
             trace_print_func,
             default_callbacks: Mutex::new(BTreeMap::new()),
             event_callbacks: Mutex::new(BTreeMap::new()),
             raw_event_callbacks: Mutex::new(BTreeMap::new()),
             schema,
+            fields,
             compiled_expr: Mutex::new(None),
+            max_payload: AtomicUsize::new(0),
+            max_callbacks: AtomicUsize::new(0),
+            hit_count: AtomicU64::new(0),
+            trigger: AtomicU8::new(TRIGGER_NONE),
+            hist: Mutex::new(None),
+            cpu_hit_counts: AtomicPtr::new(core::ptr::null_mut()),
+            cpu_hit_counts_len: AtomicUsize::new(0),
+            shared: AtomicPtr::new(core::ptr::null_mut()),
         }
     }
 
     /// Returns the schema of the tracepoint.
+    ///
+    /// This is the field layout as described by [`tp_lexer`]. To introspect the fields, use
+    /// [`TracePoint::fields`] or [`TracePoint::all_fields`] instead.
     pub fn schema(&self) -> &Schema {
         &self.schema
     }
 
+    /// Returns the descriptors of the event-specific fields of the tracepoint.
+    ///
+    /// The fields are listed in `TP_STRUCT__entry` order, which is also their order in the
+    /// entry. The common fields are described by [`crate::COMMON_FIELDS`].
+    pub fn fields(&self) -> &'static [FieldDesc] {
+        self.fields
+    }
+
+    /// Returns the descriptors of all fields of the tracepoint, the common fields first.
+    pub fn all_fields(&self) -> impl Iterator<Item = &'static FieldDesc> + use<L, K> {
+        crate::COMMON_FIELDS.iter().chain(self.fields.iter())
+    }
+
+    /// Returns the minimum length of an entry holding all fields of the tracepoint.
+    pub fn entry_size(&self) -> usize {
+        self.all_fields()
+            .map(|field| field.offset + field.size)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the descriptor of the field with the given name, including the common fields.
+    pub fn field(&self, name: &str) -> Option<&'static FieldDesc> {
+        self.all_fields().find(|field| field.name == name)
+    }
+
+    /// Compile a filter expression against the fields of the tracepoint.
+    ///
+    /// Besides the fields of `TP_STRUCT__entry`, the expression can refer to the common
+    /// fields `common_type`, `common_flags`, `common_preempt_count` and `common_pid`, which
+    /// are read from the [`TraceEntry`] at the front of every entry, e.g. `common_pid == 1234`.
+    /// Flag bits are tested with the bitwise operators, e.g. `flags & 0x4 != 0`, and
+    /// `__string` fields with strings, e.g. `path ~ "/dev/*"`, see [`CompiledFilter`] for the
+    /// grammar.
+    ///
+    /// An unknown field, or a field used with an operator its type doesn't support, e.g. an
+    /// array or a glob match on an integer, is reported as such, and syntax errors are
+    /// reported with their offset.
+    pub fn compile_filter(&self, filter: &str) -> Result<CompiledFilter, FilterError> {
+        let fields = self.all_fields().collect::<Vec<_>>();
+        CompiledFilter::compile(filter, &fields)
+    }
+
+    /// Evaluate a compiled filter against a trace entry.
+    ///
+    /// The entry must cover every field of the tracepoint, including the common fields, for
+    /// the filter to read it. A shorter entry, e.g. one truncated in its dynamic area, doesn't
+    /// match and is reported as [`DiagnosticKind::FilterError`] instead of being read out of
+    /// bounds.
+    pub fn evaluate_filter(&self, filter: &CompiledFilter, entry: &[u8]) -> bool {
+        if entry.len() < self.entry_size() {
+            if let Some(shared) = self.shared() {
+                shared.report(DiagnosticNotice {
+                    kind: DiagnosticKind::FilterError,
+                    event_id: self.id(),
+                    timestamp: self.clock().now::<K>(),
+                });
+            }
+            return false;
+        }
+        filter.matches(entry)
+    }
+
+    fn check_filter_field(&self, name: &str) -> Result<(), FilterError> {
+        let Some(field) = self.field(name) else {
+            return Err(FilterError::UnknownField {
+                name: name.into(),
+                valid: self.all_fields().map(|field| field.name).collect(),
+            });
+        };
+        if field.is_array() || field.is_string() {
+            return Err(FilterError::TypeMismatch {
+                field: field.name,
+                ty: field.ty,
+            });
+        }
+        Ok(())
+    }
+
     /// Returns the name of the tracepoint.
     pub fn name(&self) -> &'static str {
         self.name
@@ -155,22 +1209,285 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePoint<L, K> {
     }
 
     /// Returns the flags of the tracepoint.
+    ///
+    /// These are `TRACE_FLAG_*` bits, e.g. [`TRACE_FLAG_IRQS_NOSUPPORT`], that the generated
+    /// code ORs into the `common_flags` of every entry of the tracepoint, next to the
+    /// [`KernelTraceOps::irq_flags`] of the moment. A flag set here is thus reported for
+    /// every event, which suits a state the platform knows per tracepoint rather than per
+    /// call, like a tracepoint that always fires with IRQs off.
     pub fn flags(&self) -> u8 {
+        self.flags.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Set the given `TRACE_FLAG_*` bits of the tracepoint, see [`TracePoint::flags`].
+    ///
+    /// This can be called at any time, concurrently with emits and other updates. An event
+    /// racing with the change may be stamped with the old or the new flags.
+    pub fn set_flag(&self, flag: u8) {
         self.flags
+            .fetch_or(flag, core::sync::atomic::Ordering::Relaxed);
     }
 
-    /// Sets the compiled expression for the tracepoint.
-    pub fn set_compiled_expr(&self, compiled: Option<Compiled>) {
+    /// Clear the given `TRACE_FLAG_*` bits of the tracepoint, see [`TracePoint::set_flag`].
+    pub fn clear_flag(&self, flag: u8) {
+        self.flags
+            .fetch_and(!flag, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns whether all the given `TRACE_FLAG_*` bits of the tracepoint are set.
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags() & flag == flag
+    }
+
+    /// Sets the compiled expression for the tracepoint.
+    pub fn set_compiled_expr(&self, compiled: Option<CompiledFilter>) {
         let mut guard = self.compiled_expr.lock();
         *guard = compiled;
     }
 
     /// Returns the compiled expression for the tracepoint.
-    pub fn get_compiled_expr(&self) -> Option<Compiled> {
+    pub fn get_compiled_expr(&self) -> Option<CompiledFilter> {
         let guard = self.compiled_expr.lock();
         guard.clone()
     }
 
+    /// Set the maximum number of payload bytes recorded for each event.
+    ///
+    /// Entries with a larger payload are truncated and flagged with
+    /// [`RECORD_FLAG_TRUNCATED`]. Zero means no cap.
+    pub fn set_max_payload(&self, bytes: usize) {
+        self.max_payload
+            .store(bytes, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of payload bytes recorded for each event.
+    pub fn max_payload(&self) -> usize {
+        self.max_payload.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Allocate one hit counter for each of `num_cpus` CPUs.
+    ///
+    /// Hits on CPUs whose ID is out of range, or before the counters are allocated, are
+    /// counted by a shared counter. Allocating again is a no-op.
+    pub(crate) fn init_cpu_hit_counts(&self, num_cpus: usize) {
+        if num_cpus == 0 || !self.cpu_hit_counts().is_empty() {
+            return;
+        }
+        let counts: Box<[CpuHitCount]> = (0..num_cpus)
+            .map(|_| CpuHitCount(AtomicU64::new(0)))
+            .collect();
+        // The counters live as long as the static tracepoint
+        let counts = Box::leak(counts);
+        self.cpu_hit_counts_len
+            .store(num_cpus, core::sync::atomic::Ordering::Relaxed);
+        self.cpu_hit_counts
+            .store(counts.as_mut_ptr(), core::sync::atomic::Ordering::Release);
+    }
+
+    fn cpu_hit_counts(&self) -> &[CpuHitCount] {
+        let ptr = self
+            .cpu_hit_counts
+            .load(core::sync::atomic::Ordering::Acquire);
+        if ptr.is_null() {
+            return &[];
+        }
+        let len = self
+            .cpu_hit_counts_len
+            .load(core::sync::atomic::Ordering::Relaxed);
+        unsafe { core::slice::from_raw_parts(ptr, len) }
+    }
+
+    /// Count a hit of the tracepoint on the current CPU.
+    ///
+    /// This is called by the generated `trace_xxx` function whenever it fires with the
+    /// default print or the event callbacks enabled.
+    pub fn inc_hit_count(&self) {
+        let counter = match self.cpu_hit_counts().get(K::cpu_id() as usize) {
+            Some(count) => &count.0,
+            None => &self.hit_count,
+        };
+        counter.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns how many times the tracepoint has fired since the last reset, summed across
+    /// all CPUs.
+    pub fn hit_count(&self) -> u64 {
+        self.cpu_hit_counts()
+            .iter()
+            .map(|count| count.0.load(core::sync::atomic::Ordering::Relaxed))
+            .sum::<u64>()
+            + self.hit_count.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Attach the state shared with the [`crate::TracingEventsManager`].
+    ///
+    /// The tracepoint keeps a strong reference for the rest of the program, as the record
+    /// path may read it at any time. The state can be attached only once: returns `false`
+    /// and keeps the current state if one is already attached, since a record path may still
+    /// be reading it.
+    pub(crate) fn set_shared(&self, shared: &Arc<TraceShared<L>>) -> bool {
+        let ptr = Arc::into_raw(shared.clone()) as *mut TraceShared<L>;
+        let attached = self.shared.compare_exchange(
+            core::ptr::null_mut(),
+            ptr,
+            core::sync::atomic::Ordering::AcqRel,
+            core::sync::atomic::Ordering::Acquire,
+        );
+        if attached.is_err() {
+            // Never published, so nothing else holds this reference
+            drop(unsafe { Arc::from_raw(ptr) });
+        }
+        attached.is_ok()
+    }
+
+    /// Check whether the tracepoint was registered with a [`crate::TracingEventsManager`].
+    pub fn is_registered(&self) -> bool {
+        self.shared().is_some()
+    }
+
+    fn shared(&self) -> Option<&TraceShared<L>> {
+        let ptr = self.shared.load(core::sync::atomic::Ordering::Acquire);
+        // The reference taken in `set_shared` is never released
+        unsafe { ptr.as_ref() }
+    }
+
+    fn clock(&self) -> TraceClock {
+        self.shared().map_or(TraceClock::Local, |shared| {
+            TraceClock::from_u8(shared.clock.load(core::sync::atomic::Ordering::Relaxed))
+        })
+    }
+
+    /// Check if the event callbacks and raw event callbacks may run.
+    ///
+    /// They may not while tracing is off, unless configured otherwise with
+    /// [`crate::TracingEventsManager::set_callbacks_while_off`]. This is called by the
+    /// generated `trace_xxx` function.
+    pub fn callbacks_enabled(&self) -> bool {
+        self.shared()
+            .is_none_or(|shared| shared.callbacks_enabled())
+    }
+
+    /// Set the trigger run when the tracepoint fires, or remove it with `None`.
+    pub fn set_trigger(&self, trigger: Option<TraceTrigger>) {
+        let trigger = match trigger {
+            Some(TraceTrigger::TraceOn) => TRIGGER_TRACE_ON,
+            Some(TraceTrigger::TraceOff) => TRIGGER_TRACE_OFF,
+            None => TRIGGER_NONE,
+        };
+        self.trigger
+            .store(trigger, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the trigger run when the tracepoint fires.
+    pub fn trigger(&self) -> Option<TraceTrigger> {
+        match self.trigger.load(core::sync::atomic::Ordering::Relaxed) {
+            TRIGGER_TRACE_ON => Some(TraceTrigger::TraceOn),
+            TRIGGER_TRACE_OFF => Some(TraceTrigger::TraceOff),
+            _ => None,
+        }
+    }
+
+    /// Set a histogram trigger keyed on the given field, replacing any existing one.
+    ///
+    /// The key may be a common field. The histogram starts empty, even if it replaces one
+    /// with the same key. Fails if the field doesn't exist or has no numeric value.
+    pub fn set_hist_trigger(&self, key: &str) -> Result<(), FilterError> {
+        self.set_hist_trigger_with_val(key, None)
+    }
+
+    /// Set a histogram trigger keyed on the given field that also aggregates the sum,
+    /// minimum and maximum of the value field, if any, replacing any existing trigger.
+    ///
+    /// See [`TracePoint::set_hist_trigger`]. The value field must be numeric as well.
+    pub fn set_hist_trigger_with_val(
+        &self,
+        key: &str,
+        val: Option<&str>,
+    ) -> Result<(), FilterError> {
+        self.check_filter_field(key)?;
+        let key = self.field(key).expect("the field was checked");
+        let val = match val {
+            Some(val) => {
+                self.check_filter_field(val)?;
+                Some(self.field(val).expect("the field was checked"))
+            }
+            None => None,
+        };
+        *self.hist.lock() = Some(HistTrigger {
+            key,
+            val,
+            counts: BTreeMap::new(),
+            aggregates: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Remove the histogram trigger and its counts.
+    pub fn clear_hist_trigger(&self) {
+        *self.hist.lock() = None;
+    }
+
+    /// Returns a copy of the histogram trigger, with its counts so far.
+    pub fn hist_trigger(&self) -> Option<HistTrigger> {
+        self.hist.lock().clone()
+    }
+
+    /// Reset the hit count of the tracepoint on all CPUs.
+    ///
+    /// Hits racing with the reset on other CPUs may or may not be counted.
+    pub fn reset_hit_count(&self) {
+        for count in self.cpu_hit_counts() {
+            count.0.store(0, core::sync::atomic::Ordering::Relaxed);
+        }
+        self.hit_count
+            .store(0, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Build the raw record for an assembled trace entry.
+    ///
+    /// The entry is prefixed with a [`RecordHeader`] and the given comm, if any, and its
+    /// payload is truncated to the cap set by [`TracePoint::set_max_payload`]. The record is
+    /// timestamped with the [`TraceClock`] selected in the manager.
+    ///
+    /// An entry longer than [`RecordHeader::entry_len`] can describe, `u16::MAX` bytes, is
+    /// truncated to that length.
+    pub fn build_record(&self, entry: &[u8], comm: Option<&[u8; TASK_COMM_LEN]>) -> Vec<u8> {
+        let (header, len) = self.record_header(entry, comm.is_some());
+        let mut record = vec![0; header.entry_offset() + len];
+        write_record(&mut record, &header, comm, &entry[..len]);
+        record
+    }
+
+    /// Returns the header of the record for an assembled trace entry, and the number of
+    /// bytes of the entry the record holds, see [`TracePoint::build_record`].
+    fn record_header(&self, entry: &[u8], has_comm: bool) -> (RecordHeader, usize) {
+        let common_len = size_of::<TraceEntry>();
+        let max_payload = self.max_payload();
+        let mut flags = 0;
+        if entry.len() > u16::MAX as usize {
+            flags |= RECORD_FLAG_TRUNCATED;
+        }
+        let entry = &entry[..entry.len().min(u16::MAX as usize)];
+        let mut len = entry.len();
+        if max_payload != 0 && len > common_len + max_payload {
+            len = common_len + max_payload;
+            flags |= RECORD_FLAG_TRUNCATED;
+        }
+        if has_comm {
+            flags |= RECORD_FLAG_COMM;
+        }
+        let header = RecordHeader {
+            timestamp: self.clock().now::<K>(),
+            cpu: K::cpu_id(),
+            flags,
+            entry_len: entry.len() as u16,
+            repeat: 0,
+            magic: RECORD_MAGIC,
+            version: RECORD_FORMAT_VERSION,
+        };
+        (header, len)
+    }
+
     /// Returns the format function for the tracepoint.
     pub(crate) fn fmt_func(&self) -> fn(&[u8]) -> String {
         self.trace_entry_fmt_func
@@ -182,17 +1499,164 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePoint<L, K> {
     /// to see the format of the tracepoint.
     pub fn print_fmt(&self) -> String {
         let post_str = (self.trace_print_func)();
-        format!("name: {}\nID: {}\n{}\n", self.name(), self.id(), post_str)
+        format!(
+            "name: {}\nID: {}\ncompat: {:#018x}\n{}\n",
+            self.name(),
+            self.id(),
+            self.compat_hash(),
+            post_str
+        )
+    }
+
+    /// Returns the format of the tracepoint exactly as Linux's `format` file lays it out.
+    ///
+    /// Unlike [`TracePoint::print_fmt`], every field, including the common fields, is
+    /// described by a `field:` line with its C type, offset, size and signedness, so tools
+    /// like `trace-cmd` can parse the records. Arrays are written as `type name[len]` and
+    /// `__string` fields as `__data_loc char[] name`.
+    pub fn print_fmt_linux(&self) -> String {
+        let mut fmt = format!("name: {}\nID: {}\nformat:\n", self.name(), self.id());
+        let common_types = ["unsigned short", "unsigned char", "unsigned char", "int"];
+        let fields = crate::COMMON_FIELDS
+            .iter()
+            .zip(common_types)
+            .map(|(field, ty)| (field, String::from(ty), String::from(field.name)))
+            .chain(self.fields.iter().map(|field| {
+                let (ty, name) = linux_field_decl(field);
+                (field, ty, name)
+            }));
+        for (idx, (field, ty, name)) in fields.enumerate() {
+            if idx == crate::COMMON_FIELDS.len() {
+                fmt.push('\n');
+            }
+            fmt.push_str(&format!(
+                "\tfield:{ty} {name};\toffset:{};\tsize:{};\tsigned:{};\n",
+                field.offset, field.size, field.signed as u8
+            ));
+        }
+        let post_str = (self.trace_print_func)();
+        if let Some(pos) = post_str.find("print fmt:") {
+            // Tools expect the print format on a single line
+            fmt.push('\n');
+            fmt.push_str(&post_str[pos..].replace('\n', " "));
+            fmt.push('\n');
+        }
+        fmt
+    }
+
+    /// Returns a hash of the tracepoint layout for cross-build compatibility checks.
+    ///
+    /// The hash is derived from the system, the name and the ordered `(name, type, offset, size)`
+    /// of every field, so a tool built against a different layout of the event can detect the
+    /// mismatch. It is stable across builds and targets (64-bit FNV-1a).
+    pub fn compat_hash(&self) -> u64 {
+        let mut hash = fnv_feed(FNV_OFFSET, self.system.as_bytes());
+        hash = fnv_feed(hash, self.name.as_bytes());
+        for field in self.fields {
+            hash = fnv_feed(hash, field.name.as_bytes());
+            hash = fnv_feed(hash, field.ty.as_bytes());
+            hash = fnv_feed(hash, &(field.offset as u64).to_le_bytes());
+            hash = fnv_feed(hash, &(field.size as u64).to_le_bytes());
+        }
+        hash
+    }
+
+    /// Returns an ID derived from the system and name only, stable across builds.
+    ///
+    /// It is the 64-bit FNV-1a hash of the system and the name, folded to the 16 bits of
+    /// [`TraceEntry::common_type`], see [`crate::global_init_events_stable_ids`]. Distinct
+    /// tracepoints may share an ID, which becomes likely past a few hundred tracepoints.
+    pub fn stable_id(&self) -> u16 {
+        let hash = fnv_feed(
+            fnv_feed(FNV_OFFSET, self.system.as_bytes()),
+            self.name.as_bytes(),
+        );
+        (hash ^ hash >> 16 ^ hash >> 32 ^ hash >> 48) as u16
+    }
+
+    /// Set the maximum number of callbacks of each kind, i.e. default callbacks, event
+    /// callbacks and raw event callbacks, that may be registered. Zero means no cap.
+    ///
+    /// Registering past the cap fails with [`TraceError::CallbackLimit`] for the
+    /// `try_register_*` functions and drops the callback for the others. Callbacks that are
+    /// already registered are kept even if they exceed a lowered cap.
+    pub fn set_max_callbacks(&self, max: usize) {
+        self.max_callbacks
+            .store(max, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the maximum number of callbacks of each kind, zero meaning no cap.
+    pub fn max_callbacks(&self) -> usize {
+        self.max_callbacks
+            .load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Check that one more callback fits next to `count` registered ones.
+    fn check_callback_cap(&self, count: usize) -> Result<(), TraceError> {
+        let max = self.max_callbacks();
+        if max != 0 && count >= max {
+            return Err(TraceError::CallbackLimit { max });
+        }
+        Ok(())
+    }
+
+    /// Returns the number of registered default callbacks.
+    pub fn default_callback_count(&self) -> usize {
+        self.default_callbacks.lock().len()
+    }
+
+    /// Returns the number of registered event callbacks.
+    pub fn event_callback_count(&self) -> usize {
+        self.event_callbacks.lock().len()
+    }
+
+    /// Returns the number of registered raw event callbacks.
+    pub fn raw_event_callback_count(&self) -> usize {
+        self.raw_event_callbacks.lock().len()
     }
 
     /// Register a callback function to the tracepoint
+    ///
+    /// This is insert-only: registering a function again keeps the existing data, see
+    /// [`TracePoint::register_or_replace`]. The callback is dropped if it exceeds the cap,
+    /// see [`TracePoint::try_register`].
     pub fn register(&self, func: fn(), data: Box<dyn Any + Sync + Send>) {
-        let trace_point_func = TracePointFunc { func, data };
+        let _ = self.try_register(func, data);
+    }
+
+    /// Register a callback function to the tracepoint, failing if it exceeds the cap
+    ///
+    /// See [`TracePoint::set_max_callbacks`]. Registering a function again keeps the
+    /// existing registration and succeeds.
+    pub fn try_register(
+        &self,
+        func: fn(),
+        data: Box<dyn Any + Sync + Send>,
+    ) -> Result<(), TraceError> {
         let ptr = func as usize;
-        self.default_callbacks
-            .lock()
-            .entry(ptr)
-            .or_insert(trace_point_func);
+        let mut callbacks = self.default_callbacks.lock();
+        if callbacks.contains_key(&ptr) {
+            return Ok(());
+        }
+        self.check_callback_cap(callbacks.len())?;
+        callbacks.insert(ptr, TracePointFunc { func, data });
+        Ok(())
+    }
+
+    /// Register a callback function to the tracepoint, replacing the data of an existing
+    /// registration of the same function
+    ///
+    /// A new registration is dropped if it exceeds the cap, replacing one always succeeds.
+    pub fn register_or_replace(&self, func: fn(), data: Box<dyn Any + Sync + Send>) {
+        let ptr = func as usize;
+        let mut callbacks = self.default_callbacks.lock();
+        if !callbacks.contains_key(&ptr) && self.check_callback_cap(callbacks.len()).is_err() {
+            return;
+        }
+        let old = callbacks.insert(ptr, TracePointFunc { func, data });
+        // Drop the old data after the lock is released.
+        drop(callbacks);
+        drop(old);
     }
 
     /// Unregister a callback function from the tracepoint
@@ -212,40 +1676,213 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePoint<L, K> {
     /// Register a event callback function to the tracepoint
     ///
     /// This function will be called when default tracepoint fmt function is called.
+    /// The callback has priority 0, see [`TracePoint::register_event_callback_with_priority`].
+    /// This is insert-only: if a callback with the same ID exists, it is kept, see
+    /// [`TracePoint::register_event_callback_or_replace`]. It is dropped if it exceeds the
+    /// cap, see [`TracePoint::try_register_event_callback`].
     pub fn register_event_callback(
         &self,
         callback_id: usize,
         callback: Box<dyn TracePointCallBackFunc>,
     ) {
-        self.event_callbacks
-            .lock()
-            .entry(callback_id)
-            .or_insert(callback);
+        self.register_event_callback_with_priority(callback_id, 0, callback);
+    }
+
+    /// Register a field callback function to the tracepoint
+    ///
+    /// The callback is registered as an event callback with priority 0 that hands it a
+    /// [`FieldView`] of each entry, so it shares the IDs of the event callbacks and is
+    /// unregistered with [`TracePoint::unregister_event_callback`]. Like
+    /// [`TracePoint::register_event_callback`], this is insert-only.
+    pub fn register_field_callback(
+        &self,
+        callback_id: usize,
+        callback: Box<dyn TracePointFieldCallBackFunc>,
+    ) {
+        self.register_event_callback(
+            callback_id,
+            Box::new(FieldCallback {
+                fields: self.fields,
+                callback,
+            }),
+        );
+    }
+
+    /// Register a event callback function to the tracepoint, failing if it exceeds the cap
+    ///
+    /// See [`TracePoint::set_max_callbacks`]. If a callback with the same ID exists, it is
+    /// kept and this succeeds.
+    pub fn try_register_event_callback(
+        &self,
+        callback_id: usize,
+        callback: Box<dyn TracePointCallBackFunc>,
+    ) -> Result<(), TraceError> {
+        self.insert_event_callback(
+            callback_id,
+            0,
+            EventCallback {
+                callback,
+                filter: None,
+            },
+        )
+    }
+
+    /// Register a event callback function to the tracepoint, replacing any callback with
+    /// the same ID
+    ///
+    /// The new callback has priority 0 and no filter, whatever the replaced one had. A new
+    /// registration is dropped if it exceeds the cap, replacing one always succeeds.
+    pub fn register_event_callback_or_replace(
+        &self,
+        callback_id: usize,
+        callback: Box<dyn TracePointCallBackFunc>,
+    ) {
+        let mut callbacks = self.event_callbacks.lock();
+        let old = callbacks
+            .keys()
+            .find(|(_, id)| *id == callback_id)
+            .copied()
+            .and_then(|key| callbacks.remove(&key));
+        if old.is_none() && self.check_callback_cap(callbacks.len()).is_err() {
+            return;
+        }
+        callbacks.insert(
+            (Reverse(0), callback_id),
+            EventCallback {
+                callback,
+                filter: None,
+            },
+        );
+        drop(callbacks);
+        drop(old);
+    }
+
+    /// Register a event callback function with the given priority to the tracepoint
+    ///
+    /// Callbacks with a higher priority fire first, and callbacks with the same priority
+    /// fire in ascending order of their IDs. If a callback with the same ID exists, it is kept.
+    pub fn register_event_callback_with_priority(
+        &self,
+        callback_id: usize,
+        priority: i32,
+        callback: Box<dyn TracePointCallBackFunc>,
+    ) {
+        let _ = self.insert_event_callback(
+            callback_id,
+            priority,
+            EventCallback {
+                callback,
+                filter: None,
+            },
+        );
+    }
+
+    /// Register a event callback function that only fires for entries matching `filter`.
+    ///
+    /// The filter uses the same syntax as the filter file but is independent of the
+    /// tracepoint's filter, so several consumers can each watch their own subset of the
+    /// same tracepoint. It is evaluated against every entry before the callback is invoked,
+    /// which adds one filter evaluation per filtered callback to each hit. Callbacks
+    /// registered without a filter always fire. The callback has priority 0.
+    ///
+    /// Returns the compile error if the filter is invalid, see [`TracePoint::compile_filter`].
+    pub fn register_event_callback_filtered(
+        &self,
+        callback_id: usize,
+        filter: &str,
+        callback: Box<dyn TracePointCallBackFunc>,
+    ) -> Result<(), FilterError> {
+        let compiled = self.compile_filter(filter)?;
+        let _ = self.insert_event_callback(
+            callback_id,
+            0,
+            EventCallback {
+                callback,
+                filter: Some(compiled),
+            },
+        );
+        Ok(())
+    }
+
+    fn insert_event_callback(
+        &self,
+        callback_id: usize,
+        priority: i32,
+        callback: EventCallback,
+    ) -> Result<(), TraceError> {
+        let mut callbacks = self.event_callbacks.lock();
+        if callbacks.keys().any(|(_, id)| *id == callback_id) {
+            return Ok(());
+        }
+        self.check_callback_cap(callbacks.len())?;
+        callbacks.insert((Reverse(priority), callback_id), callback);
+        Ok(())
     }
 
     /// Unregister a event callback function from the tracepoint
     pub fn unregister_event_callback(&self, callback_id: usize) {
-        self.event_callbacks.lock().remove(&callback_id);
+        self.event_callbacks
+            .lock()
+            .retain(|(_, id), _| *id != callback_id);
     }
 
     /// Iterate over all registered event callback functions
+    ///
+    /// The callbacks are visited in firing order, regardless of their filters.
     pub fn event_callback_list(&self, f: &dyn Fn(&Box<dyn TracePointCallBackFunc>)) {
         let raw_callback = self.event_callbacks.lock();
         for callback in raw_callback.values() {
-            f(callback);
+            f(&callback.callback);
+        }
+    }
+
+    /// Invoke the event callbacks whose filter matches the given trace entry.
+    pub fn call_event_callbacks(&self, entry: &[u8]) {
+        let callbacks = self.event_callbacks.lock();
+        if callbacks.is_empty() {
+            return;
+        }
+        for callback in callbacks.values() {
+            if callback
+                .filter
+                .as_ref()
+                .is_some_and(|filter| !self.evaluate_filter(filter, entry))
+            {
+                continue;
+            }
+            callback.callback.call(entry);
         }
     }
 
     /// Register a raw event callback function to the tracepoint
+    ///
+    /// Like event callbacks, raw event callbacks only run while the event is enabled, see
+    /// [`TracePoint::enable_event`]. The callback is dropped if it exceeds the cap, see
+    /// [`TracePoint::try_register_raw_event_callback`].
     pub fn register_raw_event_callback(
         &self,
         callback_id: usize,
         callback: Box<dyn RawTracePointCallBackFunc>,
     ) {
-        self.raw_event_callbacks
-            .lock()
-            .entry(callback_id)
-            .or_insert(callback);
+        let _ = self.try_register_raw_event_callback(callback_id, callback);
+    }
+
+    /// Register a raw event callback function to the tracepoint, failing if it exceeds the cap
+    ///
+    /// See [`TracePoint::set_max_callbacks`]. If a callback with the same ID exists, it is
+    /// kept and this succeeds.
+    pub fn try_register_raw_event_callback(
+        &self,
+        callback_id: usize,
+        callback: Box<dyn RawTracePointCallBackFunc>,
+    ) -> Result<(), TraceError> {
+        let mut callbacks = self.raw_event_callbacks.lock();
+        if callbacks.contains_key(&callback_id) {
+            return Ok(());
+        }
+        self.check_callback_cap(callbacks.len())?;
+        callbacks.insert(callback_id, callback);
+        Ok(())
     }
 
     /// Unregister a raw event callback function from the tracepoint
@@ -261,40 +1898,545 @@ impl<L: RawMutex + 'static, K: KernelTraceOps + 'static> TracePoint<L, K> {
         }
     }
 
+    /// Unregister all default callbacks, returning how many were removed.
+    ///
+    /// The default print registered by [`crate::global_init_events`] is kept, so the
+    /// tracepoint still records to the trace pipe.
+    pub fn clear_default_callbacks(&self) -> usize {
+        let mut callbacks = self.default_callbacks.lock();
+        let (keep, removed): (BTreeMap<_, _>, BTreeMap<_, _>) = core::mem::take(&mut *callbacks)
+            .into_iter()
+            .partition(|(_, func)| func.data.is::<TracePointContext<L, K>>());
+        *callbacks = keep;
+        // Drop the callbacks' data after the lock is released.
+        drop(callbacks);
+        removed.len()
+    }
+
+    /// Unregister all event callbacks, returning how many were removed.
+    pub fn clear_event_callbacks(&self) -> usize {
+        let callbacks = core::mem::take(&mut *self.event_callbacks.lock());
+        callbacks.len()
+    }
+
+    /// Unregister all raw event callbacks, returning how many were removed.
+    pub fn clear_raw_event_callbacks(&self) -> usize {
+        let callbacks = core::mem::take(&mut *self.raw_event_callbacks.lock());
+        callbacks.len()
+    }
+
+    /// Unregister every callback of the tracepoint, returning how many were removed.
+    ///
+    /// This is meant for teardown, when the owner of the callbacks goes away.
+    pub fn clear_all_callbacks(&self) -> usize {
+        self.clear_default_callbacks()
+            + self.clear_event_callbacks()
+            + self.clear_raw_event_callbacks()
+    }
+
+    /// Set how the tracepoint checks whether it is enabled.
+    pub(crate) fn set_dispatch_mode(&self, mode: DispatchMode) {
+        let _gate = self.gate.lock();
+        let active = self.is_active();
+        // Close the gate of the previous mode before opening the new one.
+        if active {
+            self.set_gate(false);
+        }
+        self.atomic_dispatch.store(
+            mode != DispatchMode::StaticKey,
+            core::sync::atomic::Ordering::Relaxed,
+        );
+        if active {
+            self.set_gate(true);
+        }
+    }
+
+    /// Returns how the tracepoint checks whether it is enabled.
+    pub fn dispatch_mode(&self) -> DispatchMode {
+        #[cfg(any(test, feature = "atomic-dispatch"))]
+        if self
+            .atomic_dispatch
+            .load(core::sync::atomic::Ordering::Relaxed)
+        {
+            return DispatchMode::AtomicFlag;
+        }
+        DispatchMode::StaticKey
+    }
+
+    /// Open or close the gate of the dispatch mode, the static key or the flag.
+    ///
+    /// The caller holds `gate`.
+    fn set_gate(&self, open: bool) {
+        match self.dispatch_mode() {
+            DispatchMode::StaticKey if open => unsafe { self.key.enable() },
+            DispatchMode::StaticKey => unsafe { self.key.disable() },
+            #[cfg(any(test, feature = "atomic-dispatch"))]
+            DispatchMode::AtomicFlag => self
+                .armed
+                .store(open, core::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    /// Set one of the statuses and update the gate to match.
+    fn set_status(&self, status: &AtomicBool, enabled: bool) {
+        let _gate = self.gate.lock();
+        let was_active = self.is_active();
+        status.store(enabled, core::sync::atomic::Ordering::Release);
+        let active = self.is_active();
+        if active != was_active {
+            self.set_gate(active);
+        }
+    }
+
     /// Enable the tracepoint for the default print
+    ///
+    /// This pushes records to the trace pipe, independently of the event, see
+    /// [`TracePoint::enable_event`].
     pub fn enable_default(&self) {
-        unsafe {
-            self.key.enable();
-        }
+        self.set_status(&self.default_status, true);
     }
 
     /// Disable the tracepoint for the default print
     pub fn disable_default(&self) {
-        unsafe {
-            self.key.disable();
-        }
+        self.set_status(&self.default_status, false);
     }
 
     /// Check if the tracepoint is enabled for the default print
     pub fn default_is_enabled(&self) -> bool {
-        self.key.is_enabled()
+        self.default_status
+            .load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Check the flag replacing the static key in `DispatchMode::AtomicFlag`.
+    ///
+    /// It is set while the default print or the event is enabled, and never in
+    /// [`DispatchMode::StaticKey`]. This is called by the generated `trace_xxx` function
+    /// only if the static key is disabled. Without the `atomic-dispatch` feature, this is
+    /// a constant `false`, so the generated check folds into the static key branch alone.
+    #[inline(always)]
+    pub fn flag_is_armed(&self) -> bool {
+        if cfg!(any(test, feature = "atomic-dispatch")) {
+            self.armed.load(core::sync::atomic::Ordering::Relaxed)
+        } else {
+            false
+        }
     }
 
     /// Enable the tracepoint event for custom event handling
+    ///
+    /// This runs the event callbacks and raw event callbacks even while the default print
+    /// is disabled, so nothing is pushed to the trace pipe. Callbacks registered before the
+    /// event is enabled are seen by every emit that observes the event as enabled, including
+    /// emits on other CPUs.
     pub fn enable_event(&self) {
-        self.event_status
-            .store(true, core::sync::atomic::Ordering::Relaxed);
+        self.set_status(&self.event_status, true);
     }
 
     /// Disable the tracepoint event for custom event handling
     pub fn disable_event(&self) {
-        self.event_status
-            .store(false, core::sync::atomic::Ordering::Relaxed);
+        self.set_status(&self.event_status, false);
     }
 
     /// Check if the tracepoint event is enabled for custom event handling
     pub fn event_is_enabled(&self) -> bool {
         self.event_status
-            .load(core::sync::atomic::Ordering::Relaxed)
+            .load(core::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Enable both the default print and the event
+    pub fn enable_all(&self) {
+        self.enable_default();
+        self.enable_event();
+    }
+
+    /// Disable both the default print and the event
+    pub fn disable_all(&self) {
+        self.disable_default();
+        self.disable_event();
+    }
+
+    /// Check if the tracepoint does anything at all, i.e. the default print or the event
+    /// is enabled
+    pub fn is_active(&self) -> bool {
+        self.default_is_enabled() || self.event_is_enabled()
+    }
+
+    /// Returns whether the default print and the event are enabled.
+    pub fn enable_state(&self) -> EnableState {
+        match (self.default_is_enabled(), self.event_is_enabled()) {
+            (true, true) => EnableState::On,
+            (false, false) => EnableState::Off,
+            _ => EnableState::Partial,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::testing::{self, Kops, Lock};
+
+    mod events {
+        #![allow(dead_code)]
+        use crate::testing::{Kops, Lock};
+
+        crate::define_event_trace!(
+            point_sample,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(point_tests),
+            TP_PROTO(x: u32),
+            TP_STRUCT__entry {
+                a: u32,
+            },
+            TP_fast_assign {
+                a: x,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={}", __entry.a))
+        );
+    }
+
+    fn sample() -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("point_tests", "point_sample")
+            .unwrap()
+    }
+
+    #[test]
+    fn build_record_caps_entry_len() {
+        let fits = vec![0u8; u16::MAX as usize];
+        let record = sample().build_record(&fits, None);
+        let header = RecordHeader::from_bytes(&record).unwrap();
+        assert_eq!(header.entry_len, u16::MAX);
+        assert!(!header.is_truncated());
+
+        let long = vec![0u8; u16::MAX as usize + 10];
+        let record = sample().build_record(&long, None);
+        let header = RecordHeader::from_bytes(&record).unwrap();
+        assert_eq!(header.entry_len, u16::MAX);
+        assert!(header.is_truncated());
+        assert_eq!(record.len(), size_of::<RecordHeader>() + u16::MAX as usize);
+    }
+
+    #[test]
+    fn fields_describe_the_entry() {
+        let a = FieldDesc {
+            name: "a",
+            ty: "u32",
+            offset: size_of::<TraceEntry>(),
+            size: 4,
+            signed: false,
+            array_len: 0,
+            unit: None,
+        };
+        let tracepoint = sample();
+        assert_eq!(tracepoint.fields(), [a]);
+        assert_eq!(tracepoint.field("a"), Some(&a));
+        assert_eq!(
+            tracepoint.field("common_pid"),
+            Some(&crate::COMMON_FIELDS[3])
+        );
+        assert_eq!(tracepoint.field("b"), None);
+        let names = tracepoint.all_fields().map(|field| field.name);
+        assert!(names.eq([
+            "common_type",
+            "common_flags",
+            "common_preempt_count",
+            "common_pid",
+            "a"
+        ]));
+        assert_eq!(tracepoint.entry_size(), 12);
+    }
+
+    #[test]
+    fn shared_state_is_attached_once() {
+        let tracepoint = sample();
+        assert!(tracepoint.is_registered());
+        let other = Arc::new(TraceShared::<Lock>::new());
+        assert!(!tracepoint.set_shared(&other));
+        assert_eq!(Arc::strong_count(&other), 1);
+        let attached = tracepoint.shared().unwrap() as *const TraceShared<Lock>;
+        assert!(core::ptr::eq(attached, &*testing::manager().shared));
+    }
+
+    fn entry(flags: u8, preempt_count: u8) -> TraceEntry {
+        TraceEntry {
+            common_type: 1,
+            common_flags: flags,
+            common_preempt_count: preempt_count,
+            common_pid: 1,
+        }
+    }
+
+    #[test]
+    fn lat_fmt_decodes_flags() {
+        let cases = [
+            (0, 0, "....."),
+            (TRACE_FLAG_IRQS_OFF, 0, "d...."),
+            (TRACE_FLAG_IRQS_OFF | TRACE_FLAG_BH_OFF, 0, "D...."),
+            (TRACE_FLAG_BH_OFF, 0, "b...."),
+            (TRACE_FLAG_IRQS_NOSUPPORT, 0, "X...."),
+            (TRACE_FLAG_NEED_RESCHED, 0, ".n..."),
+            (
+                TRACE_FLAG_NEED_RESCHED | TRACE_FLAG_PREEMPT_RESCHED,
+                0,
+                ".N...",
+            ),
+            (TRACE_FLAG_PREEMPT_RESCHED, 0, ".p..."),
+            (TRACE_FLAG_HARDIRQ, 0, "..h.."),
+            (TRACE_FLAG_SOFTIRQ, 0, "..s.."),
+            (TRACE_FLAG_HARDIRQ | TRACE_FLAG_SOFTIRQ, 0, "..H.."),
+            (TRACE_FLAG_NMI, 0, "..z.."),
+            (TRACE_FLAG_NMI | TRACE_FLAG_HARDIRQ, 0, "..Z.."),
+            (0, 0x21, "...12"),
+            (0, 0x0f, "...f."),
+        ];
+        for (flags, preempt_count, expected) in cases {
+            assert_eq!(entry(flags, preempt_count).trace_print_lat_fmt(), expected);
+        }
+    }
+
+    #[test]
+    fn records_carry_irq_state() {
+        let _serial = testing::serial();
+        testing::set_irq_flags(TRACE_FLAG_IRQS_OFF | TRACE_FLAG_HARDIRQ);
+        sample().enable_default();
+        events::trace_point_sample(1);
+        sample().disable_default();
+        let records = testing::take_records(sample().id());
+        let header = RecordHeader::from_bytes(&records[0]).unwrap();
+        let common = unsafe {
+            core::ptr::read_unaligned(
+                records[0][header.entry_offset()..].as_ptr() as *const TraceEntry
+            )
+        };
+        assert_eq!(common.trace_print_lat_fmt(), "d.h..");
+    }
+
+    #[test]
+    fn flags_update_concurrently() {
+        extern crate std;
+
+        let _serial = testing::serial();
+        let tp = sample();
+        std::thread::scope(|scope| {
+            for flag in [TRACE_FLAG_HARDIRQ, TRACE_FLAG_SOFTIRQ] {
+                scope.spawn(move || {
+                    for _ in 0..10_000 {
+                        tp.set_flag(flag);
+                        assert!(tp.has_flag(flag));
+                        tp.clear_flag(flag);
+                        assert!(!tp.has_flag(flag));
+                    }
+                    tp.set_flag(flag);
+                });
+            }
+        });
+        assert!(tp.has_flag(TRACE_FLAG_HARDIRQ | TRACE_FLAG_SOFTIRQ));
+        tp.clear_flag(TRACE_FLAG_HARDIRQ | TRACE_FLAG_SOFTIRQ);
+        assert_eq!(tp.flags(), 0);
+    }
+
+    /// An event callback that logs its tag to a shared list on every call.
+    struct Tagged {
+        tag: u32,
+        log: Arc<Mutex<Lock, Vec<u32>>>,
+    }
+
+    impl TracePointCallBackFunc for Tagged {
+        fn call(&self, _entry: &[u8]) {
+            self.log.lock().push(self.tag);
+        }
+    }
+
+    #[test]
+    fn callbacks_fire_by_priority() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tagged = |tag| {
+            Box::new(Tagged {
+                tag,
+                log: log.clone(),
+            })
+        };
+        for (id, priority) in [(101, 0), (102, 10), (103, -5), (104, 10)] {
+            tracepoint.register_event_callback_with_priority(id, priority, tagged(id as u32));
+        }
+        // Kept, since the ID is taken
+        tracepoint.register_event_callback_with_priority(103, 20, tagged(0));
+        tracepoint.call_event_callbacks(&[0; 16]);
+        for id in 101..=104 {
+            tracepoint.unregister_event_callback(id);
+        }
+        assert_eq!(*log.lock(), [102, 104, 101, 103]);
+    }
+
+    #[test]
+    fn enable_state_combines_both_switches() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let state = || (tracepoint.enable_state(), tracepoint.is_active());
+        assert_eq!(state(), (EnableState::Off, false));
+        tracepoint.enable_event();
+        assert_eq!(state(), (EnableState::Partial, true));
+        tracepoint.enable_all();
+        assert_eq!(state(), (EnableState::On, true));
+        tracepoint.disable_event();
+        assert_eq!(state(), (EnableState::Partial, true));
+        tracepoint.disable_all();
+        assert_eq!(state(), (EnableState::Off, false));
+    }
+
+    /// A raw event callback that ignores its arguments.
+    struct Ignore;
+
+    impl RawTracePointCallBackFunc for Ignore {
+        fn call(&self, _args: &[u64]) {}
+    }
+
+    fn noop() {}
+
+    #[test]
+    fn clearing_keeps_the_default_print() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        tracepoint.register(noop, Box::new(0u32));
+        for tag in [301, 302] {
+            let callback = Box::new(Tagged {
+                tag,
+                log: log.clone(),
+            });
+            tracepoint.register_event_callback(tag as usize, callback);
+        }
+        tracepoint.register_raw_event_callback(303, Box::new(Ignore));
+        assert_eq!(tracepoint.default_callback_count(), 2);
+        assert_eq!(tracepoint.clear_all_callbacks(), 4);
+        assert_eq!(tracepoint.default_callback_count(), 1);
+        assert_eq!(tracepoint.event_callback_count(), 0);
+        assert_eq!(tracepoint.raw_event_callback_count(), 0);
+        assert_eq!(tracepoint.clear_all_callbacks(), 0);
+
+        tracepoint.enable_default();
+        tracepoint.enable_event();
+        events::trace_point_sample(1);
+        tracepoint.disable_event();
+        tracepoint.disable_default();
+        assert_eq!(testing::take_records(tracepoint.id()).len(), 1);
+        assert!(log.lock().is_empty());
+    }
+
+    fn other_noop() {}
+
+    #[test]
+    fn callback_cap_counts_each_kind() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tagged = |tag| {
+            Box::new(Tagged {
+                tag,
+                log: log.clone(),
+            })
+        };
+        tracepoint.set_max_callbacks(2);
+        let full = Err(TraceError::CallbackLimit { max: 2 });
+        // The default print takes one of the two default callbacks
+        assert_eq!(tracepoint.try_register(noop, Box::new(0u32)), Ok(()));
+        assert_eq!(tracepoint.try_register(other_noop, Box::new(0u32)), full);
+        assert_eq!(tracepoint.try_register(noop, Box::new(1u32)), Ok(()));
+        for id in [501, 502] {
+            assert_eq!(
+                tracepoint.try_register_event_callback(id, tagged(0)),
+                Ok(())
+            );
+        }
+        assert_eq!(tracepoint.try_register_event_callback(503, tagged(0)), full);
+        assert_eq!(
+            tracepoint.try_register_event_callback(501, tagged(0)),
+            Ok(())
+        );
+        tracepoint.register_event_callback_or_replace(503, tagged(0));
+        assert_eq!(tracepoint.event_callback_count(), 2);
+        for id in [601, 602] {
+            let registered = tracepoint.try_register_raw_event_callback(id, Box::new(Ignore));
+            assert_eq!(registered, Ok(()));
+        }
+        let registered = tracepoint.try_register_raw_event_callback(603, Box::new(Ignore));
+        assert_eq!(registered, full);
+
+        // Lowering the cap keeps what is registered
+        tracepoint.set_max_callbacks(1);
+        assert_eq!(tracepoint.event_callback_count(), 2);
+        tracepoint.set_max_callbacks(0);
+        assert_eq!(tracepoint.clear_all_callbacks(), 5);
+    }
+
+    #[test]
+    fn replacing_swaps_the_data() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let noop_data = || {
+            let data = Mutex::<Lock, _>::new(None);
+            tracepoint.callback_list(&|callback| {
+                if callback.func as usize == noop as fn() as usize {
+                    *data.lock() = callback.data.downcast_ref::<u32>().copied();
+                }
+            });
+            data.into_inner()
+        };
+        tracepoint.register(noop, Box::new(1u32));
+        tracepoint.register(noop, Box::new(2u32));
+        assert_eq!(noop_data(), Some(1));
+        tracepoint.register_or_replace(noop, Box::new(3u32));
+        assert_eq!(noop_data(), Some(3));
+        tracepoint.unregister(noop);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tagged = |tag| {
+            Box::new(Tagged {
+                tag,
+                log: log.clone(),
+            })
+        };
+        tracepoint.register_event_callback_with_priority(401, 5, tagged(1));
+        tracepoint.register_event_callback(401, tagged(2));
+        tracepoint.register_event_callback_or_replace(401, tagged(3));
+        tracepoint.call_event_callbacks(&[0; 16]);
+        assert_eq!(tracepoint.event_callback_count(), 1);
+        tracepoint.unregister_event_callback(401);
+        assert_eq!(*log.lock(), [3]);
+    }
+
+    /// A field callback that logs the fields it reads to a shared list on every call.
+    struct Fields {
+        log: Arc<Mutex<Lock, Vec<[Option<u64>; 3]>>>,
+    }
+
+    impl TracePointFieldCallBackFunc for Fields {
+        fn call(&self, view: &FieldView<'_>) {
+            let read = ["a", "common_pid", "missing"].map(|name| view.u64(name));
+            self.log.lock().push(read);
+        }
+    }
+
+    #[test]
+    fn field_callbacks_read_fields_by_name() {
+        let _serial = testing::serial();
+        let tracepoint = sample();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        tracepoint.register_field_callback(201, Box::new(Fields { log: log.clone() }));
+        testing::set_pid(3);
+        tracepoint.enable_event();
+        events::trace_point_sample(7);
+        tracepoint.unregister_event_callback(201);
+        events::trace_point_sample(8);
+        tracepoint.disable_event();
+        assert_eq!(*log.lock(), [[Some(7), Some(3), None]]);
     }
 }
@@ -1,5 +1,6 @@
 //! A trait to convert various types to u64 representation.
 //! This is useful for passing arguments to tracepoints in a uniform way.
+//! [`FromU64`] is the companion for reading the values back.
 //!
 
 /// A trait to convert various types to u64 representation.
@@ -25,6 +26,50 @@ impl_basic!(
     u8, u16, u32, u64, i8, i16, i32, i64, usize, isize, bool, char
 );
 
+// Floats are passed as their IEEE-754 bit pattern, so the value round-trips losslessly.
+impl AsU64 for f32 {
+    fn as_u64(self) -> u64 {
+        self.to_bits() as u64
+    }
+}
+
+impl AsU64 for f64 {
+    fn as_u64(self) -> u64 {
+        self.to_bits()
+    }
+}
+
+/// Small byte arrays are packed into the value in little-endian order,
+/// so `[0x01, 0x02]` becomes `0x0201`.
+///
+/// `N` must be at most 8. Larger arrays fail to compile rather than being truncated.
+impl<const N: usize> AsU64 for [u8; N] {
+    fn as_u64(self) -> u64 {
+        const { assert!(N <= 8, "byte arrays longer than 8 bytes don't fit in a u64") };
+        let mut raw = [0u8; 8];
+        raw[..N].copy_from_slice(&self);
+        u64::from_le_bytes(raw)
+    }
+}
+
+macro_rules! impl_non_zero {
+    ($($t:ty),+) => {
+        $(
+            impl AsU64 for $t {
+                fn as_u64(self) -> u64 {
+                    self.get().as_u64()
+                }
+            }
+        )+
+    };
+}
+
+impl_non_zero!(
+    core::num::NonZeroU32,
+    core::num::NonZeroU64,
+    core::num::NonZeroUsize
+);
+
 impl<T> AsU64 for &T {
     fn as_u64(self) -> u64 {
         self as *const T as u64
@@ -49,6 +94,16 @@ impl<T> AsU64 for *mut T {
     }
 }
 
+/// `None` is passed as zero and `Some(v)` as `v.as_u64()`.
+///
+/// `None` and `Some` of a value converting to zero, e.g. `Some(0)`, can't be told apart, so
+/// this is best suited to pointer-like types, where zero is the NULL pointer meaning absent.
+impl<T: AsU64> AsU64 for Option<T> {
+    fn as_u64(self) -> u64 {
+        self.map_or(0, AsU64::as_u64)
+    }
+}
+
 impl AsU64 for &str {
     fn as_u64(self) -> u64 {
         self.as_ptr() as u64
@@ -60,3 +115,139 @@ impl AsU64 for &[u8] {
         self.as_ptr() as u64
     }
 }
+
+/// A trait to convert a u64 representation produced by [`AsU64`] back to the original type.
+///
+/// Narrowing conversions, e.g. `usize` on 32-bit targets, truncate like `as` casts.
+/// In debug builds they assert that the value fits the target width.
+pub trait FromU64 {
+    /// Convert the u64 value back to the type.
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_from_u64_unsigned {
+    ($($t:ty),+) => {
+        $(
+            impl FromU64 for $t {
+                fn from_u64(value: u64) -> Self {
+                    debug_assert!(
+                        <$t>::try_from(value).is_ok(),
+                        "u64 value {value:#x} overflows {}",
+                        stringify!($t)
+                    );
+                    value as $t
+                }
+            }
+        )+
+    };
+}
+
+macro_rules! impl_from_u64_signed {
+    ($($t:ty),+) => {
+        $(
+            impl FromU64 for $t {
+                fn from_u64(value: u64) -> Self {
+                    // Signed values are sign-extended by `AsU64`
+                    debug_assert!(
+                        <$t>::try_from(value as i64).is_ok(),
+                        "u64 value {value:#x} overflows {}",
+                        stringify!($t)
+                    );
+                    value as $t
+                }
+            }
+        )+
+    };
+}
+
+impl_from_u64_unsigned!(u8, u16, u32, u64, usize);
+impl_from_u64_signed!(i8, i16, i32, i64, isize);
+
+impl FromU64 for bool {
+    fn from_u64(value: u64) -> Self {
+        value != 0
+    }
+}
+
+impl FromU64 for f32 {
+    fn from_u64(value: u64) -> Self {
+        f32::from_bits(u32::from_u64(value))
+    }
+}
+
+impl FromU64 for f64 {
+    fn from_u64(value: u64) -> Self {
+        f64::from_bits(value)
+    }
+}
+
+impl<T> FromU64 for *const T {
+    fn from_u64(value: u64) -> Self {
+        usize::from_u64(value) as *const T
+    }
+}
+
+impl<T> FromU64 for *mut T {
+    fn from_u64(value: u64) -> Self {
+        usize::from_u64(value) as *mut T
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floats_pass_their_bits() {
+        assert_eq!(3.5f64.as_u64(), f64::to_bits(3.5));
+        assert_eq!((-0.25f32).as_u64(), f32::to_bits(-0.25) as u64);
+        assert_eq!(f64::from_u64(3.5f64.as_u64()), 3.5);
+        assert_eq!(f32::from_u64((-0.25f32).as_u64()), -0.25);
+        assert!(f64::from_u64(f64::NAN.as_u64()).is_nan());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for value in [0, -1, i32::MIN, i32::MAX] {
+            assert_eq!(i32::from_u64(value.as_u64()), value);
+        }
+        for value in [0, 1, usize::MAX] {
+            assert_eq!(usize::from_u64(value.as_u64()), value);
+        }
+        let x = 5u32;
+        let ptr = &x as *const u32;
+        assert_eq!(<*const u32>::from_u64(ptr.as_u64()), ptr);
+        assert!(<*const u32>::from_u64(core::ptr::null::<u32>().as_u64()).is_null());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overflows i8")]
+    fn narrowing_overflow_is_caught_in_debug() {
+        i8::from_u64(200);
+    }
+
+    #[test]
+    fn byte_arrays_pack_little_endian() {
+        assert_eq!([0x01u8, 0x02].as_u64(), 0x0201);
+        assert_eq!([0u8; 0].as_u64(), 0);
+        assert_eq!([0xffu8; 8].as_u64(), u64::MAX);
+    }
+
+    #[test]
+    fn non_zero_forwards_its_value() {
+        assert_eq!(core::num::NonZeroU32::new(7).unwrap().as_u64(), 7);
+        assert_eq!(core::num::NonZeroU64::MAX.as_u64(), u64::MAX);
+        assert_eq!(core::num::NonZeroUsize::new(9).unwrap().as_u64(), 9);
+    }
+
+    #[test]
+    fn options_pass_none_as_zero() {
+        let x = 5u32;
+        assert_eq!(None::<&u32>.as_u64(), 0);
+        assert_eq!(Some(&x).as_u64(), &x as *const u32 as u64);
+        assert_eq!(Some(7u32).as_u64(), 7);
+        // The documented collision
+        assert_eq!(Some(0u32).as_u64(), None::<u32>.as_u64());
+    }
+}
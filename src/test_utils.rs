@@ -0,0 +1,164 @@
+//! Helpers to build raw trace records for tests, enabled by the `test-utils` feature.
+//!
+
+use alloc::vec::Vec;
+
+use crate::{
+    RECORD_FLAG_COMM, RECORD_FORMAT_VERSION, RECORD_MAGIC, RecordHeader, TASK_COMM_LEN, TraceEntry,
+};
+
+/// A builder for a raw trace record, laid out as pushed by a tracepoint.
+///
+/// Fields are appended in order and aligned to their size, matching the `#[repr(C)]`
+/// layout of the `TP_STRUCT__entry` structure.
+///
+/// # Example
+/// ```rust ignore
+/// let record = RecordBuilder::new(id).pid(1).cpu(0).timestamp(1000).field_u32(7).build();
+/// ```
+#[derive(Debug)]
+pub struct RecordBuilder {
+    header: RecordHeader,
+    comm: Option<[u8; TASK_COMM_LEN]>,
+    common: TraceEntry,
+    payload: Vec<u8>,
+    align: usize,
+}
+
+impl RecordBuilder {
+    /// Create a new builder for a record of the tracepoint with the given ID.
+    pub fn new(id: u32) -> Self {
+        Self {
+            header: RecordHeader {
+                timestamp: 0,
+                cpu: 0,
+                flags: 0,
+                entry_len: 0,
+                repeat: 0,
+                magic: RECORD_MAGIC,
+                version: RECORD_FORMAT_VERSION,
+            },
+            comm: None,
+            common: TraceEntry {
+                common_type: id as u16,
+                common_flags: 0,
+                common_preempt_count: 0,
+                common_pid: 0,
+            },
+            payload: Vec::new(),
+            align: align_of::<TraceEntry>(),
+        }
+    }
+
+    /// Set the PID of the record.
+    pub fn pid(mut self, pid: i32) -> Self {
+        self.common.common_pid = pid;
+        self
+    }
+
+    /// Capture the comm of the task into the record, truncated to [`TASK_COMM_LEN`] bytes.
+    pub fn comm(mut self, comm: &str) -> Self {
+        let mut buf = [0; TASK_COMM_LEN];
+        let len = comm.len().min(TASK_COMM_LEN);
+        buf[..len].copy_from_slice(&comm.as_bytes()[..len]);
+        self.comm = Some(buf);
+        self.header.flags |= RECORD_FLAG_COMM;
+        self
+    }
+
+    /// Set the CPU of the record.
+    pub fn cpu(mut self, cpu: u32) -> Self {
+        self.header.cpu = cpu;
+        self
+    }
+
+    /// Set the timestamp of the record in nanoseconds.
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.header.timestamp = timestamp;
+        self
+    }
+
+    /// Set the common flags of the record.
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.common.common_flags = flags;
+        self
+    }
+
+    /// Set the preemption count of the record.
+    pub fn preempt_count(mut self, preempt_count: u8) -> Self {
+        self.common.common_preempt_count = preempt_count;
+        self
+    }
+
+    /// Set how many identical records were coalesced into the record.
+    pub fn repeat(mut self, repeat: u32) -> Self {
+        self.header.repeat = repeat;
+        self
+    }
+
+    /// Append a `u8` field.
+    pub fn field_u8(self, value: u8) -> Self {
+        self.field_bytes(&value.to_ne_bytes(), 1)
+    }
+
+    /// Append a `u16` field.
+    pub fn field_u16(self, value: u16) -> Self {
+        self.field_bytes(&value.to_ne_bytes(), 2)
+    }
+
+    /// Append a `u32` field.
+    pub fn field_u32(self, value: u32) -> Self {
+        self.field_bytes(&value.to_ne_bytes(), 4)
+    }
+
+    /// Append a `u64` field.
+    pub fn field_u64(self, value: u64) -> Self {
+        self.field_bytes(&value.to_ne_bytes(), 8)
+    }
+
+    /// Append an `i32` field.
+    pub fn field_i32(self, value: i32) -> Self {
+        self.field_bytes(&value.to_ne_bytes(), 4)
+    }
+
+    /// Append an `i64` field.
+    pub fn field_i64(self, value: i64) -> Self {
+        self.field_bytes(&value.to_ne_bytes(), 8)
+    }
+
+    /// Append a field with the given raw bytes and alignment.
+    pub fn field_bytes(mut self, bytes: &[u8], align: usize) -> Self {
+        self.pad_to(align);
+        self.payload.extend_from_slice(bytes);
+        self.align = self.align.max(align);
+        self
+    }
+
+    /// Build the raw record.
+    pub fn build(mut self) -> Vec<u8> {
+        // Trailing padding of the repr(C) entry structure
+        self.pad_to(self.align);
+        let entry_len = size_of::<TraceEntry>() + self.payload.len();
+        self.header.entry_len = entry_len as u16;
+        let common = unsafe {
+            core::slice::from_raw_parts(
+                &self.common as *const TraceEntry as *const u8,
+                size_of::<TraceEntry>(),
+            )
+        };
+        let mut record = Vec::with_capacity(self.header.entry_offset() + entry_len);
+        record.extend_from_slice(self.header.as_bytes());
+        if let Some(comm) = self.comm.as_ref() {
+            record.extend_from_slice(comm);
+        }
+        record.extend_from_slice(common);
+        record.extend_from_slice(&self.payload);
+        record
+    }
+
+    fn pad_to(&mut self, align: usize) {
+        let offset = size_of::<TraceEntry>() + self.payload.len();
+        let padded = offset.next_multiple_of(align);
+        self.payload.resize(padded - size_of::<TraceEntry>(), 0);
+    }
+}
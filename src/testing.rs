@@ -0,0 +1,170 @@
+//! The kernel the unit tests run against.
+//!
+//! Tracepoints are collected from the `.tracepoint` linker section of the test binary, so
+//! every tracepoint defined by a test must use [`Lock`] and [`Kops`], and the manager can be
+//! initialized only once. [`manager`] initializes it on first use with
+//! [`DispatchMode::AtomicFlag`], since the test binary can't patch its own text.
+//!
+//! The manager, the trace pipe and the state read by [`Kops`] are shared by all tests, so
+//! a test that fires tracepoints or changes them takes [`serial`] first.
+
+extern crate std;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard, OnceLock};
+
+use crate::{
+    DispatchMode, KernelTraceOps, TraceCmdLineCache, TracePipeRaw, TracingEventsManager,
+    global_init_events_with_mode,
+};
+
+/// The lock type of the tracepoints of the tests.
+pub type Lock = spin::Mutex<()>;
+
+/// The trace pipe the tracepoints of the tests push to.
+pub static PIPE: spin::Mutex<TracePipeRaw> = spin::Mutex::new(TracePipeRaw::new(1024));
+
+/// The cmdline cache filled by [`Kops::trace_cmdline_push`].
+pub static CMDLINE: spin::Mutex<TraceCmdLineCache> = spin::Mutex::new(TraceCmdLineCache::new(64));
+
+static PID: AtomicU32 = AtomicU32::new(1);
+static CPU: AtomicU32 = AtomicU32::new(0);
+static NOW: AtomicU64 = AtomicU64::new(1_000);
+static IRQ_FLAGS: AtomicU8 = AtomicU8::new(0);
+
+/// The kernel operations of the tests.
+///
+/// The PID, the CPU and the IRQ state are set by the test, and the clock ticks by one nanosecond
+/// on every read so records are ordered. The other clocks follow it from [`GLOBAL_BASE`],
+/// [`MONO_BASE`] and [`COUNTER_BASE`], so a record tells which clock stamped it. The comm of
+/// PIDs from [`RESOLVED_PIDS`] up is resolved as `proc<pid>`.
+pub struct Kops;
+
+/// The first PID whose comm is resolved by [`Kops`].
+pub const RESOLVED_PIDS: u32 = 1000;
+
+/// The start of [`TraceClock::Global`](crate::TraceClock::Global).
+pub const GLOBAL_BASE: u64 = 1 << 40;
+/// The start of [`TraceClock::Mono`](crate::TraceClock::Mono).
+pub const MONO_BASE: u64 = 2 << 40;
+/// The start of [`TraceClock::Counter`](crate::TraceClock::Counter).
+pub const COUNTER_BASE: u64 = 3 << 40;
+
+impl KernelTraceOps for Kops {
+    fn time_now() -> u64 {
+        NOW.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn time_global() -> u64 {
+        GLOBAL_BASE + Self::time_now()
+    }
+
+    fn time_mono() -> u64 {
+        MONO_BASE + Self::time_now()
+    }
+
+    fn cycle_counter() -> u64 {
+        COUNTER_BASE + Self::time_now()
+    }
+
+    fn cpu_id() -> u32 {
+        CPU.load(Ordering::Relaxed)
+    }
+
+    fn current_pid() -> u32 {
+        PID.load(Ordering::Relaxed)
+    }
+
+    fn irq_flags() -> u8 {
+        IRQ_FLAGS.load(Ordering::Relaxed)
+    }
+
+    fn trace_pipe_push_raw_record(buf: &[u8]) {
+        let callback = PIPE.lock().push_event_deferred(buf.to_vec());
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    fn trace_pipe_write_raw_record(len: usize, write: &mut dyn FnMut(&mut [u8])) {
+        let mut pipe = PIPE.lock();
+        let Some(record) = pipe.reserve(len) else {
+            return;
+        };
+        write(record);
+        let callback = pipe.commit_deferred();
+        drop(pipe);
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    fn trace_cmdline_push(pid: u32) {
+        CMDLINE.lock().insert(pid, "test".to_string());
+    }
+
+    fn comm_for_pid(pid: u32) -> Option<String> {
+        (pid >= RESOLVED_PIDS).then(|| alloc::format!("proc{pid}"))
+    }
+
+    fn write_kernel_text(_addr: *mut core::ffi::c_void, _data: &[u8]) {
+        unreachable!("the tests dispatch with DispatchMode::AtomicFlag");
+    }
+}
+
+/// Set the PID of the current task.
+pub fn set_pid(pid: u32) {
+    PID.store(pid, Ordering::Relaxed);
+}
+
+/// Set the CPU the current task runs on.
+pub fn set_cpu(cpu: u32) {
+    CPU.store(cpu, Ordering::Relaxed);
+}
+
+/// Set the `TRACE_FLAG_*` bits reported for the current context.
+pub fn set_irq_flags(flags: u8) {
+    IRQ_FLAGS.store(flags, Ordering::Relaxed);
+}
+
+/// Returns the manager of the tracepoints of the tests, initializing it on first use.
+pub fn manager() -> &'static TracingEventsManager<Lock, Kops> {
+    static MANAGER: OnceLock<TracingEventsManager<Lock, Kops>> = OnceLock::new();
+    MANAGER.get_or_init(|| {
+        global_init_events_with_mode::<Lock, Kops>(4, DispatchMode::AtomicFlag)
+            .expect("the tracepoints of the tests are valid")
+    })
+}
+
+/// Serialize the tests using the shared state, and reset it to the defaults.
+///
+/// The trace pipe is emptied, the clock is the local one, and the current task is PID 1 on
+/// CPU 0 with no IRQ state.
+pub fn serial() -> StdMutexGuard<'static, ()> {
+    static SERIAL: StdMutex<()> = StdMutex::new(());
+    let guard = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let manager = manager();
+    manager.set_tracing_on(true);
+    manager.clear_pid_filter();
+    manager.set_cpumask(u64::MAX);
+    manager.set_clock(crate::TraceClock::Local);
+    PIPE.lock().clear();
+    set_pid(1);
+    set_cpu(0);
+    set_irq_flags(0);
+    guard
+}
+
+/// Drain the trace pipe, returning the records of the tracepoint with the given ID.
+pub fn take_records(id: u32) -> Vec<Vec<u8>> {
+    let mut pipe = PIPE.lock();
+    core::iter::from_fn(|| pipe.read_consuming())
+        .filter(|record| crate::trace_pipe::record_event_id(record) == Some(id))
+        .collect()
+}
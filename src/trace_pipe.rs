@@ -1,8 +1,24 @@
-use alloc::{format, string::String, vec::Vec};
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::BTreeSet,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Write,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use lock_api::RawMutex;
+use lock_api::{Mutex, RawMutex};
 
-use crate::{KernelTraceOps, TraceEntry, TracePointMap};
+use crate::{
+    FieldValue, KernelTraceOps, ParseError, RECORD_FORMAT_VERSION, RECORD_MAGIC, RecordHeader,
+    TraceEntry, TracePoint, TracePointMap,
+};
 
 /// A trait defining operations for a trace pipe buffer.
 pub trait TracePipeOps {
@@ -14,21 +30,152 @@ pub trait TracePipeOps {
 
     /// Whether the trace pipe buffer is empty.
     fn is_empty(&self) -> bool;
+
+    /// Freeze the current events into a snapshot and continue with an empty buffer.
+    ///
+    /// Returns `None` if the buffer doesn't support snapshots, which is the default.
+    fn take_snapshot(&mut self) -> Option<TracePipeSnapshot> {
+        None
+    }
+}
+
+/// A trace pipe buffer that events can be pushed to, like the sinks of the
+/// [`crate::TracingEventsManager`].
+///
+/// A buffer that is only read implements just [`TracePipeOps`].
+pub trait TracePipeSink: TracePipeOps {
+    /// Push a new event into the trace pipe buffer.
+    fn push(&mut self, event: Vec<u8>);
+
+    /// Push a new event into the trace pipe buffer if it has room for it.
+    ///
+    /// Returns the event back if the buffer is full. By default, this always pushes.
+    fn try_push(&mut self, event: Vec<u8>) -> Result<(), Vec<u8>> {
+        self.push(event);
+        Ok(())
+    }
+}
+
+/// What [`TracePipeRaw::push_event`] does when the buffer is full.
+///
+/// This mirrors the `overwrite` option of ftrace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RingMode {
+    /// Evict the oldest event to make room for the new one.
+    #[default]
+    Overwrite,
+    /// Drop the new event and count it as dropped.
+    Discard,
 }
 
 /// A raw trace pipe buffer that stores trace events as byte vectors.
 pub struct TracePipeRaw {
     max_record: usize,
+    mode: RingMode,
     event_buf: Vec<Vec<u8>>,
+    spare: Vec<Vec<u8>>,
+    written: usize,
+    overrun: usize,
+    dropped: usize,
+    coalesce: bool,
+    watermark: f32,
+    on_watermark: Option<fn()>,
+    max_event_size: usize,
+    oversized: usize,
+    reserved: Option<Vec<u8>>,
+    // The sequence number of the oldest event, counting every event ever removed from the
+    // front, so a TracePipeCursor stays valid while events are consumed or overwritten.
+    head: u64,
 }
 
 impl TracePipeRaw {
     /// Create a new TracePipeRaw with the specified maximum number of records.
+    ///
+    /// The oldest events are overwritten when the buffer is full, see [`RingMode::Overwrite`].
     pub const fn new(max_record: usize) -> Self {
+        Self::with_mode(max_record, RingMode::Overwrite)
+    }
+
+    /// Create a new TracePipeRaw with the specified maximum number of records and ring mode.
+    pub const fn with_mode(max_record: usize, mode: RingMode) -> Self {
         Self {
             max_record,
+            mode,
             event_buf: Vec::new(),
+            spare: Vec::new(),
+            written: 0,
+            overrun: 0,
+            dropped: 0,
+            coalesce: false,
+            watermark: 0.0,
+            on_watermark: None,
+            max_event_size: 0,
+            oversized: 0,
+            reserved: None,
+            head: 0,
+        }
+    }
+
+    /// Returns a builder to configure a new TracePipeRaw.
+    pub const fn builder() -> TracePipeRawBuilder {
+        TracePipeRawBuilder::new()
+    }
+
+    /// Set the maximum size of a single event in bytes, zero for no limit.
+    ///
+    /// Larger events are rejected by [`TracePipeRaw::push_event`] and
+    /// [`TracePipeSink::try_push`] and counted by [`TracePipeRaw::oversized_count`], so a
+    /// single tracepoint with huge records can't take over the buffer.
+    pub fn set_max_event_size(&mut self, max_event_size: usize) {
+        self.max_event_size = max_event_size;
+    }
+
+    /// Returns the maximum size of a single event in bytes, zero for no limit.
+    pub fn max_event_size(&self) -> usize {
+        self.max_event_size
+    }
+
+    /// Check if the event exceeds the maximum event size, counting it as oversized if so.
+    fn reject_oversized(&mut self, event: &[u8]) -> bool {
+        if self.max_event_size == 0 || event.len() <= self.max_event_size {
+            return false;
+        }
+        self.oversized += 1;
+        true
+    }
+
+    /// Set whether a pushed record identical to the newest one is coalesced into it.
+    ///
+    /// Records are coalesced only if they are byte-identical except for the timestamp and
+    /// the repeat count of their [`RecordHeader`], regardless of the tracepoint that emitted
+    /// them. The newest record then takes the timestamp of the pushed record and its
+    /// [`RecordHeader::repeat`] is incremented, instead of storing the duplicate.
+    pub fn set_coalesce(&mut self, coalesce: bool) {
+        self.coalesce = coalesce;
+    }
+
+    /// Try to coalesce the record into the newest one, handing it back on a mismatch.
+    fn coalesce(&mut self, event: Vec<u8>) -> Result<(), Vec<u8>> {
+        let Some(last) = self.event_buf.last_mut() else {
+            return Err(event);
+        };
+        if !self.coalesce || !RecordHeader::is_repeat_of(last, &event) {
+            return Err(event);
         }
+        let (Some(mut header), Some(pushed)) = (
+            RecordHeader::from_bytes(last),
+            RecordHeader::from_bytes(&event),
+        ) else {
+            return Err(event);
+        };
+        header.timestamp = pushed.timestamp;
+        header.repeat = header
+            .repeat
+            .saturating_add(pushed.repeat)
+            .saturating_add(1);
+        last[..size_of::<RecordHeader>()].copy_from_slice(header.as_bytes());
+        self.written += 1;
+        Ok(())
     }
 
     /// Set the maximum number of records to keep in the trace pipe buffer.
@@ -36,17 +183,195 @@ impl TracePipeRaw {
     /// If the current number of records exceeds this limit, the oldest records will be removed.
     pub fn set_max_record(&mut self, max_record: usize) {
         self.max_record = max_record;
-        if self.event_buf.len() > max_record {
-            self.event_buf.truncate(max_record); // Keep only the latest records
+        while self.event_buf.len() > max_record {
+            self.remove_oldest();
+            self.overrun += 1;
+        }
+    }
+
+    /// Returns the ring mode of the trace pipe buffer.
+    pub fn mode(&self) -> RingMode {
+        self.mode
+    }
+
+    /// Set what happens to new events when the trace pipe buffer is full.
+    pub fn set_mode(&mut self, mode: RingMode) {
+        self.mode = mode;
+    }
+
+    /// Set the fill level, as a fraction of the maximum number of records, at which the
+    /// watermark callback is invoked.
+    ///
+    /// The fraction is clamped to `0.0..=1.0`, and `0.0` disables the watermark.
+    pub fn set_watermark(&mut self, fraction: f32) {
+        self.watermark = fraction.clamp(0.0, 1.0);
+    }
+
+    /// Set the callback invoked when the buffer fills up to the watermark.
+    ///
+    /// It is invoked once when a push brings the buffer up to the watermark, and again only
+    /// after the buffer was drained below it. This lets a consumer sleep until there is
+    /// enough to read instead of polling. The callback runs on the emit path and should only
+    /// wake the consumer. [`TracePipeRaw::push_event`] and [`TracePipeRaw::commit`] invoke it
+    /// right away, so within whatever lock guards the buffer. To invoke it after the lock is
+    /// released, push with [`TracePipeRaw::push_event_deferred`] or commit with
+    /// [`TracePipeRaw::commit_deferred`], as [`PerCpuTracePipe::push_event`] does.
+    pub fn set_watermark_callback(&mut self, callback: Option<fn()>) {
+        self.on_watermark = callback;
+    }
+
+    /// Returns the number of records at which the watermark is reached, zero if disabled.
+    fn watermark_len(&self) -> usize {
+        if self.watermark == 0.0 {
+            return 0;
+        }
+        ((self.max_record as f32 * self.watermark) as usize).max(1)
+    }
+
+    fn below_watermark(&self) -> bool {
+        self.event_buf.len() < self.watermark_len()
+    }
+
+    /// Returns the callback to invoke if an event just brought the buffer up to the
+    /// watermark, given whether it was below before.
+    fn reached_watermark(&self, was_below: bool) -> Option<fn()> {
+        if was_below && !self.below_watermark() {
+            self.on_watermark
+        } else {
+            None
         }
     }
 
     /// Push a new event into the trace pipe buffer.
+    ///
+    /// If the buffer is full, the oldest event is overwritten or the new event is dropped,
+    /// depending on the [`RingMode`].
     pub fn push_event(&mut self, event: Vec<u8>) {
+        if let Some(callback) = self.push_event_deferred(event) {
+            callback();
+        }
+    }
+
+    /// Push a new event, returning the watermark callback for the caller to invoke.
+    ///
+    /// This is [`TracePipeRaw::push_event`] for a buffer behind a lock: invoke the returned
+    /// callback once the lock is released, so it never runs under it.
+    pub fn push_event_deferred(&mut self, event: Vec<u8>) -> Option<fn()> {
+        if self.reject_oversized(&event) {
+            return None;
+        }
+        let Err(event) = self.coalesce(event) else {
+            return None;
+        };
+        let was_below = self.below_watermark();
         if self.event_buf.len() >= self.max_record {
-            self.event_buf.remove(0); // Remove the oldest record
+            // A buffer without room has no oldest event to overwrite either
+            if self.mode == RingMode::Discard || self.max_record == 0 {
+                self.dropped += 1;
+                return None;
+            }
+            self.remove_oldest();
+            self.overrun += 1;
         }
         self.event_buf.push(event);
+        self.written += 1;
+        self.reached_watermark(was_below)
+    }
+
+    /// Reserve room for an event of `len` bytes, to be written in place.
+    ///
+    /// The returned slice is zeroed and becomes the stored event when
+    /// [`TracePipeRaw::commit`] is called, so the entry can be assigned directly into it
+    /// instead of being built elsewhere and copied by [`TracePipeRaw::push_event`]. The
+    /// reservation must be committed or [aborted](TracePipeRaw::abort) before the next one.
+    ///
+    /// Nothing is evicted until the reservation is committed, so in [`RingMode::Overwrite`]
+    /// aborting it on a full buffer keeps the oldest event.
+    ///
+    /// Returns `None` if there is already a pending reservation, if `len` exceeds the
+    /// maximum event size, or if the buffer is full in [`RingMode::Discard`]. Like a push,
+    /// an event over the maximum size is counted as oversized, see
+    /// [`TracePipeRaw::oversized_count`], and one that doesn't fit is counted as dropped, see
+    /// [`TracePipeRaw::dropped_count`].
+    pub fn reserve(&mut self, len: usize) -> Option<&mut [u8]> {
+        if self.reserved.is_some() {
+            return None;
+        }
+        if self.max_event_size != 0 && len > self.max_event_size {
+            self.oversized += 1;
+            return None;
+        }
+        if self.mode == RingMode::Discard && self.event_buf.len() >= self.max_record {
+            self.dropped += 1;
+            return None;
+        }
+        Some(self.reserved.insert(vec![0; len]).as_mut_slice())
+    }
+
+    /// Commit the pending reservation as a new event.
+    ///
+    /// The event is then handled like one passed to [`TracePipeRaw::push_event`], for
+    /// coalescing, overwriting the oldest event and the watermark. Does nothing if there is no
+    /// pending reservation.
+    pub fn commit(&mut self) {
+        if let Some(callback) = self.commit_deferred() {
+            callback();
+        }
+    }
+
+    /// Commit the pending reservation, returning the watermark callback for the caller to
+    /// invoke, see [`TracePipeRaw::push_event_deferred`].
+    pub fn commit_deferred(&mut self) -> Option<fn()> {
+        let event = self.reserved.take()?;
+        self.push_event_deferred(event)
+    }
+
+    /// Abort the pending reservation, discarding whatever was written into it.
+    pub fn abort(&mut self) {
+        self.reserved = None;
+    }
+
+    /// Remove the oldest event, which must exist.
+    fn remove_oldest(&mut self) -> Vec<u8> {
+        self.head += 1;
+        self.event_buf.remove(0)
+    }
+
+    /// Read the oldest event and remove it from the buffer.
+    ///
+    /// This is the consuming read of ftrace's `trace_pipe`: each event is returned once, so
+    /// a daemon can drain the buffer continuously while events are pushed. Use
+    /// [`TracePipeRaw::read`] to read without consuming, like ftrace's `trace` file.
+    pub fn read_consuming(&mut self) -> Option<Vec<u8>> {
+        if self.event_buf.is_empty() {
+            None
+        } else {
+            Some(self.remove_oldest())
+        }
+    }
+
+    /// Returns a cursor positioned at the oldest event of the buffer.
+    pub fn cursor(&self) -> TracePipeCursor {
+        TracePipeCursor {
+            next: self.head,
+            missed: 0,
+        }
+    }
+
+    /// Read the event at the cursor without removing it, and advance the cursor.
+    ///
+    /// The cursor remembers its position across reads and pushes, so a reader sees each
+    /// event once while the buffer keeps them for other readers. Events removed before the
+    /// cursor reached them, by overwriting or by a consuming read, are skipped and counted
+    /// by [`TracePipeCursor::missed_count`]. Returns `None` once the cursor has caught up.
+    pub fn read(&self, cursor: &mut TracePipeCursor) -> Option<&[u8]> {
+        if cursor.next < self.head {
+            cursor.missed += self.head - cursor.next;
+            cursor.next = self.head;
+        }
+        let event = self.event_buf.get((cursor.next - self.head) as usize)?;
+        cursor.next += 1;
+        Some(event)
     }
 
     /// The number of events currently in the trace pipe buffer.
@@ -54,14 +379,80 @@ impl TracePipeRaw {
         self.event_buf.len()
     }
 
-    /// Clear the trace pipe buffer.
+    /// The number of events written to the trace pipe buffer since it was created or cleared.
+    pub fn entries_written(&self) -> usize {
+        self.written
+    }
+
+    /// The number of events overwritten by newer ones because the buffer was full.
+    pub fn overwritten_count(&self) -> usize {
+        self.overrun
+    }
+
+    /// The number of new events dropped because the buffer was full.
+    ///
+    /// This counts the events discarded in [`RingMode::Discard`] and those rejected by
+    /// [`TracePipeSink::try_push`].
+    pub fn dropped_count(&self) -> usize {
+        self.dropped
+    }
+
+    /// The number of events rejected because they exceed the maximum event size.
+    ///
+    /// See [`TracePipeRaw::set_max_event_size`].
+    pub fn oversized_count(&self) -> usize {
+        self.oversized
+    }
+
+    /// Clear the trace pipe buffer and reset its counters.
     pub fn clear(&mut self) {
+        self.head += self.event_buf.len() as u64;
         self.event_buf.clear();
+        self.written = 0;
+        self.overrun = 0;
+        self.dropped = 0;
+        self.oversized = 0;
     }
 
     /// Create a snapshot of the current state of the trace pipe buffer.
     pub fn snapshot(&self) -> TracePipeSnapshot {
-        TracePipeSnapshot::new(self.event_buf.clone())
+        let mut snapshot = TracePipeSnapshot::new(self.event_buf.clone());
+        snapshot.written = self.written;
+        snapshot.overrun = self.overrun;
+        snapshot.dropped = self.dropped;
+        snapshot.sort_chronological();
+        snapshot
+    }
+
+    /// Pre-allocate the spare buffer used by [`TracePipeRaw::swap_snapshot`].
+    ///
+    /// The spare gets room for the maximum number of records, so the next swap doesn't
+    /// allocate. This is like writing `1` to ftrace's `snapshot` file.
+    pub fn alloc_snapshot(&mut self) {
+        self.spare.reserve(self.max_record);
+    }
+
+    /// Swap the buffer with the spare buffer and return the old events as a snapshot.
+    ///
+    /// Only the buffers are exchanged, so the swap takes constant time and doesn't copy any
+    /// event. New events go to the fresh buffer, whose counters start at zero, while the
+    /// snapshot keeps the old events and counters. The spare is used up by the swap, so call
+    /// [`TracePipeRaw::alloc_snapshot`] again to keep the next swap from reallocating.
+    pub fn swap_snapshot(&mut self) -> TracePipeSnapshot {
+        self.head += self.event_buf.len() as u64;
+        let event_buf = core::mem::replace(&mut self.event_buf, core::mem::take(&mut self.spare));
+        let mut snapshot = TracePipeSnapshot {
+            event_buf,
+            written: self.written,
+            overrun: self.overrun,
+            dropped: self.dropped,
+        };
+        snapshot.sort_chronological();
+        self.written = 0;
+        self.overrun = 0;
+        self.dropped = 0;
+        self.oversized = 0;
+        snapshot
     }
 
     /// Get the maximum number of records allowed in the trace pipe buffer.
@@ -70,35 +461,434 @@ impl TracePipeRaw {
     }
 }
 
+#[cfg(any(test, feature = "test-utils"))]
+impl TracePipeRaw {
+    /// Inject a record as is, e.g. one built with [`crate::RecordBuilder`].
+    ///
+    /// Unlike [`TracePipeRaw::push_event`], the record is never coalesced, so a test gets
+    /// exactly the sequence it injected. The capacity, the [`RingMode`] and the counters apply
+    /// as usual.
+    pub fn inject(&mut self, record: Vec<u8>) {
+        if self.event_buf.len() >= self.max_record {
+            if self.mode == RingMode::Discard || self.max_record == 0 {
+                self.dropped += 1;
+                return;
+            }
+            self.remove_oldest();
+            self.overrun += 1;
+        }
+        self.event_buf.push(record);
+        self.written += 1;
+    }
+}
+
+/// A position in a [`TracePipeRaw`] for non-consuming reads, see [`TracePipeRaw::read`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TracePipeCursor {
+    next: u64,
+    missed: u64,
+}
+
+impl TracePipeCursor {
+    /// The number of events removed from the buffer before the cursor reached them.
+    pub fn missed_count(&self) -> u64 {
+        self.missed
+    }
+}
+
+/// A builder for a [`TracePipeRaw`].
+///
+/// # Example
+/// ```rust ignore
+/// let pipe = TracePipeRaw::builder()
+///     .capacity(1024)
+///     .max_event_size(256)
+///     .mode(RingMode::Discard)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct TracePipeRawBuilder {
+    capacity: usize,
+    max_event_size: usize,
+    mode: RingMode,
+}
+
+impl TracePipeRawBuilder {
+    /// Create a new builder for a pipe of 1024 records without size limit, overwriting the
+    /// oldest events when full.
+    pub const fn new() -> Self {
+        Self {
+            capacity: 1024,
+            max_event_size: 0,
+            mode: RingMode::Overwrite,
+        }
+    }
+
+    /// Set the maximum number of records, see [`TracePipeRaw::set_max_record`].
+    pub const fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set the maximum size of a single event, see [`TracePipeRaw::set_max_event_size`].
+    pub const fn max_event_size(mut self, max_event_size: usize) -> Self {
+        self.max_event_size = max_event_size;
+        self
+    }
+
+    /// Set what happens to new events when the buffer is full, see [`RingMode`].
+    pub const fn mode(mut self, mode: RingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Build the trace pipe buffer.
+    pub const fn build(self) -> TracePipeRaw {
+        let mut pipe = TracePipeRaw::with_mode(self.capacity, self.mode);
+        pipe.max_event_size = self.max_event_size;
+        pipe
+    }
+}
+
+impl Default for TracePipeRawBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TracePipeOps for TracePipeRaw {
     fn peek(&self) -> Option<&Vec<u8>> {
         self.event_buf.first()
     }
 
     fn pop(&mut self) -> Option<Vec<u8>> {
-        if self.event_buf.is_empty() {
-            None
-        } else {
-            Some(self.event_buf.remove(0))
-        }
+        self.read_consuming()
     }
 
     fn is_empty(&self) -> bool {
         self.event_buf.is_empty()
     }
+
+    fn take_snapshot(&mut self) -> Option<TracePipeSnapshot> {
+        Some(self.swap_snapshot())
+    }
+}
+
+impl TracePipeSink for TracePipeRaw {
+    fn push(&mut self, event: Vec<u8>) {
+        self.push_event(event);
+    }
+
+    fn try_push(&mut self, event: Vec<u8>) -> Result<(), Vec<u8>> {
+        if self.reject_oversized(&event) {
+            return Err(event);
+        }
+        let Err(event) = self.coalesce(event) else {
+            return Ok(());
+        };
+        if self.event_buf.len() >= self.max_record {
+            self.dropped += 1;
+            return Err(event);
+        }
+        let was_below = self.below_watermark();
+        self.event_buf.push(event);
+        self.written += 1;
+        if let Some(callback) = self.reached_watermark(was_below) {
+            callback();
+        }
+        Ok(())
+    }
+}
+
+/// A set of `N` independent trace pipe buffers, one per CPU.
+///
+/// Each record is pushed to the buffer of the CPU recorded in its [`RecordHeader`], so CPUs
+/// only take their own lock and never contend with each other on the emit path.
+/// [`PerCpuTracePipe::snapshot_merged`] merges the buffers back into a single time-ordered
+/// snapshot.
+pub struct PerCpuTracePipe<L: RawMutex, const N: usize> {
+    pipes: [Mutex<L, TracePipeRaw>; N],
+}
+
+impl<L: RawMutex, const N: usize> PerCpuTracePipe<L, N> {
+    /// Create `N` trace pipe buffers with the specified maximum number of records each.
+    ///
+    /// `N` must not be zero.
+    pub const fn new(max_record: usize) -> Self {
+        assert!(N > 0, "a per-CPU trace pipe needs at least one buffer");
+        let mut pipes = [const { MaybeUninit::<Mutex<L, TracePipeRaw>>::uninit() }; N];
+        let mut cpu = 0;
+        while cpu < N {
+            pipes[cpu] = MaybeUninit::new(Mutex::new(TracePipeRaw::new(max_record)));
+            cpu += 1;
+        }
+        // SAFETY: every buffer was initialized above
+        Self {
+            pipes: unsafe { core::ptr::read(pipes.as_ptr() as *const [Mutex<L, TracePipeRaw>; N]) },
+        }
+    }
+
+    /// Returns the trace pipe buffer of the given CPU.
+    pub fn cpu_pipe(&self, cpu: usize) -> Option<&Mutex<L, TracePipeRaw>> {
+        self.pipes.get(cpu)
+    }
+
+    /// Push a new event into the trace pipe buffer of the CPU that recorded it.
+    ///
+    /// CPU IDs past the number of buffers wrap around. The watermark callback of the
+    /// buffer is invoked after its lock is released.
+    pub fn push_event(&self, event: Vec<u8>) {
+        let cpu = RecordHeader::from_bytes(&event).map_or(0, |header| header.cpu as usize);
+        let callback = self.pipes[cpu % N].lock().push_event_deferred(event);
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    /// Clear every trace pipe buffer and reset its counters.
+    pub fn clear(&self) {
+        for pipe in &self.pipes {
+            pipe.lock().clear();
+        }
+    }
+
+    /// Create a snapshot of all trace pipe buffers, merged by the record timestamps.
+    ///
+    /// Records with the same timestamp are ordered by CPU. The counters of the snapshot are
+    /// the sums of the counters of the buffers.
+    pub fn snapshot_merged(&self) -> TracePipeSnapshot {
+        let snapshots = self
+            .pipes
+            .iter()
+            .map(|pipe| pipe.lock().snapshot())
+            .collect::<Vec<_>>();
+        let total = snapshots.iter().map(|s| s.event_buf.len()).sum();
+        let mut heads = [0; N];
+        let mut event_buf = Vec::with_capacity(total);
+        while event_buf.len() < total {
+            let (cpu, _) = snapshots
+                .iter()
+                .enumerate()
+                .filter_map(|(cpu, s)| {
+                    let record = s.event_buf.get(heads[cpu])?;
+                    let timestamp = RecordHeader::from_bytes(record).map_or(0, |h| h.timestamp);
+                    Some((cpu, timestamp))
+                })
+                .min_by_key(|&(cpu, timestamp)| (timestamp, cpu))
+                .expect("a buffer has records left");
+            event_buf.push(snapshots[cpu].event_buf[heads[cpu]].clone());
+            heads[cpu] += 1;
+        }
+        let mut merged = TracePipeSnapshot::new(event_buf);
+        merged.written = snapshots.iter().map(|s| s.written).sum();
+        merged.overrun = snapshots.iter().map(|s| s.overrun).sum();
+        merged.dropped = snapshots.iter().map(|s| s.dropped).sum();
+        merged
+    }
+}
+
+/// The length of the header of [`TracePipeSnapshot::to_bytes`]: the magic number, the format
+/// version, a reserved word and four 64-bit counters.
+const SNAPSHOT_HEADER_LEN: usize = 8 + 4 * 8;
+
+/// Metadata about the events of a [`TracePipeSnapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SnapshotStats {
+    /// The number of events in the snapshot.
+    pub entries: usize,
+    /// The number of events written to the buffer, including those overwritten since.
+    pub written: usize,
+    /// The number of events overwritten by newer ones.
+    pub overrun: usize,
+    /// The number of new events dropped because the buffer was full.
+    pub dropped: usize,
+    /// The number of distinct CPUs that recorded the events.
+    pub cpus: usize,
+    /// The earliest timestamp of the events, if any.
+    pub earliest: Option<u64>,
+    /// The latest timestamp of the events, if any.
+    pub latest: Option<u64>,
 }
 
 /// A snapshot of the trace pipe buffer at a specific point in time.
+///
+/// Snapshots taken from a [`TracePipeRaw`] hold their events in timestamp order, even if
+/// they were pushed out of order, e.g. by several CPUs sharing the buffer. Events with the
+/// same timestamp keep the order in which they were pushed.
 #[derive(Debug)]
-pub struct TracePipeSnapshot(Vec<Vec<u8>>);
+pub struct TracePipeSnapshot {
+    event_buf: Vec<Vec<u8>>,
+    written: usize,
+    overrun: usize,
+    dropped: usize,
+}
 
 impl TracePipeSnapshot {
     /// Create a new TracePipeSnapshot with the given event buffer.
+    ///
+    /// Every event is counted as written, with nothing overrun or dropped.
     pub fn new(event_buf: Vec<Vec<u8>>) -> Self {
-        Self(event_buf)
+        Self {
+            written: event_buf.len(),
+            event_buf,
+            overrun: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Sort the events by the timestamp of their [`RecordHeader`], keeping the push order of
+    /// events with the same timestamp.
+    fn sort_chronological(&mut self) {
+        let timestamp =
+            |record: &Vec<u8>| RecordHeader::from_bytes(record).map_or(0, |h| h.timestamp);
+        if !self.event_buf.is_sorted_by_key(timestamp) {
+            self.event_buf.sort_by_key(timestamp);
+        }
+    }
+
+    /// Returns an iterator over the events, oldest first, without removing them.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.event_buf.iter().map(|event| event.as_slice())
+    }
+
+    /// Remove all events and return them as an iterator, oldest first.
+    ///
+    /// This is the iterator form of popping until the snapshot is empty. Events not
+    /// consumed by the iterator are removed anyway.
+    pub fn drain(&mut self) -> alloc::vec::Drain<'_, Vec<u8>> {
+        self.event_buf.drain(..)
+    }
+
+    /// The number of events overwritten in the trace pipe buffer when the snapshot was taken.
+    pub fn overwritten_count(&self) -> usize {
+        self.overrun
+    }
+
+    /// The number of events dropped by the trace pipe buffer when the snapshot was taken.
+    pub fn dropped_count(&self) -> usize {
+        self.dropped
+    }
+
+    /// Serialize the snapshot into a flat buffer, e.g. to parse it offline on another host.
+    ///
+    /// The buffer starts with a header of [`RECORD_MAGIC`], [`RECORD_FORMAT_VERSION`] and the
+    /// counters of the snapshot, followed by each event prefixed with its length. The header
+    /// and the lengths are little-endian, the events are stored as is, in the byte order of
+    /// the target that recorded them. Read it back with [`TracePipeSnapshot::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self
+            .event_buf
+            .iter()
+            .map(|event| 4 + event.len())
+            .sum::<usize>();
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_LEN + len);
+        bytes.extend_from_slice(&RECORD_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&RECORD_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        for value in [
+            self.written,
+            self.overrun,
+            self.dropped,
+            self.event_buf.len(),
+        ] {
+            bytes.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+        for event in &self.event_buf {
+            bytes.extend_from_slice(&(event.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(event);
+        }
+        bytes
+    }
+
+    /// Deserialize a snapshot written by [`TracePipeSnapshot::to_bytes`].
+    ///
+    /// Returns [`ParseError::BadMagic`] or [`ParseError::UnsupportedVersion`] if the buffer
+    /// wasn't written by this format version, and [`ParseError::TooShort`] if it ends in the
+    /// middle of the header or of an event.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let too_short = || ParseError::TooShort { len: bytes.len() };
+        let header = bytes.get(..SNAPSHOT_HEADER_LEN).ok_or_else(too_short)?;
+        let magic = u16::from_le_bytes([header[0], header[1]]);
+        if magic != RECORD_MAGIC {
+            return Err(ParseError::BadMagic { magic });
+        }
+        let version = u16::from_le_bytes([header[2], header[3]]);
+        if version != RECORD_FORMAT_VERSION {
+            return Err(ParseError::UnsupportedVersion { version });
+        }
+        let counter = |idx: usize| {
+            let start = 8 + idx * 8;
+            u64::from_le_bytes(header[start..start + 8].try_into().unwrap()) as usize
+        };
+        let mut event_buf = Vec::new();
+        let mut pos = SNAPSHOT_HEADER_LEN;
+        for _ in 0..counter(3) {
+            let len = bytes.get(pos..pos + 4).ok_or_else(too_short)?;
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            let event = bytes.get(pos + 4..pos + 4 + len).ok_or_else(too_short)?;
+            event_buf.push(event.to_vec());
+            pos += 4 + len;
+        }
+        Ok(Self {
+            event_buf,
+            written: counter(0),
+            overrun: counter(1),
+            dropped: counter(2),
+        })
+    }
+
+    /// Returns the metadata of the snapshot.
+    pub fn stats(&self) -> SnapshotStats {
+        let mut cpus = BTreeSet::new();
+        let mut stats = SnapshotStats {
+            entries: self.event_buf.len(),
+            written: self.written,
+            overrun: self.overrun,
+            dropped: self.dropped,
+            ..Default::default()
+        };
+        for header in self
+            .event_buf
+            .iter()
+            .filter_map(|r| RecordHeader::from_bytes(r))
+        {
+            cpus.insert(header.cpu);
+            stats.earliest = Some(
+                stats
+                    .earliest
+                    .map_or(header.timestamp, |t| t.min(header.timestamp)),
+            );
+            stats.latest = Some(
+                stats
+                    .latest
+                    .map_or(header.timestamp, |t| t.max(header.timestamp)),
+            );
+        }
+        stats.cpus = cpus.len();
+        stats
+    }
+
+    /// Create a new snapshot with only the records of the tracepoint with the given ID.
+    pub fn filter_by_id(&self, id: u32) -> TracePipeSnapshot {
+        self.filter_by_ids(&[id])
+    }
+
+    /// Create a new snapshot with only the records of the tracepoints with the given IDs.
+    pub fn filter_by_ids(&self, ids: &[u32]) -> TracePipeSnapshot {
+        let event_buf = self
+            .event_buf
+            .iter()
+            .filter(|record| record_event_id(record).is_some_and(|id| ids.contains(&id)))
+            .cloned()
+            .collect();
+        TracePipeSnapshot::new(event_buf)
     }
 
     /// The formatted string representation to be used as a header for the trace pipe output.
+    ///
+    /// Besides the kernel's `entries-in-buffer/entries-written` line, it reports the overrun
+    /// and dropped events and the timestamps of the oldest and newest records. `#P` is the
+    /// number of CPUs that recorded the events, see [`SnapshotStats::cpus`].
     pub fn default_fmt_str(&self) -> String {
         let show = "#
 #
@@ -111,86 +901,296 @@ impl TracePipeSnapshot {
 #           TASK-PID     CPU#  |||||  TIMESTAMP  FUNCTION
 #              | |         |   |||||     |         |
 ";
+        let snapshot_stats = self.stats();
+        let mut stats = format!("# overrun: {}\n", snapshot_stats.overrun);
+        let _ = writeln!(stats, "# dropped events: {}", snapshot_stats.dropped);
+        if let (Some(oldest), Some(newest)) = (snapshot_stats.earliest, snapshot_stats.latest) {
+            for (label, time) in [("oldest", oldest), ("newest", newest)] {
+                let secs = time / 1_000_000_000;
+                let usec_rem = time % 1_000_000_000 / 1000;
+                let _ = writeln!(stats, "# {label} event ts: {secs:5}.{usec_rem:06}");
+            }
+        }
         format!(
-            "# tracer: nop\n#\n# entries-in-buffer/entries-written: {}/{}   #P:32\n{}",
-            self.0.len(),
-            self.0.len(),
-            show
+            "# tracer: nop\n#\n# entries-in-buffer/entries-written: {}/{}   #P:{}\n{}{}",
+            snapshot_stats.entries, snapshot_stats.written, snapshot_stats.cpus, stats, show
         )
     }
 }
 
 impl TracePipeOps for TracePipeSnapshot {
     fn peek(&self) -> Option<&Vec<u8>> {
-        self.0.first()
+        self.event_buf.first()
     }
 
     fn pop(&mut self) -> Option<Vec<u8>> {
-        if self.0.is_empty() {
+        if self.event_buf.is_empty() {
             None
         } else {
-            Some(self.0.remove(0))
+            Some(self.event_buf.remove(0))
         }
     }
 
     fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.event_buf.is_empty()
     }
 }
 
-/// A cache for storing command line arguments for each trace point.
-///
-/// See <https://www.kernel.org/doc/Documentation/trace/ftrace.txt>
-pub struct TraceCmdLineCache {
-    cmdline: Vec<(u32, [u8; 16])>,
-    max_record: usize,
+impl TracePipeSink for TracePipeSnapshot {
+    fn push(&mut self, event: Vec<u8>) {
+        self.event_buf.push(event);
+    }
 }
 
-impl TraceCmdLineCache {
-    /// Create a new TraceCmdLineCache with the specified maximum number of records.
-    pub const fn new(max_record: usize) -> Self {
-        Self {
-            cmdline: Vec::new(),
-            max_record,
-        }
+/// Returns the entry of a raw record, with a truncated payload padded with zeros so the
+/// format function can read it.
+fn record_entry<'a>(record: &'a [u8], header: &RecordHeader) -> Cow<'a, [u8]> {
+    let entry = record.get(header.entry_offset()..).unwrap_or(&[]);
+    if header.is_truncated() {
+        let mut padded = entry.to_vec();
+        padded.resize(header.entry_len as usize, 0);
+        Cow::Owned(padded)
+    } else {
+        Cow::Borrowed(entry)
     }
+}
 
-    /// Insert a command line argument for a trace point.
-    ///
-    /// If the command line exceeds 16 bytes, it will be truncated.
-    /// If the cache exceeds the maximum record limit, the oldest entry will be removed.
-    pub fn insert(&mut self, id: u32, cmdline: String) {
-        if self.cmdline.len() >= self.max_record {
-            // Remove the oldest entry if we exceed the max record limit
-            self.cmdline.remove(0);
-        }
-        let mut cmdline_bytes = [0u8; 16];
-        if cmdline.len() > 16 {
-            // Truncate to fit the fixed size
-            cmdline_bytes.copy_from_slice(&cmdline.as_bytes()[..16]);
-        } else {
-            // Copy the command line bytes into the fixed size array
+/// A raw record that passed [`check_record`], with its entry ready to be decoded.
+struct CheckedRecord<'a, L: RawMutex + 'static, K: KernelTraceOps + 'static> {
+    header: RecordHeader,
+    entry: Cow<'a, [u8]>,
+    common: TraceEntry,
+    // `None` for a marker.
+    tracepoint: Option<&'static TracePoint<L, K>>,
+}
+
+/// Check that a raw record can be decoded without reading outside of it.
+///
+/// The header must be valid, the entry must hold the [`TraceEntry`] and, unless the record
+/// is a marker, belong to a known tracepoint and hold all of its fields.
+fn check_record<'a, L: RawMutex + 'static, K: KernelTraceOps + 'static>(
+    tracepoint_map: &TracePointMap<L, K>,
+    record: &'a [u8],
+) -> Result<CheckedRecord<'a, L, K>, ParseError> {
+    let header =
+        RecordHeader::from_bytes(record).ok_or(ParseError::TooShort { len: record.len() })?;
+    header.validate()?;
+    if record.len() < header.entry_offset() {
+        return Err(ParseError::TooShort { len: record.len() });
+    }
+    let entry = record_entry(record, &header);
+    if entry.len() < size_of::<TraceEntry>() {
+        return Err(ParseError::Truncated {
+            len: entry.len(),
+            expected: size_of::<TraceEntry>(),
+        });
+    }
+    let common = unsafe { core::ptr::read_unaligned(entry.as_ptr() as *const TraceEntry) };
+    if header.is_marker() {
+        return Ok(CheckedRecord {
+            header,
+            entry,
+            common,
+            tracepoint: None,
+        });
+    }
+    let id = common.common_type;
+    let tracepoint = tracepoint_map
+        .get(&(id as u32))
+        .ok_or(ParseError::UnknownId { id })?;
+    if entry.len() < tracepoint.entry_size() {
+        return Err(ParseError::Truncated {
+            len: entry.len(),
+            expected: tracepoint.entry_size(),
+        });
+    }
+    Ok(CheckedRecord {
+        header,
+        entry,
+        common,
+        tracepoint: Some(tracepoint),
+    })
+}
+
+/// Returns the comm of the task that generated a raw record.
+///
+/// The comm captured into the record is preferred over the cmdline cache.
+fn record_comm<'a>(
+    record: &'a [u8],
+    header: &RecordHeader,
+    cmdline_cache: &'a TraceCmdLineCache,
+    pid: i32,
+) -> &'a str {
+    header
+        .comm(record)
+        .filter(|comm| !comm.is_empty())
+        .or_else(|| cmdline_cache.get(pid as u32))
+        .unwrap_or("<...>")
+}
+
+/// Returns the signed difference between two timestamps, saturating at the range of `i64`.
+///
+/// Records from different CPUs may arrive slightly out of order, so `later` can be the
+/// smaller one, and the difference must not wrap around.
+fn ts_delta(later: u64, earlier: u64) -> i64 {
+    if later >= earlier {
+        i64::try_from(later - earlier).unwrap_or(i64::MAX)
+    } else {
+        i64::try_from(earlier - later).map_or(i64::MIN, |delta| -delta)
+    }
+}
+
+/// Append the string to the JSON output as a quoted and escaped JSON string.
+fn push_json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Append the value to the CSV output, quoted if it contains a separator or a quote.
+fn push_csv_field(out: &mut String, value: &str) {
+    if !value.contains([',', '"', '\n', '\r']) {
+        out.push_str(value);
+        return;
+    }
+    out.push('"');
+    out.push_str(&value.replace('"', "\"\""));
+    out.push('"');
+}
+
+/// The event name under which marker records are rendered, as in ftrace.
+const MARKER_EVENT: &str = "tracing_mark_write";
+
+/// The key of the header row of marker records in [`TraceEntryParser::parse_csv`].
+const MARKER_CSV_ID: u32 = u32::MAX;
+
+/// Read the tracepoint ID from the [`TraceEntry`] of a raw record.
+///
+/// Marker records have no tracepoint ID.
+pub(crate) fn record_event_id(record: &[u8]) -> Option<u32> {
+    let header = RecordHeader::from_bytes(record)?;
+    if header.is_marker() {
+        return None;
+    }
+    let offset = header.entry_offset();
+    let common_type = record.get(offset..offset + 2)?;
+    Some(u16::from_ne_bytes([common_type[0], common_type[1]]) as u32)
+}
+
+/// A cache for storing command line arguments for each trace point.
+///
+/// The cache holds at most [`TraceCmdLineCache::capacity`] entries and evicts the least
+/// recently used one to make room, where both [`TraceCmdLineCache::insert`] and
+/// [`TraceCmdLineCache::get`] count as a use.
+///
+/// See <https://www.kernel.org/doc/Documentation/trace/ftrace.txt>
+pub struct TraceCmdLineCache {
+    cmdline: Vec<(u32, [u8; 16])>,
+    // The `tick` of the last use of each entry, bumped by `get` through a shared reference.
+    // It only orders the entries for eviction, so `Relaxed` is enough.
+    last_used: Vec<AtomicU64>,
+    tick: AtomicU64,
+    max_record: usize,
+    evicted: usize,
+}
+
+impl TraceCmdLineCache {
+    /// Create a new TraceCmdLineCache with the specified maximum number of records.
+    pub const fn new(max_record: usize) -> Self {
+        Self {
+            cmdline: Vec::new(),
+            last_used: Vec::new(),
+            tick: AtomicU64::new(0),
+            max_record,
+            evicted: 0,
+        }
+    }
+
+    /// Returns the next tick, marking a use.
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Evict the least recently used entry.
+    fn evict(&mut self) {
+        let Some(idx) = (0..self.last_used.len())
+            .min_by_key(|idx| self.last_used[*idx].load(Ordering::Relaxed))
+        else {
+            return;
+        };
+        self.cmdline.remove(idx);
+        self.last_used.remove(idx);
+        self.evicted += 1;
+    }
+
+    /// Insert a command line argument for a trace point.
+    ///
+    /// If the command line exceeds 16 bytes, it will be truncated. An existing entry for the
+    /// ID is replaced. If the cache is full, the least recently used entry is evicted.
+    pub fn insert(&mut self, id: u32, cmdline: String) {
+        if let Some(idx) = self.cmdline.iter().position(|(key, _)| *key == id) {
+            self.cmdline.remove(idx);
+            self.last_used.remove(idx);
+        }
+        if self.max_record == 0 {
+            return;
+        }
+        while self.cmdline.len() >= self.max_record {
+            self.evict();
+        }
+        let mut cmdline_bytes = [0u8; 16];
+        if cmdline.len() > 16 {
+            // Truncate to fit the fixed size
+            cmdline_bytes.copy_from_slice(&cmdline.as_bytes()[..16]);
+        } else {
+            // Copy the command line bytes into the fixed size array
             cmdline_bytes[..cmdline.len()].copy_from_slice(cmdline.as_bytes());
         }
         self.cmdline.push((id, cmdline_bytes));
+        self.last_used.push(AtomicU64::new(self.next_tick()));
     }
 
     /// Get the command line argument for a trace point.
+    ///
+    /// A hit marks the entry as recently used.
     pub fn get(&self, id: u32) -> Option<&str> {
-        self.cmdline.iter().find_map(|(key, value)| {
-            if *key == id {
-                Some(core::str::from_utf8(value).unwrap().trim_end_matches('\0'))
-            } else {
-                None
-            }
-        })
+        let idx = self.cmdline.iter().position(|(key, _)| *key == id)?;
+        self.last_used[idx].store(self.next_tick(), Ordering::Relaxed);
+        let value = &self.cmdline[idx].1;
+        Some(core::str::from_utf8(value).unwrap().trim_end_matches('\0'))
+    }
+
+    /// Get the command line argument for a trace point, resolving a missing one with
+    /// [`KernelTraceOps::comm_for_pid`].
+    ///
+    /// A resolved command line is inserted, so later lookups hit.
+    pub fn get_or_resolve<K: KernelTraceOps>(&mut self, id: u32) -> Option<&str> {
+        if self.get(id).is_none() {
+            let cmdline = K::comm_for_pid(id)?;
+            self.insert(id, cmdline);
+        }
+        self.get(id)
     }
 
     /// Set the maximum length for command line arguments.
+    ///
+    /// If the cache holds more entries, the least recently used ones are evicted.
     pub fn set_max_record(&mut self, max_len: usize) {
         self.max_record = max_len;
-        if self.cmdline.len() > max_len {
-            self.cmdline.truncate(max_len); // Keep only the latest records
+        while self.cmdline.len() > max_len {
+            self.evict();
         }
     }
 
@@ -199,6 +1199,26 @@ impl TraceCmdLineCache {
         self.max_record
     }
 
+    /// Returns the maximum number of records in the cache, like [`TraceCmdLineCache::max_record`].
+    pub fn capacity(&self) -> usize {
+        self.max_record
+    }
+
+    /// Returns the number of records in the cache.
+    pub fn len(&self) -> usize {
+        self.cmdline.len()
+    }
+
+    /// Check if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.cmdline.is_empty()
+    }
+
+    /// Returns the number of records evicted to make room since the cache was created.
+    pub fn evicted_count(&self) -> usize {
+        self.evicted
+    }
+
     /// Create a snapshot of the current state of the command line cache.
     pub fn snapshot(&self) -> TraceCmdLineCacheSnapshot {
         TraceCmdLineCacheSnapshot::new(self.cmdline.clone())
@@ -229,45 +1249,1368 @@ impl TraceCmdLineCacheSnapshot {
     }
 }
 
+/// A transform applied in place to each line formatted by [`TraceEntryParser`].
+pub type LineTransform = Box<dyn Fn(&mut String) + Send + Sync>;
+
+/// The precision of the timestamps rendered by [`TraceEntryParser`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimePrecision {
+    /// Render `seconds.microseconds`, like ftrace.
+    #[default]
+    Micros,
+    /// Render `seconds.nanoseconds`, keeping the low digits of the clock.
+    Nanos,
+}
+
+/// A column of a line formatted by [`TraceEntryParser`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// The command name of the task that generated the event.
+    Comm,
+    /// The PID of the task that generated the event.
+    Pid,
+    /// The CPU on which the event was recorded.
+    Cpu,
+    /// The latency format, see [`TraceEntry::trace_print_lat_fmt`].
+    Latency,
+    /// The timestamp of the event.
+    Timestamp,
+    /// The name of the event.
+    Event,
+    /// The formatted fields of the event.
+    Fields,
+}
+
+impl Column {
+    /// The ftrace layout: `TASK-PID [CPU#] ||||| TIMESTAMP: FUNCTION(FIELDS)`.
+    pub const DEFAULT_LAYOUT: [Column; 7] = [
+        Column::Comm,
+        Column::Pid,
+        Column::Cpu,
+        Column::Latency,
+        Column::Timestamp,
+        Column::Event,
+        Column::Fields,
+    ];
+
+    /// The separator written between this column and the next one.
+    fn separator(self, next: Column) -> &'static str {
+        match (self, next) {
+            (Column::Comm, Column::Pid) => "-",
+            (Column::Event, Column::Fields) => "",
+            _ => " ",
+        }
+    }
+}
+
+/// Options controlling how [`TraceEntryParser`] renders records.
+pub struct ParseOptions {
+    transform: Option<LineTransform>,
+    columns: Vec<Column>,
+    decode_fields: bool,
+    hex_byte_arrays: bool,
+    relative_time: bool,
+    time_precision: TimePrecision,
+    // The timestamp of the previous record in relative mode, `u64::MAX` before the first.
+    // It is only a cursor over the lines, so relaxed ordering is enough.
+    last_timestamp: AtomicU64,
+}
+
+impl ParseOptions {
+    /// Create the default options, matching the output of [`TraceEntryParser::parse`].
+    pub fn new() -> Self {
+        Self {
+            transform: None,
+            columns: Vec::new(),
+            decode_fields: false,
+            hex_byte_arrays: false,
+            relative_time: false,
+            time_precision: TimePrecision::Micros,
+            last_timestamp: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    /// Set whether the timestamp column shows the time since the previous record.
+    ///
+    /// The delta is rendered as `+MICROS.NANOS:`, in microseconds, and the first record
+    /// after this call shows `+0.000`. A record older than the previous one, e.g. from
+    /// another CPU, shows a negative delta like `-1.500`. The options remember the timestamp of
+    /// each record they render, so use one set of options per stream of records.
+    pub fn set_relative_time(&mut self, relative_time: bool) {
+        self.relative_time = relative_time;
+        self.last_timestamp = AtomicU64::new(u64::MAX);
+    }
+
+    /// Set the precision of the timestamp column.
+    ///
+    /// [`TimePrecision::Nanos`] renders e.g. `1.000000250:` instead of `1.000000:`, so events
+    /// less than a microsecond apart can be told apart. Relative timestamps always carry
+    /// nanoseconds, see [`ParseOptions::set_relative_time`].
+    pub fn set_time_precision(&mut self, time_precision: TimePrecision) {
+        self.time_precision = time_precision;
+    }
+
+    /// Set whether the fields are decoded from the entry instead of using `TP_printk`.
+    ///
+    /// Decoded fields render as `name=value` with their unit applied, see
+    /// [`crate::FieldDesc::format`]. Fields without a unit render as bare numbers.
+    pub fn set_decode_fields(&mut self, decode_fields: bool) {
+        self.decode_fields = decode_fields;
+    }
+
+    /// Set whether decoded byte arrays render as a hex string, e.g. `0a0b0c0d`, instead of
+    /// a list of numbers.
+    ///
+    /// This only applies with [`ParseOptions::set_decode_fields`], to arrays of `u8` or `i8`.
+    pub fn set_hex_byte_arrays(&mut self, hex_byte_arrays: bool) {
+        self.hex_byte_arrays = hex_byte_arrays;
+    }
+
+    /// Set which columns are rendered and in what order.
+    ///
+    /// An empty layout selects [`Column::DEFAULT_LAYOUT`].
+    pub fn set_columns(&mut self, columns: Vec<Column>) {
+        self.columns = columns;
+    }
+
+    /// Set a transform applied to each formatted line before it is returned.
+    ///
+    /// This can be used to redact or rewrite the output, e.g. to mask addresses.
+    pub fn set_transform(&mut self, transform: LineTransform) {
+        self.transform = Some(transform);
+    }
+}
+
 /// A parser for trace entries that formats them into human-readable strings.
 pub struct TraceEntryParser;
 
 impl TraceEntryParser {
     /// Parse the trace entry and return a formatted string.
+    ///
+    /// The record is expected to start with a [`RecordHeader`] as pushed by the tracepoint.
+    /// A corrupt record yields an empty string, see [`TraceEntryParser::try_parse`].
     pub fn parse<K: KernelTraceOps, L: RawMutex + 'static>(
         tracepoint_map: &TracePointMap<L, K>,
         cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+    ) -> String {
+        Self::parse_with(tracepoint_map, cmdline_cache, record, &ParseOptions::new())
+    }
+
+    /// Parse the trace entry and return it as a JSON object.
+    ///
+    /// The object holds the `timestamp` in nanoseconds, the `cpu`, `pid` and `comm` of the
+    /// task, the `event` as `system:name` and the `fields` decoded with the field descriptors
+    /// of the tracepoint. Integer fields are JSON numbers, `bool` fields are JSON booleans,
+    /// `__string` fields are JSON strings, arrays are JSON arrays of their elements and any
+    /// other field is an array of its bytes.
+    /// `"truncated": true` is added to truncated records.
+    ///
+    /// A corrupt record yields an empty string, see [`TraceEntryParser::try_parse`].
+    pub fn parse_json<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint_map: &TracePointMap<L, K>,
+        cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+    ) -> String {
+        let Ok(checked) = check_record(tracepoint_map, record) else {
+            return String::new();
+        };
+        let header = checked.header;
+        let entry = &checked.entry[..];
+        let pid = checked.common.common_pid;
+
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"timestamp\":{},\"cpu\":{},\"pid\":{pid},\"comm\":",
+            header.timestamp, header.cpu
+        );
+        push_json_str(&mut json, record_comm(record, &header, cmdline_cache, pid));
+        json.push_str(",\"event\":");
+        if let Some(msg) = header.marker(record) {
+            push_json_str(&mut json, MARKER_EVENT);
+            json.push_str(",\"fields\":{\"buf\":");
+            push_json_str(&mut json, msg);
+            json.push_str("}}");
+            return json;
+        }
+        let Some(tracepoint) = checked.tracepoint else {
+            return String::new();
+        };
+        push_json_str(
+            &mut json,
+            &format!("{}:{}", tracepoint.system(), tracepoint.name()),
+        );
+        json.push_str(",\"fields\":{");
+        for (idx, field) in tracepoint.fields().iter().enumerate() {
+            if idx != 0 {
+                json.push(',');
+            }
+            push_json_str(&mut json, field.name);
+            json.push(':');
+            if let Some(value) = field.decode_str(entry) {
+                push_json_str(&mut json, value);
+                continue;
+            }
+            if let Some(values) = field.decode_array(entry) {
+                json.push('[');
+                for (idx, value) in values.iter().enumerate() {
+                    if idx != 0 {
+                        json.push(',');
+                    }
+                    let _ = write!(json, "{value}");
+                }
+                json.push(']');
+                continue;
+            }
+            match field.decode(entry) {
+                Some(value) if field.ty == "bool" => {
+                    let _ = write!(json, "{}", value != FieldValue::Unsigned(0));
+                }
+                Some(value) => {
+                    let _ = write!(json, "{value}");
+                }
+                None => {
+                    let bytes = entry
+                        .get(field.offset..field.offset + field.size)
+                        .unwrap_or(&[]);
+                    json.push('[');
+                    for (idx, byte) in bytes.iter().enumerate() {
+                        if idx != 0 {
+                            json.push(',');
+                        }
+                        let _ = write!(json, "{byte}");
+                    }
+                    json.push(']');
+                }
+            }
+        }
+        json.push('}');
+        if header.is_truncated() {
+            json.push_str(",\"truncated\":true");
+        }
+        json.push('}');
+        json
+    }
+
+    /// Parse the trace entry and return it as a line of comma-separated values.
+    ///
+    /// The columns are `timestamp,cpu,pid,comm,event` followed by the fields of the
+    /// tracepoint in schema order. The timestamp is in nanoseconds and the event is
+    /// `system:name`. Integer fields are plain numbers, `__string` fields are their string
+    /// and arrays are their elements separated by spaces. Values containing a comma, a quote
+    /// or a line break are quoted.
+    ///
+    /// If the ID of the tracepoint is not in `headers_written`, a header row naming the
+    /// columns is emitted first and the ID is added. Insert the IDs beforehand to suppress
+    /// the header rows. Markers have the single field `buf` and use the ID `u32::MAX` here.
+    ///
+    /// A corrupt record yields an empty string, see [`TraceEntryParser::try_parse`].
+    pub fn parse_csv<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint_map: &TracePointMap<L, K>,
+        cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+        headers_written: &mut BTreeSet<u32>,
+    ) -> String {
+        let Ok(checked) = check_record(tracepoint_map, record) else {
+            return String::new();
+        };
+        let header = checked.header;
+        let entry = &checked.entry[..];
+        let pid = checked.common.common_pid;
+
+        let mut csv = String::new();
+        if let Some(msg) = header.marker(record) {
+            if headers_written.insert(MARKER_CSV_ID) {
+                csv.push_str("timestamp,cpu,pid,comm,event,buf\n");
+            }
+            let _ = write!(csv, "{},{},{pid},", header.timestamp, header.cpu);
+            push_csv_field(&mut csv, record_comm(record, &header, cmdline_cache, pid));
+            csv.push(',');
+            push_csv_field(&mut csv, MARKER_EVENT);
+            csv.push(',');
+            push_csv_field(&mut csv, msg);
+            csv.push('\n');
+            return csv;
+        }
+        let Some(tracepoint) = checked.tracepoint else {
+            return String::new();
+        };
+        let id = tracepoint.id();
+        if headers_written.insert(id) {
+            csv.push_str("timestamp,cpu,pid,comm,event");
+            for field in tracepoint.fields() {
+                csv.push(',');
+                push_csv_field(&mut csv, field.name);
+            }
+            csv.push('\n');
+        }
+        let _ = write!(csv, "{},{},{pid},", header.timestamp, header.cpu);
+        push_csv_field(&mut csv, record_comm(record, &header, cmdline_cache, pid));
+        csv.push(',');
+        push_csv_field(
+            &mut csv,
+            &format!("{}:{}", tracepoint.system(), tracepoint.name()),
+        );
+        for field in tracepoint.fields() {
+            csv.push(',');
+            if let Some(value) = field.decode_str(entry) {
+                push_csv_field(&mut csv, value);
+            } else if let Some(values) = field.decode_array(entry) {
+                for (idx, value) in values.iter().enumerate() {
+                    if idx != 0 {
+                        csv.push(' ');
+                    }
+                    let _ = write!(csv, "{value}");
+                }
+            } else if let Some(value) = field.decode(entry) {
+                let _ = write!(csv, "{value}");
+            }
+        }
+        csv.push('\n');
+        csv
+    }
+
+    /// Extract the value of an integer field from a trace entry.
+    ///
+    /// The entry starts with the [`TraceEntry`] header, as passed to event callbacks, and
+    /// the field is located with the field descriptors of the tracepoint, including the
+    /// common fields. Signed values are sign-extended. Returns `None` for an unknown field,
+    /// a field outside the entry, an array or a `__string` field.
+    pub fn field_u64<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint: &TracePoint<L, K>,
+        entry: &[u8],
+        field_name: &str,
+    ) -> Option<u64> {
+        let field = tracepoint.field(field_name)?;
+        if field.is_array() || field.is_string() {
+            return None;
+        }
+        match field.decode(entry)? {
+            FieldValue::Unsigned(value) => Some(value),
+            FieldValue::Signed(value) => Some(value as u64),
+        }
+    }
+
+    /// Extract the value of a field from a trace entry, formatted as a string.
+    ///
+    /// A `__string` field yields its string and other fields are formatted like
+    /// [`ParseOptions::set_decode_fields`] does. Returns `None` for an unknown field or one
+    /// that can't be decoded.
+    pub fn field_str<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint: &TracePoint<L, K>,
         entry: &[u8],
+        field_name: &str,
+    ) -> Option<String> {
+        tracepoint.field(field_name)?.format(entry)
+    }
+
+    /// Make sure the comm of the task that generated a raw record can be found, so the
+    /// record doesn't print as `<...>`.
+    ///
+    /// If the record carries no captured comm and its PID is missing from the cache, the
+    /// comm is resolved with [`KernelTraceOps::comm_for_pid`] and inserted into the cache.
+    /// Call this before parsing the record to enable the fallback. Returns whether the comm
+    /// is known.
+    pub fn resolve_comm<K: KernelTraceOps>(
+        cmdline_cache: &mut TraceCmdLineCache,
+        record: &[u8],
+    ) -> bool {
+        let Some(header) = RecordHeader::from_bytes(record) else {
+            return false;
+        };
+        if header.comm(record).is_some_and(|comm| !comm.is_empty()) {
+            return true;
+        }
+        let entry = record_entry(record, &header);
+        if entry.len() < size_of::<TraceEntry>() {
+            return false;
+        }
+        let trace_entry = unsafe { core::ptr::read_unaligned(entry.as_ptr() as *const TraceEntry) };
+        let pid = trace_entry.common_pid;
+        cmdline_cache.get_or_resolve::<K>(pid as u32).is_some()
+    }
+
+    /// Parse the trace entry and return a formatted string, checking the record first.
+    ///
+    /// Unlike [`TraceEntryParser::parse`], which returns an empty string, a corrupt record
+    /// is rejected with the reason: one that is too short, was written with another magic
+    /// number or format version, has an unknown tracepoint ID or an entry too short for its
+    /// fields. A consumer draining a snapshot can thus count the corrupt records and skip
+    /// them.
+    pub fn try_parse<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint_map: &TracePointMap<L, K>,
+        cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+    ) -> Result<String, ParseError> {
+        check_record(tracepoint_map, record)?;
+        Ok(Self::parse(tracepoint_map, cmdline_cache, record))
+    }
+
+    /// Parse the trace entry with the given options and return a formatted string.
+    pub fn parse_with<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint_map: &TracePointMap<L, K>,
+        cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+        options: &ParseOptions,
     ) -> String {
-        let trace_entry = unsafe { &*(entry.as_ptr() as *const TraceEntry) };
-        let id = trace_entry.common_type as u32;
-        let tracepoint = tracepoint_map.get(&id).expect("TracePoint not found");
-        let fmt_func = tracepoint.fmt_func();
-        let offset = core::mem::size_of::<TraceEntry>();
-        let str = fmt_func(&entry[offset..]);
+        let mut line = String::new();
+        let _ = Self::write_line(tracepoint_map, cmdline_cache, record, options, &mut line);
+        if let Some(transform) = options.transform.as_ref() {
+            transform(&mut line);
+        }
+        line
+    }
+
+    /// Parse the trace entry and write the formatted line to `out`.
+    ///
+    /// The output is the same as [`TraceEntryParser::parse`], but the line is formatted
+    /// straight into `out`, so dumping many records into one reused buffer doesn't allocate
+    /// a `String` per record. Nothing is written for a corrupt record.
+    pub fn write_to<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint_map: &TracePointMap<L, K>,
+        cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+        out: &mut dyn Write,
+    ) -> core::fmt::Result {
+        Self::write_line(
+            tracepoint_map,
+            cmdline_cache,
+            record,
+            &ParseOptions::new(),
+            out,
+        )
+    }
+
+    /// Write the formatted line of the trace entry to `out`, without the transform.
+    ///
+    /// Nothing is written for a corrupt record.
+    fn write_line<K: KernelTraceOps, L: RawMutex + 'static>(
+        tracepoint_map: &TracePointMap<L, K>,
+        cmdline_cache: &TraceCmdLineCache,
+        record: &[u8],
+        options: &ParseOptions,
+        out: &mut dyn Write,
+    ) -> core::fmt::Result {
+        let Ok(checked) = check_record(tracepoint_map, record) else {
+            return Ok(());
+        };
+        let header = checked.header;
+        let entry = &checked.entry[..];
+        let trace_entry = checked.common;
+        let marker = header.marker(record);
+        let (name, mut str) = match (marker, checked.tracepoint) {
+            (Some(msg), _) => (MARKER_EVENT, String::from(msg)),
+            (None, None) => return Ok(()),
+            (None, Some(tracepoint)) => {
+                let fmt_func = tracepoint.fmt_func();
+                let offset = core::mem::size_of::<TraceEntry>();
+                let str = if options.decode_fields {
+                    let fields = tracepoint
+                        .fields()
+                        .iter()
+                        .map(|field| {
+                            let value = options
+                                .hex_byte_arrays
+                                .then(|| field.format_hex(entry))
+                                .flatten()
+                                .or_else(|| field.format(entry))
+                                .unwrap_or_else(|| "?".to_string());
+                            format!("{}={}", field.name, value)
+                        })
+                        .collect::<Vec<_>>();
+                    fields.join(", ")
+                } else {
+                    fmt_func(&entry[offset..])
+                };
+                (tracepoint.name(), str)
+            }
+        };
+        if header.is_truncated() {
+            str.push_str(" [truncated]");
+        }
 
-        let time = K::time_now();
-        let cpu_id = K::cpu_id();
+        let time = header.timestamp;
+        let cpu_id = header.cpu;
 
         // Copy the packed field to a local variable to avoid unaligned reference
         let pid = trace_entry.common_pid;
-        let pname = cmdline_cache
-            .get(trace_entry.common_pid as u32)
-            .unwrap_or("<...>");
+        let pname = record_comm(record, &header, cmdline_cache, pid);
 
         let secs = time / 1_000_000_000;
-        let usec_rem = time % 1_000_000_000 / 1000;
+        let nsec_rem = time % 1_000_000_000;
+        let delta = if options.relative_time {
+            let last = options.last_timestamp.swap(time, Ordering::Relaxed);
+            if last == u64::MAX {
+                0
+            } else {
+                ts_delta(time, last)
+            }
+        } else {
+            0
+        };
 
-        format!(
-            "{:>16}-{:<7} [{:03}] {} {:5}.{:06}: {}({})\n",
-            pname,
-            pid,
-            cpu_id,
-            trace_entry.trace_print_lat_fmt(),
-            secs,
-            usec_rem,
-            tracepoint.name(),
-            str
-        )
+        let columns = if options.columns.is_empty() {
+            &Column::DEFAULT_LAYOUT[..]
+        } else {
+            &options.columns[..]
+        };
+        for (idx, column) in columns.iter().enumerate() {
+            match column {
+                Column::Comm => write!(out, "{pname:>16}")?,
+                Column::Pid => write!(out, "{pid:<7}")?,
+                Column::Cpu => write!(out, "[{cpu_id:03}]")?,
+                Column::Latency => write!(out, "{}", trace_entry.trace_print_lat_fmt())?,
+                Column::Timestamp if options.relative_time => {
+                    let sign = if delta < 0 { '-' } else { '+' };
+                    let delta = delta.unsigned_abs();
+                    let delta = format!("{sign}{}.{:03}", delta / 1000, delta % 1000);
+                    write!(out, "{delta:>12}:")?
+                }
+                Column::Timestamp => match options.time_precision {
+                    TimePrecision::Micros => write!(out, "{secs:5}.{:06}:", nsec_rem / 1000)?,
+                    TimePrecision::Nanos => write!(out, "{secs:5}.{nsec_rem:09}:")?,
+                },
+                Column::Event => out.write_str(name)?,
+                Column::Fields if marker.is_some() => write!(out, ": {str}")?,
+                Column::Fields => write!(out, "({str})")?,
+            }
+            if let Some(next) = columns.get(idx + 1) {
+                out.write_str(column.separator(*next))?;
+            }
+        }
+        if header.repeat != 0 {
+            write!(out, " (x{})", header.repeat as u64 + 1)?;
+        }
+        out.write_char('\n')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeSet, string::String, vec, vec::Vec};
+
+    use super::*;
+    use crate::{
+        RecordBuilder,
+        testing::{self, Kops, Lock},
+    };
+
+    mod events {
+        #![allow(dead_code)]
+        use crate::testing::{Kops, Lock};
+
+        crate::define_event_trace!(
+            pipe_sample,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(pipe_tests),
+            TP_PROTO(x: u32, y: u64),
+            TP_STRUCT__entry {
+                a: u32,
+                b: u64,
+            },
+            TP_fast_assign {
+                a: x,
+                b: y,
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={} b={}", __entry.a, __entry.b))
+        );
+
+        crate::define_event_trace!(
+            pipe_array,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(pipe_tests),
+            TP_PROTO(x: [u8; 4], y: i16),
+            TP_STRUCT__entry {
+                bytes: [u8; 4],
+                deltas: [i16; 2],
+            },
+            TP_fast_assign {
+                bytes: x,
+                deltas: [y, 2],
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("{:?} {:?}", __entry.bytes, __entry.deltas))
+        );
+
+        crate::define_event_trace!(
+            pipe_string,
+            TP_lock(Lock),
+            TP_kops(Kops),
+            TP_system(pipe_tests),
+            TP_PROTO(x: u32, file: &str),
+            TP_STRUCT__entry {
+                a: u32,
+                path: __string,
+            },
+            TP_fast_assign {
+                a: x,
+                path: __assign_str!(file),
+            },
+            TP_ident(__entry),
+            TP_printk(alloc::format!("a={} path={}", __entry.a, __get_str!(path)))
+        );
+    }
+
+    fn sample() -> &'static TracePoint<Lock, Kops> {
+        testing::manager()
+            .tracepoint_map()
+            .get_by_name("pipe_tests", "pipe_sample")
+            .unwrap()
+    }
+
+    fn sample_record(a: u32, b: u64) -> Vec<u8> {
+        RecordBuilder::new(sample().id())
+            .pid(7)
+            .timestamp(1_500_000_000)
+            .field_u32(a)
+            .field_u64(b)
+            .build()
+    }
+
+    fn parse(record: &[u8]) -> String {
+        let map = testing::manager().tracepoint_map();
+        TraceEntryParser::parse(&map, &TraceCmdLineCache::new(4), record)
+    }
+
+    #[test]
+    fn parse_fired_event() {
+        let _serial = testing::serial();
+        sample().enable_default();
+        events::trace_pipe_sample(3, 4);
+        sample().disable_default();
+        let records = testing::take_records(sample().id());
+        assert_eq!(records.len(), 1);
+        let line = parse(&records[0]);
+        assert!(line.ends_with("pipe_sample(a=3 b=4)\n"), "{line}");
+    }
+
+    #[test]
+    fn parse_string_fields() {
+        let _serial = testing::serial();
+        let tp = testing::manager()
+            .tracepoint_map()
+            .get_by_name("pipe_tests", "pipe_string")
+            .unwrap();
+        tp.enable_default();
+        events::trace_pipe_string(1, "/etc/passwd");
+        events::trace_pipe_string(2, &"x".repeat(70_000));
+        tp.disable_default();
+        let records = testing::take_records(tp.id());
+        assert_eq!(records.len(), 2);
+        assert!(parse(&records[0]).ends_with("pipe_string(a=1 path=/etc/passwd)\n"));
+
+        // The string is cut where the entry reaches the 64 KiB reach of its location
+        let header = RecordHeader::from_bytes(&records[1]).unwrap();
+        assert_eq!(header.entry_len, u16::MAX);
+        let line = parse(&records[1]);
+        let cut = u16::MAX as usize - tp.entry_size() - 1;
+        assert!(line.ends_with(&alloc::format!("path={})\n", "x".repeat(cut))));
+    }
+
+    fn record_at(timestamp: u64) -> Vec<u8> {
+        RecordBuilder::new(sample().id())
+            .timestamp(timestamp)
+            .field_u32(1)
+            .field_u64(2)
+            .build()
+    }
+
+    fn parse_with(record: &[u8], options: &ParseOptions) -> String {
+        let map = testing::manager().tracepoint_map();
+        TraceEntryParser::parse_with(&map, &TraceCmdLineCache::new(4), record, options)
+    }
+
+    #[test]
+    fn time_precision_sets_the_digits() {
+        let record = record_at(1_000_000_250);
+        let mut options = ParseOptions::new();
+        assert!(parse_with(&record, &options).contains("     1.000000: pipe_sample"));
+        options.set_time_precision(TimePrecision::Nanos);
+        assert!(parse_with(&record, &options).contains("     1.000000250: pipe_sample"));
+    }
+
+    #[test]
+    fn relative_time_shows_deltas() {
+        let mut options = ParseOptions::new();
+        options.set_relative_time(true);
+        let deltas = [1_000_000_000, 1_000_001_500, 1_000_000_000]
+            .map(|timestamp| parse_with(&record_at(timestamp), &options));
+        let expected = ["+0.000: pipe", "+1.500: pipe", "-1.500: pipe"];
+        for (line, expected) in deltas.iter().zip(expected) {
+            assert!(line.contains(&alloc::format!("      {expected}")), "{line}");
+        }
+
+        // Turning it on again restarts the stream
+        options.set_relative_time(true);
+        let line = parse_with(&record_at(5), &options);
+        assert!(line.contains("+0.000: pipe"), "{line}");
+    }
+
+    #[test]
+    fn ts_delta_handles_out_of_order_timestamps() {
+        assert_eq!(ts_delta(1_500, 1_000), 500);
+        assert_eq!(ts_delta(1_000, 1_500), -500);
+        assert_eq!(ts_delta(7, 7), 0);
+        assert_eq!(ts_delta(u64::MAX, 0), i64::MAX);
+        assert_eq!(ts_delta(0, u64::MAX), i64::MIN);
+        assert_eq!(ts_delta(0, 1 << 63), i64::MIN);
+    }
+
+    #[test]
+    fn decoded_arrays_render_every_element() {
+        let _serial = testing::serial();
+        let tp = testing::manager()
+            .tracepoint_map()
+            .get_by_name("pipe_tests", "pipe_array")
+            .unwrap();
+        tp.enable_default();
+        events::trace_pipe_array([1, 2, 3, 255], -1);
+        tp.disable_default();
+        let records = testing::take_records(tp.id());
+        let mut options = ParseOptions::new();
+        options.set_decode_fields(true);
+        let line = parse_with(&records[0], &options);
+        assert!(line.ends_with("pipe_array(bytes=[1, 2, 3, 255], deltas=[-1, 2])\n"));
+        options.set_hex_byte_arrays(true);
+        let line = parse_with(&records[0], &options);
+        assert!(line.ends_with("pipe_array(bytes=010203ff, deltas=[-1, 2])\n"));
+    }
+
+    #[test]
+    fn field_u64_extracts_single_fields() {
+        let record = RecordBuilder::new(sample().id())
+            .pid(-2)
+            .field_u32(3)
+            .field_u64(u64::MAX)
+            .build();
+        let entry = &record[RecordHeader::from_bytes(&record).unwrap().entry_offset()..];
+        let field = |name| TraceEntryParser::field_u64(sample(), entry, name);
+        assert_eq!(field("a"), Some(3));
+        assert_eq!(field("b"), Some(u64::MAX));
+        assert_eq!(field("common_pid"), Some(-2i64 as u64));
+        assert_eq!(field("missing"), None);
+        assert_eq!(
+            TraceEntryParser::field_u64(sample(), &entry[..12], "b"),
+            None
+        );
+        let tp = testing::manager()
+            .tracepoint_map()
+            .get_by_name("pipe_tests", "pipe_array")
+            .unwrap();
+        assert_eq!(TraceEntryParser::field_u64(tp, entry, "bytes"), None);
+        assert_eq!(
+            TraceEntryParser::field_str(sample(), entry, "b").as_deref(),
+            Some("18446744073709551615")
+        );
+    }
+
+    #[test]
+    fn linux_format_golden() {
+        let tp = testing::manager()
+            .tracepoint_map()
+            .get_by_name("pipe_tests", "pipe_array")
+            .unwrap();
+        let expected = alloc::format!(
+            "name: pipe_array\nID: {}\nformat:\n\
+            \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\
+            \tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;\n\
+            \tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;\n\
+            \tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;\n\n\
+            \tfield:u8 bytes[4];\toffset:8;\tsize:4;\tsigned:0;\n\
+            \tfield:s16 deltas[2];\toffset:12;\tsize:4;\tsigned:1;\n\n\
+            print fmt: \"alloc :: format! (\"{{:?}} {{:?}}\", __entry.bytes, __entry.deltas)\"\n",
+            tp.id()
+        );
+        assert_eq!(tp.print_fmt_linux(), expected);
+    }
+
+    #[test]
+    fn parse_misaligned_record() {
+        let record = sample_record(5, u64::MAX);
+        let mut shifted = vec![0u8];
+        shifted.extend_from_slice(&record);
+        let line = parse(&shifted[1..]);
+        assert_eq!(line, parse(&record));
+        assert!(line.contains(&alloc::format!("b={}", u64::MAX)), "{line}");
+    }
+
+    #[test]
+    fn corrupt_records_render_nothing() {
+        let record = sample_record(1, 2);
+        let unknown = RecordBuilder::new(u16::MAX as u32).field_u32(1).build();
+        let mut comm_cut = RecordBuilder::new(sample().id()).comm("task").build();
+        comm_cut.truncate(size_of::<RecordHeader>() + 4);
+        let map = testing::manager().tracepoint_map();
+        let cache = TraceCmdLineCache::new(4);
+        let corrupt: [&[u8]; 5] = [
+            &record[..4],
+            &record[..record.len() - 4],
+            &record[..size_of::<RecordHeader>() + 2],
+            &comm_cut,
+            &unknown,
+        ];
+        for record in corrupt {
+            assert_eq!(TraceEntryParser::parse(&map, &cache, record), "");
+            assert_eq!(TraceEntryParser::parse_json(&map, &cache, record), "");
+            let mut headers = BTreeSet::new();
+            assert_eq!(
+                TraceEntryParser::parse_csv(&map, &cache, record, &mut headers),
+                ""
+            );
+            let mut out = String::new();
+            TraceEntryParser::write_to(&map, &cache, record, &mut out).unwrap();
+            assert_eq!(out, "");
+            assert!(TraceEntryParser::try_parse(&map, &cache, record).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_comms_are_resolved() {
+        let resolved = RecordBuilder::new(sample().id())
+            .pid(testing::RESOLVED_PIDS as i32)
+            .field_u32(1)
+            .field_u64(2)
+            .build();
+        let unknown = sample_record(1, 2);
+        let map = testing::manager().tracepoint_map();
+        let mut cache = TraceCmdLineCache::new(4);
+        let line = TraceEntryParser::parse(&map, &cache, &resolved);
+        assert!(line.trim_start().starts_with("<...>-1000 "), "{line}");
+
+        assert!(TraceEntryParser::resolve_comm::<Kops>(
+            &mut cache, &resolved
+        ));
+        assert!(!TraceEntryParser::resolve_comm::<Kops>(
+            &mut cache, &unknown
+        ));
+        assert_eq!(cache.len(), 1);
+        let line = TraceEntryParser::parse(&map, &cache, &resolved);
+        assert!(line.trim_start().starts_with("proc1000-1000 "), "{line}");
+    }
+
+    #[test]
+    fn try_parse_rejects_foreign_headers() {
+        let record = sample_record(1, 2);
+        let map = testing::manager().tracepoint_map();
+        let cache = TraceCmdLineCache::new(4);
+        let rewrite = |edit: fn(&mut RecordHeader)| {
+            let mut header = RecordHeader::from_bytes(&record).unwrap();
+            edit(&mut header);
+            let mut record = record.clone();
+            record[..size_of::<RecordHeader>()].copy_from_slice(header.as_bytes());
+            record
+        };
+        let bad_magic = rewrite(|header| header.magic = !RECORD_MAGIC);
+        assert_eq!(
+            TraceEntryParser::try_parse(&map, &cache, &bad_magic),
+            Err(ParseError::BadMagic {
+                magic: !RECORD_MAGIC
+            })
+        );
+        let bad_version = rewrite(|header| header.version = RECORD_FORMAT_VERSION + 1);
+        assert_eq!(
+            TraceEntryParser::try_parse(&map, &cache, &bad_version),
+            Err(ParseError::UnsupportedVersion {
+                version: RECORD_FORMAT_VERSION + 1
+            })
+        );
+        assert!(TraceEntryParser::try_parse(&map, &cache, &record).is_ok());
+    }
+
+    #[test]
+    fn header_reports_stats() {
+        let mut pipe = TracePipeRaw::new(3);
+        for (cpu, timestamp) in [(1, 500_000), (0, 1_000_000), (2, 2_000_000), (2, 3_500_000)] {
+            let record = RecordBuilder::new(1).cpu(cpu).timestamp(timestamp).build();
+            pipe.push_event(record);
+        }
+        let header = pipe.snapshot().default_fmt_str();
+        let expected = "# tracer: nop\n#\n# entries-in-buffer/entries-written: 3/4   #P:2\n\
+            # overrun: 1\n# dropped events: 0\n\
+            # oldest event ts:     0.001000\n# newest event ts:     0.003500\n";
+        assert!(header.starts_with(expected), "{header}");
+    }
+
+    fn ring(mode: RingMode) -> TracePipeRaw {
+        let mut pipe = TracePipeRaw::with_mode(2, mode);
+        for timestamp in 1..=3 {
+            pipe.push_event(RecordBuilder::new(1).timestamp(timestamp).build());
+        }
+        pipe
+    }
+
+    fn timestamps(pipe: &TracePipeRaw) -> Vec<u64> {
+        let snapshot = pipe.snapshot();
+        snapshot
+            .iter()
+            .map(|record| RecordHeader::from_bytes(record).unwrap().timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn overwrite_evicts_the_oldest() {
+        let pipe = ring(RingMode::Overwrite);
+        assert_eq!(timestamps(&pipe), [2, 3]);
+        assert_eq!((pipe.overwritten_count(), pipe.dropped_count()), (1, 0));
+        let snapshot = pipe.snapshot();
+        assert_eq!(snapshot.overwritten_count(), 1);
+        assert_eq!(snapshot.dropped_count(), 0);
+    }
+
+    #[test]
+    fn drain_matches_popping() {
+        let pipe = ring(RingMode::Overwrite);
+        let mut popped = pipe.snapshot();
+        let mut manual = Vec::new();
+        while let Some(record) = popped.peek() {
+            manual.push(record.clone());
+            popped.pop();
+        }
+        let mut snapshot = pipe.snapshot();
+        assert!(snapshot.iter().eq(manual.iter().map(Vec::as_slice)));
+        assert_eq!(snapshot.drain().collect::<Vec<_>>(), manual);
+        assert!(snapshot.peek().is_none());
+
+        // Dropping the iterator early still empties the snapshot
+        let mut snapshot = pipe.snapshot();
+        assert_eq!(snapshot.drain().next(), manual.first().cloned());
+        assert_eq!(snapshot.iter().count(), 0);
+    }
+
+    #[test]
+    fn stats_summarize_the_snapshot() {
+        assert_eq!(
+            TracePipeSnapshot::new(Vec::new()).stats(),
+            SnapshotStats::default()
+        );
+        let mut pipe = TracePipeRaw::with_mode(3, RingMode::Discard);
+        for (cpu, timestamp) in [(1, 30), (0, 10), (1, 20), (2, 5)] {
+            pipe.push_event(RecordBuilder::new(1).cpu(cpu).timestamp(timestamp).build());
+        }
+        let stats = TracePipeSnapshot::new(pipe.snapshot().drain().collect()).stats();
+        assert_eq!((stats.entries, stats.written, stats.dropped), (3, 3, 0));
+        assert_eq!(
+            pipe.snapshot().stats(),
+            SnapshotStats {
+                entries: 3,
+                written: 3,
+                overrun: 0,
+                dropped: 1,
+                cpus: 2,
+                earliest: Some(10),
+                latest: Some(30),
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_events_are_rejected() {
+        let fits = RecordBuilder::new(1).field_u32(1).build();
+        let mut pipe = TracePipeRaw::builder()
+            .capacity(4)
+            .max_event_size(fits.len())
+            .build();
+        let oversized = RecordBuilder::new(1).field_u64(1).build();
+        pipe.push_event(fits.clone());
+        pipe.push_event(oversized.clone());
+        assert_eq!(pipe.try_push(oversized.clone()), Err(oversized));
+        assert_eq!(pipe.try_push(fits), Ok(()));
+        assert_eq!((pipe.event_count(), pipe.oversized_count()), (2, 2));
+        assert_eq!((pipe.overwritten_count(), pipe.dropped_count()), (0, 0));
+        pipe.clear();
+        assert_eq!(pipe.oversized_count(), 0);
+    }
+
+    #[test]
+    fn wrapped_snapshots_are_chronological() {
+        let mut pipe = TracePipeRaw::with_mode(3, RingMode::Overwrite);
+        // Two CPUs sharing the buffer push out of order, the first three are overwritten
+        for (cpu, timestamp) in [(0, 50), (1, 20), (0, 60), (1, 40), (0, 40), (1, 10)] {
+            pipe.push_event(RecordBuilder::new(1).cpu(cpu).timestamp(timestamp).build());
+        }
+        let snapshot = pipe.snapshot();
+        let events = snapshot
+            .iter()
+            .map(|record| {
+                let header = RecordHeader::from_bytes(record).unwrap();
+                (header.cpu, header.timestamp)
+            })
+            .collect::<Vec<_>>();
+        // Equal timestamps keep the order they were pushed in
+        assert_eq!(events, [(1, 10), (1, 40), (0, 40)]);
+        assert_eq!(snapshot.overwritten_count(), 3);
+    }
+
+    #[test]
+    fn default_built_pipes_take_events() {
+        let mut pipe = TracePipeRawBuilder::default().build();
+        assert_eq!(pipe.max_record(), 1024);
+        pipe.push_event(RecordBuilder::new(1).build());
+        assert_eq!(pipe.event_count(), 1);
+
+        // A pipe without room drops everything
+        let mut pipe = TracePipeRaw::builder().capacity(0).build();
+        pipe.push_event(RecordBuilder::new(1).build());
+        assert!(pipe.reserve(8).is_some());
+        pipe.commit();
+        assert_eq!((pipe.event_count(), pipe.dropped_count()), (0, 2));
+        assert_eq!(pipe.overwritten_count(), 0);
+    }
+
+    #[test]
+    fn deferred_pushes_hand_back_the_watermark_callback() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn wake() {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut pipe = TracePipeRaw::new(4);
+        pipe.set_watermark(0.5);
+        pipe.set_watermark_callback(Some(wake));
+        let record = || RecordBuilder::new(1).build();
+        assert!(pipe.push_event_deferred(record()).is_none());
+        pipe.reserve(record().len()).unwrap();
+        let callback = pipe.commit_deferred().unwrap();
+        assert!(pipe.push_event_deferred(record()).is_none());
+        assert!(pipe.commit_deferred().is_none());
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        callback();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+
+        // Back below the watermark, the plain push invokes it right away
+        pipe.clear();
+        pipe.push_event(record());
+        pipe.push_event(record());
+        assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn discard_drops_the_newest() {
+        let pipe = ring(RingMode::Discard);
+        assert_eq!(timestamps(&pipe), [1, 2]);
+        assert_eq!((pipe.overwritten_count(), pipe.dropped_count()), (0, 1));
+        let snapshot = pipe.snapshot();
+        assert_eq!(snapshot.overwritten_count(), 0);
+        assert_eq!(snapshot.dropped_count(), 1);
+        assert!(snapshot.default_fmt_str().contains("# dropped events: 1\n"));
+    }
+
+    #[test]
+    fn cmdline_cache_evicts_the_least_recently_used() {
+        let mut cache = TraceCmdLineCache::new(3);
+        for pid in 1..=3 {
+            cache.insert(pid, alloc::format!("task{pid}"));
+        }
+        // Using 1 leaves 2 as the least recently used
+        assert_eq!(cache.get(1), Some("task1"));
+        cache.insert(4, "task4".into());
+        assert_eq!(cache.get(2), None);
+        cache.insert(5, "task5".into());
+        assert_eq!(cache.get(3), None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.evicted_count(), 2);
+        for pid in [1, 4, 5] {
+            assert_eq!(cache.get(pid), Some(alloc::format!("task{pid}").as_str()));
+        }
+
+        // Shrinking evicts in the same order, 1 being the oldest use above
+        cache.set_max_record(2);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.evicted_count(), 3);
+    }
+
+    fn timestamp(record: &[u8]) -> u64 {
+        RecordHeader::from_bytes(record).unwrap().timestamp
+    }
+
+    #[test]
+    fn cursor_reads_dont_consume() {
+        let mut pipe = TracePipeRaw::with_mode(2, RingMode::Overwrite);
+        let mut cursor = pipe.cursor();
+        for timestamp in 1..=2 {
+            pipe.push_event(RecordBuilder::new(1).timestamp(timestamp).build());
+        }
+        assert_eq!(pipe.read(&mut cursor).map(timestamp), Some(1));
+        assert_eq!(pipe.event_count(), 2);
+
+        // The event after the cursor is overwritten before it is read
+        for timestamp in 3..=4 {
+            pipe.push_event(RecordBuilder::new(1).timestamp(timestamp).build());
+        }
+        assert_eq!(pipe.read(&mut cursor).map(timestamp), Some(3));
+        assert_eq!(cursor.missed_count(), 1);
+        assert_eq!(pipe.event_count(), 2);
+
+        assert_eq!(pipe.read_consuming().as_deref().map(timestamp), Some(3));
+        assert_eq!(pipe.read_consuming().as_deref().map(timestamp), Some(4));
+        assert_eq!(pipe.event_count(), 0);
+        assert!(pipe.read(&mut cursor).is_none());
+        assert_eq!(cursor.missed_count(), 2);
+    }
+
+    #[test]
+    fn swap_snapshot_mid_stream() {
+        let mut pipe = TracePipeRaw::new(4);
+        pipe.set_max_event_size(size_of::<RecordHeader>());
+        pipe.push_event(RecordBuilder::new(1).field_u32(1).build());
+        pipe.set_max_event_size(0);
+        assert_eq!(pipe.oversized_count(), 1);
+        pipe.alloc_snapshot();
+        let mut cursor = pipe.cursor();
+        for timestamp in 1..=2 {
+            pipe.push_event(RecordBuilder::new(1).timestamp(timestamp).build());
+        }
+        assert_eq!(pipe.read(&mut cursor).map(timestamp), Some(1));
+
+        let snapshot = pipe.swap_snapshot();
+        assert_eq!(snapshot.iter().map(timestamp).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(snapshot.stats().written, 2);
+        assert_eq!((pipe.event_count(), pipe.entries_written()), (0, 0));
+        assert_eq!(pipe.oversized_count(), 0);
+
+        // The cursor resumes in the fresh buffer, missing the event swapped out unread
+        pipe.push_event(RecordBuilder::new(1).timestamp(3).build());
+        assert_eq!(pipe.read(&mut cursor).map(timestamp), Some(3));
+        assert_eq!(cursor.missed_count(), 1);
+        assert_eq!(pipe.entries_written(), 1);
+    }
+
+    #[test]
+    fn inject_honours_the_ring_mode() {
+        for (mode, expected, counts) in [
+            (RingMode::Overwrite, [2, 3], (1, 0)),
+            (RingMode::Discard, [1, 2], (0, 1)),
+        ] {
+            let mut pipe = TracePipeRaw::with_mode(2, mode);
+            for timestamp in 1..=3 {
+                pipe.inject(RecordBuilder::new(1).timestamp(timestamp).build());
+            }
+            assert_eq!(timestamps(&pipe), expected);
+            assert_eq!((pipe.overwritten_count(), pipe.dropped_count()), counts);
+        }
+        let mut pipe = TracePipeRaw::new(0);
+        pipe.inject(RecordBuilder::new(1).build());
+        assert_eq!((pipe.event_count(), pipe.dropped_count()), (0, 1));
+    }
+
+    #[test]
+    fn shrinking_evicts_the_oldest() {
+        let mut pipe = ring(RingMode::Overwrite);
+        let mut cursor = pipe.cursor();
+        pipe.push_event(RecordBuilder::new(1).timestamp(4).build());
+        pipe.set_max_record(4);
+        pipe.push_event(RecordBuilder::new(1).timestamp(5).build());
+        pipe.set_max_record(1);
+        assert_eq!(timestamps(&pipe), [5]);
+        assert_eq!(pipe.overwritten_count(), 4);
+        let record = pipe.read(&mut cursor).unwrap();
+        assert_eq!(RecordHeader::from_bytes(record).unwrap().timestamp, 5);
+        assert_eq!(cursor.missed_count(), 3);
+    }
+
+    #[test]
+    fn snapshot_bytes_round_trip() {
+        let snapshot = ring(RingMode::Overwrite).snapshot();
+        let bytes = snapshot.to_bytes();
+        let read = TracePipeSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(read.stats(), snapshot.stats());
+        assert!(read.iter().eq(snapshot.iter()));
+
+        let mut bad_version = bytes.clone();
+        bad_version[2..4].copy_from_slice(&(RECORD_FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            TracePipeSnapshot::from_bytes(&bad_version).unwrap_err(),
+            ParseError::UnsupportedVersion {
+                version: RECORD_FORMAT_VERSION + 1
+            }
+        );
+        for len in [
+            SNAPSHOT_HEADER_LEN - 1,
+            SNAPSHOT_HEADER_LEN + 2,
+            bytes.len() - 1,
+        ] {
+            assert_eq!(
+                TracePipeSnapshot::from_bytes(&bytes[..len]).unwrap_err(),
+                ParseError::TooShort { len }
+            );
+        }
+    }
+
+    #[test]
+    fn json_types_fields_by_schema() {
+        let record = sample_record(3, u64::MAX);
+        let map = testing::manager().tracepoint_map();
+        let mut cache = TraceCmdLineCache::new(4);
+        cache.insert(7, String::from("sh"));
+        let json = TraceEntryParser::parse_json(&map, &cache, &record);
+        let expected = concat!(
+            r#"{"timestamp":1500000000,"cpu":0,"pid":7,"comm":"sh","#,
+            r#""event":"pipe_tests:pipe_sample","fields":{"a":3,"b":18446744073709551615}}"#,
+        );
+        assert_eq!(json, expected);
+    }
+
+    #[test]
+    fn write_to_matches_parse() {
+        let records = [
+            sample_record(1, 2),
+            record_at(3_000_000_000),
+            RecordBuilder::new(sample().id())
+                .comm("sh")
+                .field_u32(5)
+                .field_u64(6)
+                .build(),
+        ];
+        let map = testing::manager().tracepoint_map();
+        let cache = TraceCmdLineCache::new(4);
+        let mut out = String::new();
+        let mut expected = String::new();
+        for record in &records {
+            TraceEntryParser::write_to(&map, &cache, record, &mut out).unwrap();
+            expected.push_str(&TraceEntryParser::parse(&map, &cache, record));
+        }
+        assert_eq!(out, expected);
+        assert_eq!(out.lines().count(), records.len());
+    }
+
+    #[test]
+    fn csv_writes_one_header_per_event() {
+        let id = sample().id();
+        let record = RecordBuilder::new(id)
+            .pid(7)
+            .comm("sh, \"x\"")
+            .timestamp(1_500_000_000)
+            .field_u32(3)
+            .field_u64(4)
+            .build();
+        let map = testing::manager().tracepoint_map();
+        let cache = TraceCmdLineCache::new(4);
+        let mut headers = BTreeSet::new();
+        let csv = TraceEntryParser::parse_csv(&map, &cache, &record, &mut headers);
+        let row = "1500000000,0,7,\"sh, \"\"x\"\"\",pipe_tests:pipe_sample,3,4\n";
+        assert_eq!(
+            csv,
+            alloc::format!("timestamp,cpu,pid,comm,event,a,b\n{row}")
+        );
+        assert!(headers.contains(&id));
+        let csv = TraceEntryParser::parse_csv(&map, &cache, &record, &mut headers);
+        assert_eq!(csv, row);
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        let _serial = testing::serial();
+        testing::manager().trace_marker("say \"hi\"\\\n\u{1}");
+        let record = testing::PIPE.lock().read_consuming().unwrap();
+        let map = testing::manager().tracepoint_map();
+        let json = TraceEntryParser::parse_json(&map, &TraceCmdLineCache::new(4), &record);
+        let expected = r#""event":"tracing_mark_write","fields":{"buf":"say \"hi\"\\\n\u0001"}}"#;
+        assert!(json.ends_with(expected), "{json}");
+    }
+
+    #[test]
+    fn markers_parse_back() {
+        let _serial = testing::serial();
+        let manager = testing::manager();
+        testing::set_cpu(2);
+        manager.trace_marker("hello");
+        manager.set_tracing_on(false);
+        manager.trace_marker("dropped");
+        manager.set_tracing_on(true);
+        let record = testing::PIPE.lock().read_consuming().unwrap();
+        assert!(testing::PIPE.lock().read_consuming().is_none());
+
+        let header = RecordHeader::from_bytes(&record).unwrap();
+        assert!(header.is_marker());
+        assert_eq!(header.cpu, 2);
+        assert_eq!(header.marker(&record), Some("hello"));
+        let line = parse(&record);
+        assert!(line.contains("-1 "), "{line}");
+        assert!(line.ends_with("tracing_mark_write: hello\n"), "{line}");
+    }
+
+    #[test]
+    fn reserve_then_commit_reads_back() {
+        let mut pipe = TracePipeRaw::new(2);
+        let record = sample_record(9, 10);
+        pipe.reserve(record.len()).unwrap().copy_from_slice(&record);
+        // The pending reservation blocks the next one and isn't visible yet
+        assert!(pipe.reserve(4).is_none());
+        assert!(pipe.snapshot().is_empty());
+        pipe.commit();
+        assert_eq!(pipe.read_consuming().unwrap(), record);
+
+        pipe.reserve(4).unwrap().copy_from_slice(&[1, 2, 3, 4]);
+        pipe.abort();
+        assert!(pipe.read_consuming().is_none());
+
+        // Aborting on a full buffer loses nothing, and coalescing takes no room either
+        let mut pipe = TracePipeRaw::new(2);
+        for timestamp in 1..=2 {
+            pipe.push_event(RecordBuilder::new(1).timestamp(timestamp).build());
+        }
+        pipe.reserve(4).unwrap();
+        pipe.abort();
+        assert_eq!(timestamps(&pipe), [1, 2]);
+        assert_eq!(pipe.overwritten_count(), 0);
+        pipe.set_coalesce(true);
+        let repeat = RecordBuilder::new(1).timestamp(3).build();
+        pipe.reserve(repeat.len()).unwrap().copy_from_slice(&repeat);
+        pipe.commit();
+        assert_eq!(timestamps(&pipe), [1, 3]);
+        assert_eq!(pipe.overwritten_count(), 0);
+
+        // A full buffer in Discard mode has no room to reserve
+        let mut pipe = ring(RingMode::Discard);
+        assert!(pipe.reserve(4).is_none());
+        assert_eq!(pipe.dropped_count(), 2);
+        assert_eq!(pipe.oversized_count(), 0);
+    }
+
+    #[test]
+    fn fired_events_are_written_in_place() {
+        let _serial = testing::serial();
+        sample().enable_default();
+        events::trace_pipe_sample(3, 4);
+        // A pending reservation makes the pipe refuse the next record
+        testing::PIPE.lock().reserve(1);
+        events::trace_pipe_sample(5, 6);
+        testing::PIPE.lock().abort();
+        sample().disable_default();
+        let records = testing::take_records(sample().id());
+        assert_eq!(records.len(), 1);
+        let header = RecordHeader::from_bytes(&records[0]).unwrap();
+        assert_eq!(
+            records[0].len(),
+            header.entry_offset() + header.entry_len as usize
+        );
+        assert!(parse(&records[0]).ends_with("pipe_sample(a=3 b=4)\n"));
+    }
+
+    #[test]
+    fn per_cpu_snapshot_merges_by_timestamp() {
+        let pipes = PerCpuTracePipe::<Lock, 2>::new(4);
+        for (cpu, timestamp) in [(0, 1), (1, 2), (1, 3), (0, 4), (1, 5), (0, 5), (0, 7)] {
+            pipes.push_event(RecordBuilder::new(1).cpu(cpu).timestamp(timestamp).build());
+        }
+        assert_eq!(pipes.cpu_pipe(0).unwrap().lock().event_count(), 4);
+        let merged = pipes.snapshot_merged();
+        let order = merged
+            .iter()
+            .map(|record| {
+                let header = RecordHeader::from_bytes(record).unwrap();
+                (header.cpu, header.timestamp)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [(0, 1), (1, 2), (1, 3), (0, 4), (0, 5), (1, 5), (0, 7)]
+        );
+        assert_eq!(merged.written, 7);
     }
 }